@@ -1,10 +1,25 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     configure_me_codegen::build_script_auto().unwrap_or_else(|error| error.report_and_exit());
 
-    // Compile the protos for our grpc server.
-    tonic_build::configure()
+    // Compile the protos for our grpc server. The messages are serializable so that the cli can
+    // print them as json, with binary fields encoded as hex.
+    let mut builder = tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
-        .compile(&["proto/lndkrpc.proto"], &["proto"])?;
+        .type_attribute(".lndkrpc", "#[derive(serde::Serialize)]")
+        // Payment proofs are shared as json, so the cli needs to be able to read them back in.
+        .type_attribute(".lndkrpc.PaymentProof", "#[derive(serde::Deserialize)]");
+    for field in [
+        ".lndkrpc.PaymentHash.hash",
+        ".lndkrpc.PublicKey.key",
+        ".lndkrpc.BlindedHop.encrypted_payload",
+        ".lndkrpc.OfferContents.metadata",
+    ] {
+        builder = builder.field_attribute(
+            field,
+            "#[serde(serialize_with = \"crate::serialize_hex\")]",
+        );
+    }
+    builder.compile(&["proto/lndkrpc.proto"], &["proto"])?;
 
     Ok(())
 }
@@ -0,0 +1,413 @@
+use crate::onion_messenger::MessengerUtilities;
+use crate::{ADMIN_MACAROON_FILENAME, MACAROON_ROOT_KEY_FILENAME};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use lightning::sign::EntropySource;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Every lndk macaroon starts with these bytes, which lets us tell them apart from the LND
+/// macaroons that clients may still forward to us.
+const MACAROON_MAGIC: &[u8; 4] = b"lndk";
+const MACAROON_VERSION: u8 = 0;
+const MACAROON_ID_LEN: usize = 16;
+const MACAROON_SIG_LEN: usize = 32;
+// magic || version || id || permissions || signature
+const MACAROON_LEN: usize = MACAROON_MAGIC.len() + 1 + MACAROON_ID_LEN + 1 + MACAROON_SIG_LEN;
+const ROOT_KEY_LEN: usize = 32;
+
+/// Permission is an action that an lndk macaroon may authorize.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Fetch invoices for offers without paying them.
+    Read,
+    /// Pay offers and invoices.
+    Pay,
+    /// Create new offers.
+    CreateOffer,
+    /// Bake new macaroons and rotate our seed. Admin macaroons are allowed to call every RPC.
+    Admin,
+    /// Send and receive custom onion messages.
+    Messages,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 5] = [
+        Permission::Read,
+        Permission::Pay,
+        Permission::CreateOffer,
+        Permission::Admin,
+        Permission::Messages,
+    ];
+
+    fn bit(&self) -> u8 {
+        match self {
+            Permission::Read => 1 << 0,
+            Permission::Pay => 1 << 1,
+            Permission::CreateOffer => 1 << 2,
+            Permission::Admin => 1 << 3,
+            Permission::Messages => 1 << 4,
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = MacaroonError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "read" => Ok(Permission::Read),
+            "pay" => Ok(Permission::Pay),
+            "create-offer" | "create_offer" => Ok(Permission::CreateOffer),
+            "admin" => Ok(Permission::Admin),
+            "messages" => Ok(Permission::Messages),
+            _ => Err(MacaroonError::UnknownPermission(s.to_string())),
+        }
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permission::Read => write!(f, "read"),
+            Permission::Pay => write!(f, "pay"),
+            Permission::CreateOffer => write!(f, "create-offer"),
+            Permission::Admin => write!(f, "admin"),
+            Permission::Messages => write!(f, "messages"),
+        }
+    }
+}
+
+/// An error that occurs when baking, decoding or verifying an lndk macaroon.
+#[derive(Debug, PartialEq)]
+pub enum MacaroonError {
+    /// The macaroon is not a validly encoded lndk macaroon.
+    InvalidEncoding,
+    /// The macaroon was not signed with our root key, or it was tampered with.
+    InvalidSignature,
+    /// The macaroon is valid but doesn't grant the permission required.
+    PermissionDenied(Permission),
+    /// The permission string provided is not one we know about.
+    UnknownPermission(String),
+}
+
+impl Display for MacaroonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MacaroonError::InvalidEncoding => write!(f, "Invalid lndk macaroon encoding"),
+            MacaroonError::InvalidSignature => write!(f, "Invalid lndk macaroon signature"),
+            MacaroonError::PermissionDenied(p) => {
+                write!(f, "Macaroon doesn't grant the '{p}' permission")
+            }
+            MacaroonError::UnknownPermission(p) => write!(
+                f,
+                "Unknown permission '{p}'. Should be read, pay, create-offer, messages or admin."
+            ),
+        }
+    }
+}
+
+impl Error for MacaroonError {}
+
+/// LndkMacaroon is a bearer credential minted by lndk which grants a fixed set of permissions. It
+/// is signed with a root key that never leaves lndk's data directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LndkMacaroon {
+    id: [u8; MACAROON_ID_LEN],
+    permissions: u8,
+    signature: [u8; MACAROON_SIG_LEN],
+}
+
+impl LndkMacaroon {
+    /// Returns the permissions granted by this macaroon.
+    pub fn permissions(&self) -> Vec<Permission> {
+        Permission::ALL
+            .into_iter()
+            .filter(|p| self.permissions & p.bit() != 0)
+            .collect()
+    }
+
+    /// Returns whether this macaroon grants the permission provided. Admin macaroons grant every
+    /// permission.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.permissions & (permission.bit() | Permission::Admin.bit()) != 0
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut bytes = signed_bytes(&self.id, self.permissions);
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    pub fn to_hex(&self) -> String {
+        hex::encode(self.serialize())
+    }
+
+    pub fn from_hex(macaroon_hex: &str) -> Result<Self, MacaroonError> {
+        let bytes = hex::decode(macaroon_hex).map_err(|_| MacaroonError::InvalidEncoding)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MacaroonError> {
+        if bytes.len() != MACAROON_LEN || !bytes.starts_with(MACAROON_MAGIC) {
+            return Err(MacaroonError::InvalidEncoding);
+        }
+        let mut offset = MACAROON_MAGIC.len();
+        if bytes[offset] != MACAROON_VERSION {
+            return Err(MacaroonError::InvalidEncoding);
+        }
+        offset += 1;
+
+        let mut id = [0; MACAROON_ID_LEN];
+        id.copy_from_slice(&bytes[offset..offset + MACAROON_ID_LEN]);
+        offset += MACAROON_ID_LEN;
+
+        let permissions = bytes[offset];
+        offset += 1;
+
+        let mut signature = [0; MACAROON_SIG_LEN];
+        signature.copy_from_slice(&bytes[offset..]);
+
+        Ok(LndkMacaroon {
+            id,
+            permissions,
+            signature,
+        })
+    }
+}
+
+/// Returns whether the hex-encoded macaroon provided is an lndk macaroon, rather than an LND one.
+pub fn is_lndk_macaroon(macaroon_hex: &str) -> bool {
+    match hex::decode(macaroon_hex) {
+        Ok(bytes) => bytes.starts_with(MACAROON_MAGIC),
+        Err(_) => false,
+    }
+}
+
+/// MacaroonAuth bakes and verifies lndk macaroons using a root key.
+pub struct MacaroonAuth {
+    root_key: [u8; ROOT_KEY_LEN],
+    messenger_utils: MessengerUtilities,
+}
+
+impl MacaroonAuth {
+    pub fn new(root_key: [u8; ROOT_KEY_LEN]) -> Self {
+        MacaroonAuth {
+            root_key,
+            messenger_utils: MessengerUtilities::default(),
+        }
+    }
+
+    /// Loads the root key from lndk's data directory, generating one if it doesn't exist yet. If
+    /// no admin macaroon exists in the data directory, we also bake one with every permission so
+    /// that the operator can mint restricted macaroons from it.
+    pub fn load_or_create(data_dir: &Path) -> Result<Self, std::io::Error> {
+        let key_path = data_dir.join(MACAROON_ROOT_KEY_FILENAME);
+        let auth = if key_path.exists() {
+            let key = std::fs::read(&key_path)?;
+            let root_key: [u8; ROOT_KEY_LEN] = key.try_into().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Macaroon root key in {key_path:?} is not {ROOT_KEY_LEN} bytes"),
+                )
+            })?;
+            MacaroonAuth::new(root_key)
+        } else {
+            log::debug!("Generating fresh macaroon root key in {data_dir:?}");
+            let root_key = MessengerUtilities::default().get_secure_random_bytes();
+            write_user_only(&key_path, &root_key)?;
+            MacaroonAuth::new(root_key)
+        };
+
+        let admin_path = data_dir.join(ADMIN_MACAROON_FILENAME);
+        if !admin_path.exists() {
+            let admin = auth.bake(&Permission::ALL);
+            write_user_only(&admin_path, &admin.serialize())?;
+        }
+
+        Ok(auth)
+    }
+
+    /// Mints a new macaroon granting the permissions provided.
+    pub fn bake(&self, permissions: &[Permission]) -> LndkMacaroon {
+        let mut id = [0; MACAROON_ID_LEN];
+        id.copy_from_slice(&self.messenger_utils.get_secure_random_bytes()[..MACAROON_ID_LEN]);
+        let permissions = permissions.iter().fold(0, |acc, p| acc | p.bit());
+        let signature = self.sign(&id, permissions);
+
+        LndkMacaroon {
+            id,
+            permissions,
+            signature,
+        }
+    }
+
+    /// Checks that the macaroon was signed with our root key and that it grants the permission
+    /// required.
+    pub fn verify(
+        &self,
+        macaroon: &LndkMacaroon,
+        required: Permission,
+    ) -> Result<(), MacaroonError> {
+        let expected = self.sign(&macaroon.id, macaroon.permissions);
+        if !constant_time_eq(&expected, &macaroon.signature) {
+            return Err(MacaroonError::InvalidSignature);
+        }
+        if !macaroon.has_permission(required) {
+            return Err(MacaroonError::PermissionDenied(required));
+        }
+
+        Ok(())
+    }
+
+    fn sign(&self, id: &[u8; MACAROON_ID_LEN], permissions: u8) -> [u8; MACAROON_SIG_LEN] {
+        let mut engine = HmacEngine::<sha256::Hash>::new(&self.root_key);
+        engine.input(&signed_bytes(id, permissions));
+        Hmac::<sha256::Hash>::from_engine(engine).to_byte_array()
+    }
+}
+
+/// Parses a list of permission strings, such as the ones provided to the bakemacaroon RPC.
+pub fn parse_permissions(permissions: &[String]) -> Result<Vec<Permission>, MacaroonError> {
+    permissions
+        .iter()
+        .map(|p| Permission::from_str(p))
+        .collect()
+}
+
+fn signed_bytes(id: &[u8; MACAROON_ID_LEN], permissions: u8) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(MACAROON_LEN);
+    bytes.extend_from_slice(MACAROON_MAGIC);
+    bytes.push(MACAROON_VERSION);
+    bytes.extend_from_slice(id);
+    bytes.push(permissions);
+    bytes
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Creates a file that only the current user can read, since it holds secret material. The file is
+// created with those permissions, so it's never readable by anyone else, even briefly.
+fn write_user_only(path: &Path, contents: &[u8]) -> Result<(), std::io::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_bake_and_verify() {
+        let auth = MacaroonAuth::new([42; 32]);
+        let macaroon = auth.bake(&[Permission::CreateOffer]);
+
+        assert!(auth.verify(&macaroon, Permission::CreateOffer).is_ok());
+        assert_eq!(
+            auth.verify(&macaroon, Permission::Pay),
+            Err(MacaroonError::PermissionDenied(Permission::Pay))
+        );
+        assert_eq!(macaroon.permissions(), vec![Permission::CreateOffer]);
+    }
+
+    #[test]
+    fn test_admin_grants_everything() {
+        let auth = MacaroonAuth::new([42; 32]);
+        let macaroon = auth.bake(&[Permission::Admin]);
+
+        for permission in Permission::ALL {
+            assert!(auth.verify(&macaroon, permission).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_serialization_roundtrip() {
+        let auth = MacaroonAuth::new([42; 32]);
+        let macaroon = auth.bake(&[Permission::Read, Permission::Pay]);
+
+        let macaroon_hex = macaroon.to_hex();
+        assert!(is_lndk_macaroon(&macaroon_hex));
+        assert_eq!(LndkMacaroon::from_hex(&macaroon_hex).unwrap(), macaroon);
+    }
+
+    #[test]
+    fn test_reject_tampered_macaroon() {
+        let auth = MacaroonAuth::new([42; 32]);
+        let macaroon = auth.bake(&[Permission::Read]);
+
+        // Try to escalate our permissions without re-signing.
+        let mut bytes = macaroon.serialize();
+        bytes[MACAROON_MAGIC.len() + 1 + MACAROON_ID_LEN] = Permission::Pay.bit();
+        let tampered = LndkMacaroon::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            auth.verify(&tampered, Permission::Pay),
+            Err(MacaroonError::InvalidSignature)
+        );
+
+        // A macaroon signed by a different root key is rejected too.
+        let other_auth = MacaroonAuth::new([43; 32]);
+        let other = other_auth.bake(&[Permission::Read]);
+        assert_eq!(
+            auth.verify(&other, Permission::Read),
+            Err(MacaroonError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_lnd_macaroon_is_not_lndk_macaroon() {
+        let lnd_macaroon = "0201036c6e6402f801030a103b7a55a2bb5810a264429f9ecf7dbdd51201301a16";
+        assert!(!is_lndk_macaroon(lnd_macaroon));
+        assert!(!is_lndk_macaroon("not hex"));
+        assert_eq!(
+            LndkMacaroon::from_hex(lnd_macaroon),
+            Err(MacaroonError::InvalidEncoding)
+        );
+    }
+
+    #[test]
+    fn test_parse_permissions() {
+        let permissions = vec!["read".to_string(), "create-offer".to_string()];
+        assert_eq!(
+            parse_permissions(&permissions).unwrap(),
+            vec![Permission::Read, Permission::CreateOffer]
+        );
+
+        let permissions = vec!["read".to_string(), "steal".to_string()];
+        assert!(parse_permissions(&permissions).is_err());
+    }
+
+    #[test]
+    fn test_load_or_create() {
+        let dir = tempdir().unwrap();
+        let auth = MacaroonAuth::load_or_create(dir.path()).unwrap();
+        for filename in [MACAROON_ROOT_KEY_FILENAME, ADMIN_MACAROON_FILENAME] {
+            let metadata = std::fs::metadata(dir.path().join(filename)).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+
+        // The admin macaroon written to disk should be verifiable with the root key.
+        let admin_bytes = std::fs::read(dir.path().join(ADMIN_MACAROON_FILENAME)).unwrap();
+        let admin = LndkMacaroon::from_bytes(&admin_bytes).unwrap();
+        assert!(auth.verify(&admin, Permission::Admin).is_ok());
+
+        // Loading again should reuse the same root key.
+        let reloaded = MacaroonAuth::load_or_create(dir.path()).unwrap();
+        assert!(reloaded.verify(&admin, Permission::Admin).is_ok());
+    }
+}
@@ -1,20 +1,32 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use lightning::offers::invoice::Bolt12Invoice;
 use lndk::lndkrpc::offers_client::OffersClient;
-use lndk::lndkrpc::{CreateOfferRequest, GetInvoiceRequest, PayInvoiceRequest, PayOfferRequest};
+use lndk::lndkrpc::{
+    BakeMacaroonRequest, CancelPaymentRequest, CreateOfferRequest, EstimateFeeRequest,
+    ExportPaymentProofRequest, GetInvoiceRequest, PayHumanReadableNameRequest, PayInvoiceRequest,
+    PayOfferRequest, PaymentProof, RotateSeedRequest, SendCustomOnionMessageRequest,
+    SubscribeCustomOnionMessagesRequest, VerifyPaymentProofRequest,
+};
 use lndk::offers::decode;
 use lndk::offers::handler::DEFAULT_RESPONSE_INVOICE_TIMEOUT;
+use lndk::server::{generate_bolt12_invoice_contents, generate_offer_contents};
+use lndk::tls::issue_client_cert;
 use lndk::{
-    Bolt12InvoiceString, DEFAULT_DATA_DIR, DEFAULT_LNDK_DIR, DEFAULT_SERVER_HOST,
-    DEFAULT_SERVER_PORT, TLS_CERT_FILENAME,
+    Bolt12InvoiceString, ADMIN_MACAROON_FILENAME, DEFAULT_DATA_DIR, DEFAULT_LNDK_DIR,
+    DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, TLS_CERT_FILENAME,
 };
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::fmt::Display;
 use std::fs::File;
 use std::io::BufReader;
+use std::io::IsTerminal;
 use std::io::Read;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
-use tonic::transport::{Certificate, Channel, ClientTlsConfig};
-use tonic::Request;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::{Code, Request, Status};
 
 fn get_macaroon_path_default(network: &str) -> PathBuf {
     home::home_dir()
@@ -23,20 +35,32 @@ fn get_macaroon_path_default(network: &str) -> PathBuf {
         .join(format!(".lnd/data/chain/bitcoin/{network}/admin.macaroon"))
 }
 
+fn get_data_dir_default() -> PathBuf {
+    home::home_dir()
+        .unwrap()
+        .join(DEFAULT_LNDK_DIR)
+        .join(DEFAULT_DATA_DIR)
+}
+
+fn get_cli_config_path_default() -> PathBuf {
+    home::home_dir()
+        .unwrap()
+        .join(DEFAULT_LNDK_DIR)
+        .join(DEFAULT_CLI_CONFIG_FILE_NAME)
+}
+
+const DEFAULT_NETWORK: &str = "regtest";
+const DEFAULT_CLI_CONFIG_FILE_NAME: &str = "cli.conf";
+
 /// A cli for interacting with lndk.
 #[derive(Debug, Parser)]
 #[command(name = "lndk-cli")]
 #[command(about = "A cli for interacting with lndk", long_about = None)]
 struct Cli {
-    /// Global variables
-    #[arg(
-        short,
-        long,
-        global = true,
-        required = false,
-        default_value = "regtest"
-    )]
-    network: String,
+    /// The network lnd is running on, which is used to find lnd's admin macaroon. Defaults to
+    /// regtest.
+    #[arg(short, long, global = true, required = false)]
+    network: Option<String>,
 
     #[arg(short, long, global = true, required = false)]
     macaroon_path: Option<PathBuf>,
@@ -46,24 +70,55 @@ struct Cli {
     macaroon_hex: Option<String>,
 
     /// This option is for passing a pem-encoded TLS certificate string to establish a connection
-    /// with the LNDK server. If this isn't set, the cli will look for the TLS file in the default
-    /// location (~.lndk/data).
+    /// with the LNDK server. If this isn't set, the cli will look for the TLS file in lndk's data
+    /// directory.
     /// Only one of cert_pem or cert_path can be set at once.
     #[arg(long, global = true, required = false)]
     cert_pem: Option<String>,
 
     /// This option is for passing a file path to a pem-encoded TLS certificate string to establish
     /// a connection with the LNDK server. If this isn't set, the cli will look for the TLS file in
-    /// the default location (~.lndk/data).
+    /// lndk's data directory.
     /// Only one of cert_pem or cert_path can be set at once.
     #[arg(long, global = true, required = false)]
     cert_path: Option<PathBuf>,
 
-    #[arg(long, global = true, required = false, default_value = format!("https://{DEFAULT_SERVER_HOST}"))]
-    grpc_host: String,
+    /// The path to a pem-encoded TLS client certificate to present to the LNDK server, if it
+    /// requires clients to authenticate with one. Requires client_key_path to be set.
+    #[arg(long, global = true, required = false)]
+    client_cert_path: Option<PathBuf>,
 
-    #[arg(long, global = true, required = false, default_value = DEFAULT_SERVER_PORT.to_string())]
-    grpc_port: u16,
+    /// The path to the pem-encoded private key for client_cert_path.
+    #[arg(long, global = true, required = false)]
+    client_key_path: Option<PathBuf>,
+
+    /// The host the LNDK server is listening on. Defaults to https://127.0.0.1.
+    #[arg(long, global = true, required = false)]
+    grpc_host: Option<String>,
+
+    /// The port the LNDK server is listening on. Defaults to 7000.
+    #[arg(long, global = true, required = false)]
+    grpc_port: Option<u16>,
+
+    /// LNDK's data directory, where the cli looks for LNDK's TLS certificate and admin macaroon.
+    /// Defaults to ~/.lndk/data.
+    #[arg(long, global = true, required = false)]
+    data_dir: Option<PathBuf>,
+
+    /// The cli config file to read profiles from. Defaults to ~/.lndk/cli.conf.
+    #[arg(long, global = true, required = false)]
+    config: Option<PathBuf>,
+
+    /// The profile in the cli config file to take settings from. If this isn't set, the config
+    /// file's default_profile is used. Options passed on the command line take precedence over
+    /// the profile.
+    #[arg(long, global = true, required = false)]
+    profile: Option<String>,
+
+    /// The format to print results and errors in. With json, every command prints a single json
+    /// object, and errors are printed as {"error": {"code", "message"}}.
+    #[arg(long, global = true, required = false, value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
 
     #[command(subcommand)]
     command: Commands,
@@ -108,6 +163,55 @@ enum Commands {
         /// Mutually exclusive with fee_limit - only one can be set.
         #[arg(long, required = false, conflicts_with = "fee_limit")]
         fee_limit_percent: Option<u32>,
+        /// A unique key for this payment. Retrying with the same key returns the result of the
+        /// original payment rather than paying again.
+        #[arg(long, required = false)]
+        idempotency_key: Option<String>,
+        /// A hex-encoded 32 byte id for the payment, which can be passed to cancel-payment while
+        /// we're waiting on an invoice. If this isn't set, a random one will be used.
+        #[arg(long, required = false)]
+        payment_id: Option<String>,
+        /// Show what the payment would cost, including an estimate of the routing fee, without
+        /// paying.
+        #[arg(long, required = false)]
+        dry_run: bool,
+        /// Pay without asking for confirmation first. This is required if lndk-cli isn't run
+        /// from a terminal.
+        #[arg(short, long, required = false)]
+        yes: bool,
+    },
+    /// PayName pays the offer that a BIP-353 human-readable name (user@domain) resolves to. lndk
+    /// resolves the name with the DNS resolvers it's configured with.
+    PayName {
+        /// The name to pay, such as alice@example.com.
+        name: String,
+        /// Amount the user would like to pay. If this isn't set, we'll assume the user is paying
+        /// whatever the offer amount is.
+        #[arg(required = false)]
+        amount: Option<u64>,
+        /// A payer-provided note which will be seen by the recipient.
+        #[arg(required = false)]
+        payer_note: Option<String>,
+        /// The amount of time in seconds that the user would like to wait for the name to resolve,
+        /// and then for an invoice to arrive. If this isn't set, we'll use the default value.
+        #[arg(long, required = false)]
+        response_invoice_timeout: Option<u32>,
+        /// A fixed fee limit in millisatoshis.
+        /// Mutually exclusive with fee_limit_percent - only one can be set.
+        #[arg(long, required = false, conflicts_with = "fee_limit_percent")]
+        fee_limit: Option<u32>,
+        /// A percentage-based fee limit of the payment amount.
+        /// Mutually exclusive with fee_limit - only one can be set.
+        #[arg(long, required = false, conflicts_with = "fee_limit")]
+        fee_limit_percent: Option<u32>,
+        /// A unique key for this payment. Retrying with the same key returns the result of the
+        /// original payment rather than paying again.
+        #[arg(long, required = false)]
+        idempotency_key: Option<String>,
+        /// Pay without asking for confirmation first. This is required if lndk-cli isn't run
+        /// from a terminal.
+        #[arg(short, long, required = false)]
+        yes: bool,
     },
     /// GetInvoice fetch a BOLT 12 invoice, which will be returned as a hex-encoded string. It
     /// fetches the invoice from a BOLT 12 offer, provided as a 'lno'-prefaced offer string.
@@ -128,6 +232,12 @@ enum Commands {
         /// arrive. If this isn't set, we'll use the default value.
         #[arg(long, global = false, required = false, default_value = DEFAULT_RESPONSE_INVOICE_TIMEOUT.to_string())]
         response_invoice_timeout: Option<u32>,
+
+        /// A hex-encoded 32 byte id for the invoice request, which can be passed to
+        /// cancel-payment while we're waiting on an invoice. If this isn't set, a random one will
+        /// be used.
+        #[arg(long, required = false)]
+        payment_id: Option<String>,
     },
     /// PayInvoice pays a hex-encoded BOLT12 invoice.
     PayInvoice {
@@ -145,6 +255,18 @@ enum Commands {
         /// Mutually exclusive with fee_limit - only one can be set.
         #[arg(long, required = false, conflicts_with = "fee_limit")]
         fee_limit_percent: Option<u32>,
+        /// A unique key for this payment. Retrying with the same key returns the result of the
+        /// original payment rather than paying again.
+        #[arg(long, required = false)]
+        idempotency_key: Option<String>,
+        /// Show what the payment would cost, including an estimate of the routing fee, without
+        /// paying.
+        #[arg(long, required = false)]
+        dry_run: bool,
+        /// Pay without asking for confirmation first. This is required if lndk-cli isn't run
+        /// from a terminal.
+        #[arg(short, long, required = false)]
+        yes: bool,
     },
     /// CreateOffer creates a BOLT 12 offer.
     CreateOffer {
@@ -165,44 +287,136 @@ enum Commands {
         #[arg(required = false)]
         quantity: Option<u64>,
     },
+    /// EstimateFee estimates the fee for paying a BOLT 12 offer or a hex-encoded BOLT 12 invoice.
+    /// For an offer, an invoice is fetched from the offer's creator first.
+    EstimateFee {
+        /// The offer ('lno'-prefaced) or hex-encoded invoice string.
+        offer_or_invoice: String,
+        /// The amount the user would like to pay. If this isn't set, the offer or invoice amount
+        /// is used.
+        #[arg(required = false)]
+        amount: Option<u64>,
+        /// A fixed fee limit in millisatoshis.
+        /// Mutually exclusive with fee_limit_percent - only one can be set.
+        #[arg(long, required = false, conflicts_with = "fee_limit_percent")]
+        fee_limit: Option<u32>,
+        /// A percentage-based fee limit of the payment amount.
+        /// Mutually exclusive with fee_limit - only one can be set.
+        #[arg(long, required = false, conflicts_with = "fee_limit")]
+        fee_limit_percent: Option<u32>,
+    },
+    /// CancelPayment cancels a payment that's waiting on an invoice, or that has received an
+    /// invoice which hasn't been paid yet.
+    CancelPayment {
+        /// The hex-encoded payment id that was passed to pay-offer or get-invoice.
+        payment_id: String,
+    },
+    /// BakeMacaroon creates an lndk macaroon restricted to a set of permissions. If no macaroon
+    /// is passed in, the lndk admin macaroon in the default location (~.lndk/data) is used.
+    BakeMacaroon {
+        /// A comma-separated list of permissions to grant: read, pay, create-offer, messages or admin.
+        #[arg(required = true, value_delimiter = ',')]
+        permissions: Vec<String>,
+        /// A file path to save the new macaroon to. If not set, the hex-encoded macaroon is
+        /// printed instead.
+        #[arg(long, required = false)]
+        save_to: Option<PathBuf>,
+    },
+    /// ExportPaymentProof exports a proof that an offer was paid, which bundles the offer, the
+    /// signed invoice request and invoice, and the payment preimage as json.
+    ExportPaymentProof {
+        /// The hex-encoded payment hash of the payment.
+        payment_hash: String,
+        /// A file path to save the proof to. If not set, the proof is printed instead.
+        #[arg(long, required = false)]
+        save_to: Option<PathBuf>,
+    },
+    /// VerifyPaymentProof checks the signatures in a proof exported with export-payment-proof,
+    /// and that its preimage matches the invoice's payment hash.
+    VerifyPaymentProof {
+        /// The path to the json proof file.
+        proof_path: PathBuf,
+    },
+    /// SendOnionMessage sends an onion message of a custom type, either to a node or as a reply
+    /// to a message we received.
+    SendOnionMessage {
+        /// The TLV type of the message, which must be at least 64.
+        tlv_type: u64,
+        /// The hex-encoded contents of the message.
+        data: String,
+        /// The hex-encoded pubkey of the node to send the message to.
+        #[arg(long, required = false)]
+        destination: Option<String>,
+        /// The reply token of a message printed by subscribe-onion-messages, to reply to it.
+        #[arg(long, required = false)]
+        reply_token: Option<String>,
+        /// Include a blinded reply path, so that the recipient can respond.
+        #[arg(long, default_value_t = false)]
+        request_reply: bool,
+    },
+    /// SubscribeOnionMessages prints the onion messages of custom types that we receive, until
+    /// interrupted.
+    SubscribeOnionMessages {
+        /// A comma-separated list of the TLV types to print. If not set, messages of every
+        /// custom type are printed.
+        #[arg(long, required = false, value_delimiter = ',')]
+        tlv_types: Vec<u64>,
+    },
+    /// RotateSeed switches lndk to a new seed for authenticating offers and payments. Offers
+    /// created with the previous seed keep working until seed_grace_period_secs has passed.
+    RotateSeed,
+    /// IssueClientCert issues a TLS client certificate for connecting to an LNDK server that
+    /// requires one, from the client CA in lndk's data directory. The CA is created the first time
+    /// a certificate is issued. This needs access to lndk's data directory, but not to a running
+    /// server.
+    IssueClientCert {
+        /// The name of the client, which is used as the certificate's common name and to name the
+        /// files it's written to.
+        name: String,
+        /// The directory to write the certificate and key to. Defaults to the current directory.
+        #[arg(long, required = false)]
+        out_dir: Option<PathBuf>,
+    },
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
+    let out = Output {
+        format: args.output,
+    };
+    let profile = load_profile(args.config.clone(), args.profile.as_deref())
+        .unwrap_or_else(|e| out.user_error(e));
+    let settings = Settings::resolve(&args, profile);
     match args.command {
         Commands::DecodeOffer { offer_string } => {
-            println!("Decoding offer: {offer_string}.");
+            out.info(format!("Decoding offer: {offer_string}."));
             match decode(offer_string) {
-                Ok(offer) => {
-                    println!("Decoded offer: {:?}.", offer)
-                }
-                Err(e) => {
-                    println!(
-                        "ERROR please provide offer starting with lno. Provided offer is \
-                        invalid, failed to decode with error: {:?}.",
-                        e
-                    );
-                    exit(1)
-                }
+                Ok(offer) => out.print(
+                    format!("Decoded offer: {:?}.", offer),
+                    &generate_offer_contents(&offer),
+                ),
+                Err(e) => out.user_error(format!(
+                    "ERROR please provide offer starting with lno. Provided offer is \
+                    invalid, failed to decode with error: {:?}.",
+                    e
+                )),
             }
         }
         Commands::DecodeInvoice { invoice_string } => {
-            println!("Decoding invoice: {invoice_string}.");
+            out.info(format!("Decoding invoice: {invoice_string}."));
 
             let invoice_string: Bolt12InvoiceString = invoice_string.clone().into();
             match Bolt12Invoice::try_from(invoice_string) {
-                Ok(invoice) => {
-                    println!("Decoded invoice: {:?}.", invoice);
-                }
-                Err(e) => {
-                    println!(
-                        "ERROR please provide hex-encoded invoice string. Provided invoice is \
-                        invalid, failed to decode with error: {:?}.",
-                        e
-                    );
-                    exit(1);
-                }
+                Ok(invoice) => out.print(
+                    format!("Decoded invoice: {:?}.", invoice),
+                    &generate_bolt12_invoice_contents(&invoice),
+                ),
+                Err(e) => out.user_error(format!(
+                    "ERROR please provide hex-encoded invoice string. Provided invoice is \
+                    invalid, failed to decode with error: {:?}.",
+                    e
+                )),
             }
         }
         Commands::PayOffer {
@@ -212,43 +426,73 @@ async fn main() {
             response_invoice_timeout,
             fee_limit,
             fee_limit_percent,
+            idempotency_key,
+            payment_id,
+            dry_run,
+            yes,
         } => {
-            let tls = read_cert_from_args_or_exit(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host;
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
-
-            let mut client = OffersClient::new(channel);
-
             let offer = match decode(offer_string.to_owned()) {
                 Ok(offer) => offer,
-                Err(e) => {
-                    println!(
-                        "ERROR: please provide offer starting with lno. Provided offer is \
-                        invalid, failed to decode with error: {:?}.",
-                        e
-                    );
-                    exit(1)
-                }
+                Err(e) => out.user_error(format!(
+                    "ERROR: please provide offer starting with lno. Provided offer is \
+                    invalid, failed to decode with error: {:?}.",
+                    e
+                )),
             };
 
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+
+            // Unless the user has agreed to pay up front, show them what they'd pay first. We fetch
+            // an invoice from the offer, so that what they confirm is the invoice that gets paid.
+            if !yes || dry_run {
+                let mut request = Request::new(GetInvoiceRequest {
+                    offer: offer.to_string(),
+                    amount,
+                    payer_note,
+                    response_invoice_timeout,
+                    payment_id,
+                });
+                add_metadata(&mut request, macaroon.clone()).unwrap_or_else(|e| out.user_error(e));
+                let invoice = match client.get_invoice(request).await {
+                    Ok(response) => response.into_inner().invoice_hex_str,
+                    Err(err) => out.status_error("Error getting invoice for offer", err),
+                };
+
+                let estimate_request = EstimateFeeRequest {
+                    invoice: invoice.clone(),
+                    fee_limit,
+                    fee_limit_percent,
+                    ..Default::default()
+                };
+                let summary = estimate_payment(out, &mut client, &macaroon, estimate_request).await;
+                if !confirm_payment(out, &summary, dry_run, yes) {
+                    return;
+                }
+
+                let request = PayInvoiceRequest {
+                    invoice,
+                    amount: None,
+                    fee_limit,
+                    fee_limit_percent,
+                    idempotency_key,
+                };
+                pay_invoice(
+                    out,
+                    &mut client,
+                    macaroon,
+                    request,
+                    "Successfully paid for offer!",
+                )
+                .await;
+                return;
+            }
+
             let mut request = Request::new(PayOfferRequest {
                 offer: offer.to_string(),
                 amount,
@@ -256,73 +500,101 @@ async fn main() {
                 response_invoice_timeout,
                 fee_limit,
                 fee_limit_percent,
+                idempotency_key,
+                payment_id,
             });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
 
             match client.pay_offer(request).await {
-                Ok(_) => println!("Successfully paid for offer!"),
-                Err(err) => {
-                    println!("Error paying for offer: {err:?}");
-                    exit(1)
-                }
+                Ok(response) => out.print("Successfully paid for offer!", response.get_ref()),
+                Err(err) => out.status_error("Error paying for offer", err),
+            };
+        }
+        Commands::PayName {
+            name,
+            amount,
+            payer_note,
+            response_invoice_timeout,
+            fee_limit,
+            fee_limit_percent,
+            idempotency_key,
+            yes,
+        } => {
+            // The name is resolved by the server as part of the payment, so we can't show what
+            // the payment will cost up front.
+            let amount_text = match amount {
+                Some(amount) => format!("{amount} msats"),
+                None => "the amount in the offer".to_string(),
             };
+            if !yes
+                && !ask_to_pay(
+                    out,
+                    format!("Paying {amount_text} to {name}, plus routing fees."),
+                )
+            {
+                return;
+            }
+
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(PayHumanReadableNameRequest {
+                name: name.clone(),
+                amount,
+                payer_note,
+                response_invoice_timeout,
+                fee_limit,
+                fee_limit_percent,
+                idempotency_key,
+                payment_id: None,
+            });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            match client.pay_human_readable_name(request).await {
+                Ok(response) => out.print(format!("Successfully paid {name}!"), response.get_ref()),
+                Err(err) => out.status_error(&format!("Error paying {name}"), err),
+            }
         }
         Commands::GetInvoice {
             ref offer_string,
             amount,
             payer_note,
             response_invoice_timeout,
+            payment_id,
         } => {
-            let tls = read_cert_from_args_or_exit(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host;
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
-
-            let mut client = OffersClient::new(channel);
             let offer = match decode(offer_string.to_owned()) {
                 Ok(offer) => offer,
-                Err(e) => {
-                    println!(
-                        "ERROR: please provide offer starting with lno. Provided offer is \
-                        invalid, failed to decode with error: {:?}.",
-                        e
-                    );
-                    exit(1)
-                }
+                Err(e) => out.user_error(format!(
+                    "ERROR: please provide offer starting with lno. Provided offer is \
+                    invalid, failed to decode with error: {:?}.",
+                    e
+                )),
             };
 
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
             let mut request = Request::new(GetInvoiceRequest {
                 offer: offer.to_string(),
                 amount,
                 payer_note,
                 response_invoice_timeout,
+                payment_id,
             });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
             match client.get_invoice(request).await {
-                Ok(response) => {
-                    println!("Invoice: {:?}.", response.get_ref())
-                }
-                Err(err) => {
-                    println!("Error getting invoice for offer: {err:?}");
-                    exit(1)
-                }
+                Ok(response) => out.print(
+                    format!("Invoice: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => out.status_error("Error getting invoice for offer", err),
             }
         }
         Commands::PayInvoice {
@@ -330,44 +602,48 @@ async fn main() {
             amount,
             fee_limit,
             fee_limit_percent,
+            idempotency_key,
+            dry_run,
+            yes,
         } => {
-            let tls = read_cert_from_args_or_exit(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host.clone();
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+
+            // Unless the user has agreed to pay up front, show them what they'd pay first.
+            if !yes || dry_run {
+                let estimate_request = EstimateFeeRequest {
+                    invoice: invoice_string.to_owned(),
+                    amount,
+                    fee_limit,
+                    fee_limit_percent,
+                    ..Default::default()
+                };
+                let summary = estimate_payment(out, &mut client, &macaroon, estimate_request).await;
+                if !confirm_payment(out, &summary, dry_run, yes) {
+                    return;
+                }
+            }
 
-            let mut client = OffersClient::new(channel);
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
-            let mut request = Request::new(PayInvoiceRequest {
+            let request = PayInvoiceRequest {
                 invoice: invoice_string.to_owned(),
                 amount,
                 fee_limit,
                 fee_limit_percent,
-            });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
-            match client.pay_invoice(request).await {
-                Ok(_) => println!("Successfully paid for offer!"),
-                Err(err) => {
-                    println!("Error paying invoice: {err:?}");
-                    exit(1)
-                }
-            }
+                idempotency_key,
+            };
+            pay_invoice(
+                out,
+                &mut client,
+                macaroon,
+                request,
+                "Successfully paid for invoice!",
+            )
+            .await;
         }
         Commands::CreateOffer {
             amount,
@@ -376,29 +652,13 @@ async fn main() {
             expiry,
             quantity,
         } => {
-            let tls = read_cert_from_args_or_exit(args.cert_pem, args.cert_path);
-            let grpc_host = args.grpc_host.clone();
-            let grpc_port = args.grpc_port;
-            let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
-                .unwrap_or_else(|e| {
-                    println!("ERROR creating endpoint: {e:?}");
-                    exit(1)
-                })
-                .tls_config(tls)
-                .unwrap_or_else(|e| {
-                    println!("ERROR tls config: {e:?}");
-                    exit(1)
-                })
-                .connect()
-                .await
-                .unwrap_or_else(|e| {
-                    println!("ERROR connecting: {e:?}");
-                    exit(1)
-                });
-
-            let mut client = OffersClient::new(channel);
-            let macaroon =
-                read_macaroon_from_args(args.macaroon_path, args.macaroon_hex, &args.network);
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
             let mut request = Request::new(CreateOfferRequest {
                 amount,
                 quantity,
@@ -406,24 +666,678 @@ async fn main() {
                 issuer,
                 expiry,
             });
-            add_metadata(&mut request, macaroon).unwrap_or_else(|_| exit(1));
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
             match client.create_offer(request).await {
+                Ok(response) => out.print(
+                    format!("Offer: {:?}.", response.get_ref()),
+                    response.get_ref(),
+                ),
+                Err(err) => out.status_error("Error creating offer", err),
+            }
+        }
+        Commands::EstimateFee {
+            offer_or_invoice,
+            amount,
+            fee_limit,
+            fee_limit_percent,
+        } => {
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let (invoice, offer) = if offer_or_invoice.starts_with("lno") {
+                (String::new(), Some(offer_or_invoice))
+            } else {
+                (offer_or_invoice, None)
+            };
+            let mut request = Request::new(EstimateFeeRequest {
+                invoice,
+                amount,
+                fee_limit,
+                fee_limit_percent,
+                offer,
+                response_invoice_timeout: None,
+            });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            match client.estimate_fee(request).await {
                 Ok(response) => {
-                    println!("Offer: {:?}.", response.get_ref())
+                    let estimate = response.get_ref();
+                    out.print(
+                        format!(
+                            "Estimated fee for paying {} msats: {} msats over the cheapest of {} \
+                            routes, which is the one the payment is made over. The most expensive \
+                            route costs {} msats.",
+                            estimate.amount_msats,
+                            estimate.min_fee_msats,
+                            estimate.routable_paths,
+                            estimate.max_fee_msats
+                        ),
+                        estimate,
+                    )
+                }
+                Err(err) => out.status_error("Error estimating fee", err),
+            }
+        }
+        Commands::CancelPayment { payment_id } => {
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(CancelPaymentRequest { payment_id });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            match client.cancel_payment(request).await {
+                Ok(response) => out.print("Payment cancelled.", response.get_ref()),
+                Err(err) => out.status_error("Error cancelling payment", err),
+            }
+        }
+        Commands::BakeMacaroon {
+            permissions,
+            save_to,
+        } => {
+            let mut client = connect(out, &settings).await;
+            // Only lndk macaroons can bake new macaroons, so default to lndk's admin macaroon
+            // rather than LND's.
+            let macaroon_path = match (&settings.macaroon_path, &settings.macaroon_hex) {
+                (None, None) => Some(settings.data_dir.join(ADMIN_MACAROON_FILENAME)),
+                _ => settings.macaroon_path,
+            };
+            let macaroon = read_macaroon_from_args(
+                out,
+                macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(BakeMacaroonRequest { permissions });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            match client.bake_macaroon(request).await {
+                Ok(response) => {
+                    let macaroon = response.into_inner().macaroon;
+                    match save_to {
+                        Some(path) => {
+                            let bytes = hex::decode(&macaroon).unwrap_or_else(|e| {
+                                out.server_error(format!("ERROR decoding macaroon: {e:?}"))
+                            });
+                            std::fs::write(&path, bytes).unwrap_or_else(|e| {
+                                out.user_error(format!("ERROR writing macaroon to {path:?}: {e:?}"))
+                            });
+                            out.print(
+                                format!("Macaroon saved to {path:?}."),
+                                &json!({ "macaroon": macaroon, "saved_to": path }),
+                            );
+                        }
+                        None => out.print(
+                            format!("Macaroon: {macaroon}"),
+                            &json!({ "macaroon": macaroon }),
+                        ),
+                    }
+                }
+                Err(err) => out.status_error("Error baking macaroon", err),
+            }
+        }
+        Commands::ExportPaymentProof {
+            payment_hash,
+            save_to,
+        } => {
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(ExportPaymentProofRequest { payment_hash });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            let proof = match client.export_payment_proof(request).await {
+                Ok(response) => response.into_inner(),
+                Err(err) => out.status_error("Error exporting payment proof", err),
+            };
+            let json = serde_json::to_string_pretty(&proof).unwrap_or_else(|e| {
+                out.server_error(format!("ERROR serializing payment proof: {e:?}"))
+            });
+            match save_to {
+                Some(path) => {
+                    std::fs::write(&path, json).unwrap_or_else(|e| {
+                        out.user_error(format!("ERROR writing proof to {path:?}: {e:?}"))
+                    });
+                    out.print(
+                        format!("Payment proof saved to {path:?}."),
+                        &json!({ "saved_to": path }),
+                    );
+                }
+                None => out.print(json, &proof),
+            }
+        }
+        Commands::VerifyPaymentProof { proof_path } => {
+            let contents = std::fs::read_to_string(&proof_path).unwrap_or_else(|e| {
+                out.user_error(format!("ERROR reading proof from {proof_path:?}: {e:?}"))
+            });
+            let proof: PaymentProof = serde_json::from_str(&contents)
+                .unwrap_or_else(|e| out.user_error(format!("ERROR parsing proof: {e:?}")));
+
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(VerifyPaymentProofRequest { proof: Some(proof) });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            let verified = match client.verify_payment_proof(request).await {
+                Ok(response) => response.into_inner(),
+                Err(err) => out.status_error("Error verifying payment proof", err),
+            };
+            if !verified.valid {
+                out.user_error(format!("Payment proof is invalid: {}", verified.error));
+            }
+            out.print(
+                format!(
+                    "Payment proof is valid: {} msats were paid for payment hash {}.",
+                    verified.amount_msats, verified.payment_hash
+                ),
+                &verified,
+            );
+        }
+        Commands::SendOnionMessage {
+            tlv_type,
+            data,
+            destination,
+            reply_token,
+            request_reply,
+        } => {
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(SendCustomOnionMessageRequest {
+                tlv_type,
+                data,
+                destination: destination.unwrap_or_default(),
+                reply_token: reply_token.unwrap_or_default(),
+                request_reply,
+            });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            match client.send_custom_onion_message(request).await {
+                Ok(response) => out.print("Onion message queued.", response.get_ref()),
+                Err(err) => out.status_error("Error sending onion message", err),
+            }
+        }
+        Commands::SubscribeOnionMessages { tlv_types } => {
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(SubscribeCustomOnionMessagesRequest { tlv_types });
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            let mut stream = match client.subscribe_custom_onion_messages(request).await {
+                Ok(response) => response.into_inner(),
+                Err(err) => out.status_error("Error subscribing to onion messages", err),
+            };
+            out.info("Waiting for onion messages...");
+            loop {
+                match stream.message().await {
+                    Ok(Some(message)) => out.print(
+                        format!(
+                            "Received message of type {}: {} (reply token: {})",
+                            message.tlv_type,
+                            message.data,
+                            if message.reply_token.is_empty() {
+                                "none"
+                            } else {
+                                &message.reply_token
+                            }
+                        ),
+                        &message,
+                    ),
+                    Ok(None) => break,
+                    Err(err) => out.status_error("Error receiving onion messages", err),
                 }
-                Err(err) => {
-                    println!("Error creating offer: {err:?}");
-                    exit(1)
+            }
+        }
+        Commands::RotateSeed => {
+            let mut client = connect(out, &settings).await;
+            let macaroon = read_macaroon_from_args(
+                out,
+                settings.macaroon_path,
+                settings.macaroon_hex,
+                &settings.network,
+            );
+            let mut request = Request::new(RotateSeedRequest {});
+            add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+            match client.rotate_seed(request).await {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    out.print(
+                        format!(
+                            "Rotated seed to key index {}. Still accepting key indexes: {:?}",
+                            response.key_index, response.retired_key_indexes
+                        ),
+                        &response,
+                    )
                 }
+                Err(err) => out.status_error("Error rotating seed", err),
             }
         }
+        Commands::IssueClientCert { name, out_dir } => {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                out.user_error(
+                    "ERROR: the client name may only contain letters, numbers, '-' and '_'.",
+                );
+            }
+
+            let cert = issue_client_cert(&settings.data_dir, &name).unwrap_or_else(|e| {
+                out.user_error(format!("ERROR issuing client certificate: {e}"))
+            });
+            let out_dir = out_dir.unwrap_or_else(|| PathBuf::from("."));
+            let cert_path = out_dir.join(format!("{name}-cert.pem"));
+            let key_path = out_dir.join(format!("{name}-key.pem"));
+            cert.write(&cert_path, &key_path).unwrap_or_else(|e| {
+                out.user_error(format!("ERROR saving client certificate: {e:?}"))
+            });
+
+            out.print(
+                format!(
+                    "Issued client certificate {cert_path:?} with key {key_path:?}. Its \
+                    fingerprint is {}.",
+                    cert.fingerprint
+                ),
+                &json!({
+                    "cert_path": cert_path,
+                    "key_path": key_path,
+                    "fingerprint": cert.fingerprint,
+                }),
+            );
+        }
+    }
+}
+
+/// What the user is about to pay, which we show them before paying.
+#[derive(Debug, Serialize)]
+struct PaymentSummary {
+    amount_msats: u64,
+    /// The range of fees across the routes to the invoice's payment paths.
+    min_fee_msats: u64,
+    max_fee_msats: u64,
+    /// The range of absolute block heights that the payment's HTLCs would time out at.
+    min_total_time_lock: u32,
+    max_total_time_lock: u32,
+    /// The hex-encoded key that signed the invoice.
+    payee: String,
+    description: Option<String>,
+    /// When the invoice expires, in seconds since the unix epoch.
+    expires_at: i64,
+}
+
+impl Display for PaymentSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Amount: {} msats", self.amount_msats)?;
+        // The payment is made over the cheapest route, so that's the fee the user will pay.
+        writeln!(f, "Estimated fee: {} msats", self.min_fee_msats)?;
+        writeln!(f, "Payee: {}", self.payee)?;
+        writeln!(
+            f,
+            "Description: {}",
+            self.description.as_deref().unwrap_or("none")
+        )?;
+        write!(f, "Expires at: {} (unix time)", self.expires_at)
+    }
+}
+
+// Asks the server what paying an invoice or offer would cost, and puts that together with the
+// details of the invoice that the estimate is for.
+async fn estimate_payment(
+    out: Output,
+    client: &mut OffersClient<Channel>,
+    macaroon: &str,
+    request: EstimateFeeRequest,
+) -> PaymentSummary {
+    let mut request = Request::new(request);
+    add_metadata(&mut request, macaroon.to_string()).unwrap_or_else(|e| out.user_error(e));
+    let estimate = match client.estimate_fee(request).await {
+        Ok(response) => response.into_inner(),
+        Err(err) => out.status_error("Error estimating fee", err),
+    };
+    let invoice = Bolt12Invoice::try_from(Bolt12InvoiceString::from(estimate.invoice))
+        .unwrap_or_else(|e| {
+            out.server_error(format!(
+                "ERROR: the server returned an invalid invoice with its estimate: {e:?}"
+            ))
+        });
+    let contents = generate_bolt12_invoice_contents(&invoice);
+
+    PaymentSummary {
+        amount_msats: estimate.amount_msats,
+        min_fee_msats: estimate.min_fee_msats,
+        max_fee_msats: estimate.max_fee_msats,
+        min_total_time_lock: estimate.min_total_time_lock,
+        max_total_time_lock: estimate.max_total_time_lock,
+        payee: contents
+            .node_id
+            .as_ref()
+            .map(|node_id| hex::encode(&node_id.key))
+            .unwrap_or_default(),
+        description: contents.description.clone(),
+        expires_at: contents.created_at + contents.relative_expiry as i64,
+    }
+}
+
+// Shows the user what they're about to pay. With --dry-run we stop there, and otherwise we ask
+// them to confirm unless they passed --yes. Returns whether we should go ahead with the payment.
+fn confirm_payment(out: Output, summary: &PaymentSummary, dry_run: bool, yes: bool) -> bool {
+    if dry_run {
+        out.print(summary, summary);
+        return false;
+    }
+    yes || ask_to_pay(out, summary)
+}
+
+// Asks the user whether to go ahead with a payment.
+fn ask_to_pay(out: Output, details: impl Display) -> bool {
+    // We only ask on a terminal, so that a script can't end up paying by accident.
+    if !std::io::stdin().is_terminal() {
+        out.user_error(
+            "ERROR: not paying without confirmation. Pass --yes to pay without being asked.",
+        );
+    }
+    eprintln!("{details}");
+    eprint!("Pay? [y/N] ");
+    let mut answer = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut answer) {
+        out.user_error(format!("ERROR reading confirmation: {e:?}"));
+    }
+
+    let confirmed = matches!(answer.trim().to_lowercase().as_str(), "y" | "yes");
+    if !confirmed {
+        out.print("Payment cancelled.", &json!({ "paid": false }));
+    }
+    confirmed
+}
+
+async fn pay_invoice(
+    out: Output,
+    client: &mut OffersClient<Channel>,
+    macaroon: String,
+    request: PayInvoiceRequest,
+    success_text: &str,
+) {
+    let mut request = Request::new(request);
+    add_metadata(&mut request, macaroon).unwrap_or_else(|e| out.user_error(e));
+    match client.pay_invoice(request).await {
+        Ok(response) => out.print(success_text, response.get_ref()),
+        Err(err) => out.status_error("Error paying invoice", err),
+    }
+}
+
+/// The format lndk-cli prints its results and errors in.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// The exit code used when a command fails because of the input it was given.
+const EXIT_USER_ERROR: i32 = 2;
+/// The exit code used when a command fails because of an error in lndk (or in reaching it).
+const EXIT_SERVER_ERROR: i32 = 3;
+
+// Prints the results of a command in the format the user asked for.
+#[derive(Clone, Copy)]
+struct Output {
+    format: OutputFormat,
+}
+
+impl Output {
+    // Prints progress information, which is only shown in text mode.
+    fn info(&self, text: impl Display) {
+        if self.format == OutputFormat::Text {
+            println!("{text}");
+        }
+    }
+
+    // Prints the result of a successful command, either as the text provided or as json.
+    fn print<T: Serialize>(&self, text: impl Display, value: &T) {
+        match self.format {
+            OutputFormat::Text => println!("{text}"),
+            OutputFormat::Json => match serde_json::to_string_pretty(value) {
+                Ok(json) => println!("{json}"),
+                Err(e) => self.server_error(format!("ERROR serializing output: {e:?}")),
+            },
+        }
+    }
+
+    // Prints an error caused by the user's input and exits.
+    fn user_error(&self, message: impl Display) -> ! {
+        let message = message.to_string();
+        self.exit_with_error(Code::InvalidArgument, &message, &message)
+    }
+
+    // Prints an error that isn't the user's fault and exits.
+    fn server_error(&self, message: impl Display) -> ! {
+        let message = message.to_string();
+        self.exit_with_error(Code::Internal, &message, &message)
+    }
+
+    // Prints an error returned by the server and exits. The exit code depends on whether the
+    // server blamed the request.
+    fn status_error(&self, context: &str, status: Status) -> ! {
+        self.exit_with_error(
+            status.code(),
+            &format!("{context}: {status:?}"),
+            &format!("{context}: {}", status.message()),
+        )
+    }
+
+    fn exit_with_error(&self, code: Code, text: &str, message: &str) -> ! {
+        match self.format {
+            OutputFormat::Text => println!("{text}"),
+            OutputFormat::Json => println!(
+                "{}",
+                json!({ "error": { "code": format!("{code:?}"), "message": message } })
+            ),
+        }
+        exit(exit_code(code))
+    }
+}
+
+// Maps a grpc code to our exit codes. Codes that mean the request itself was wrong are user
+// errors, and everything else is on the server.
+fn exit_code(code: Code) -> i32 {
+    match code {
+        Code::InvalidArgument
+        | Code::NotFound
+        | Code::AlreadyExists
+        | Code::PermissionDenied
+        | Code::Unauthenticated
+        | Code::FailedPrecondition
+        | Code::OutOfRange => EXIT_USER_ERROR,
+        _ => EXIT_SERVER_ERROR,
+    }
+}
+
+/// The lndk-cli config file, which holds named profiles of settings for the nodes the cli talks
+/// to. It's a toml file that looks like:
+///
+/// default_profile = "mainnet"
+///
+/// [profiles.mainnet]
+/// network = "mainnet"
+/// grpc_host = "https://10.0.0.2"
+/// macaroon_path = "/credentials/lndk-pay.macaroon"
+/// data_dir = "/mnt/lndk/data"
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CliConfig {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// A set of settings from the cli config file. Each field corresponds to the global option of the
+/// same name.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    network: Option<String>,
+    macaroon_path: Option<PathBuf>,
+    macaroon_hex: Option<String>,
+    cert_pem: Option<String>,
+    cert_path: Option<PathBuf>,
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+    grpc_host: Option<String>,
+    grpc_port: Option<u16>,
+    data_dir: Option<PathBuf>,
+}
+
+// Reads the profile to use from the config file. If no profile was asked for and the config file
+// doesn't set a default, an empty profile is returned. A missing config file is only an error if
+// it was passed in explicitly, or a profile was asked for.
+fn load_profile(config_path: Option<PathBuf>, profile: Option<&str>) -> Result<Profile, String> {
+    let path = config_path
+        .clone()
+        .unwrap_or_else(get_cli_config_path_default);
+    if !path.exists() {
+        if config_path.is_some() || profile.is_some() {
+            return Err(format!("ERROR: cli config file {path:?} not found."));
+        }
+        return Ok(Profile::default());
+    }
+
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|e| format!("ERROR reading cli config file {path:?}: {e:?}"))?;
+    let config: CliConfig = toml::from_str(&contents)
+        .map_err(|e| format!("ERROR parsing cli config file {path:?}: {e}"))?;
+
+    match profile.or(config.default_profile.as_deref()) {
+        Some(name) => config
+            .profiles
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("ERROR: profile {name} not found in {path:?}.")),
+        None => Ok(Profile::default()),
+    }
+}
+
+// The settings a command connects to lndk with, taken from the command line, the selected
+// profile and our defaults, in that order.
+#[derive(Debug, PartialEq)]
+struct Settings {
+    network: String,
+    macaroon_path: Option<PathBuf>,
+    macaroon_hex: Option<String>,
+    cert_pem: Option<String>,
+    cert_path: Option<PathBuf>,
+    client_cert_path: Option<PathBuf>,
+    client_key_path: Option<PathBuf>,
+    grpc_host: String,
+    grpc_port: u16,
+    data_dir: PathBuf,
+}
+
+impl Settings {
+    fn resolve(args: &Cli, profile: Profile) -> Self {
+        // Only one of each pair of macaroon and cert options can be set, so if either is passed on
+        // the command line we ignore the profile's.
+        let (macaroon_path, macaroon_hex) =
+            if args.macaroon_path.is_some() || args.macaroon_hex.is_some() {
+                (args.macaroon_path.clone(), args.macaroon_hex.clone())
+            } else {
+                (profile.macaroon_path, profile.macaroon_hex)
+            };
+        let (cert_pem, cert_path) = if args.cert_pem.is_some() || args.cert_path.is_some() {
+            (args.cert_pem.clone(), args.cert_path.clone())
+        } else {
+            (profile.cert_pem, profile.cert_path)
+        };
+        let (client_cert_path, client_key_path) =
+            if args.client_cert_path.is_some() || args.client_key_path.is_some() {
+                (args.client_cert_path.clone(), args.client_key_path.clone())
+            } else {
+                (profile.client_cert_path, profile.client_key_path)
+            };
+
+        Settings {
+            network: args
+                .network
+                .clone()
+                .or(profile.network)
+                .unwrap_or_else(|| DEFAULT_NETWORK.to_string()),
+            macaroon_path,
+            macaroon_hex,
+            cert_pem,
+            cert_path,
+            client_cert_path,
+            client_key_path,
+            grpc_host: args
+                .grpc_host
+                .clone()
+                .or(profile.grpc_host)
+                .unwrap_or_else(|| format!("https://{DEFAULT_SERVER_HOST}")),
+            grpc_port: args
+                .grpc_port
+                .or(profile.grpc_port)
+                .unwrap_or(DEFAULT_SERVER_PORT),
+            data_dir: args
+                .data_dir
+                .clone()
+                .or(profile.data_dir)
+                .unwrap_or_else(get_data_dir_default),
+        }
+    }
+}
+
+// Connects to the lndk server, exiting if we can't.
+async fn connect(out: Output, settings: &Settings) -> OffersClient<Channel> {
+    let mut tls = read_cert_from_args(
+        settings.cert_pem.clone(),
+        settings.cert_path.clone(),
+        &settings.data_dir,
+    )
+    .unwrap_or_else(|e| out.user_error(e));
+    if let Some(identity) = read_client_identity(
+        settings.client_cert_path.as_deref(),
+        settings.client_key_path.as_deref(),
+    )
+    .unwrap_or_else(|e| out.user_error(e))
+    {
+        tls = tls.identity(identity);
     }
+    let grpc_host = &settings.grpc_host;
+    let grpc_port = settings.grpc_port;
+    let channel = Channel::from_shared(format!("{grpc_host}:{grpc_port}"))
+        .unwrap_or_else(|e| out.user_error(format!("ERROR creating endpoint: {e:?}")))
+        .tls_config(tls)
+        .unwrap_or_else(|e| out.user_error(format!("ERROR tls config: {e:?}")))
+        .connect()
+        .await
+        .unwrap_or_else(|e| {
+            let message = format!("ERROR connecting: {e:?}");
+            out.exit_with_error(Code::Unavailable, &message, &message)
+        });
+
+    OffersClient::new(channel)
 }
 
-fn add_metadata<R>(request: &mut Request<R>, macaroon: String) -> Result<(), ()> {
-    let macaroon = macaroon.parse().map_err(|e| {
-        println!("Error parsing provided macaroon string into tonic metadata {e:?}")
-    })?;
+fn add_metadata<R>(request: &mut Request<R>, macaroon: String) -> Result<(), String> {
+    let macaroon = macaroon
+        .parse()
+        .map_err(|e| format!("Error parsing provided macaroon string into tonic metadata {e:?}"))?;
     request.metadata_mut().insert("macaroon", macaroon);
 
     Ok(())
@@ -441,6 +1355,7 @@ fn read_macaroon_from_file(path: PathBuf) -> Result<String, std::io::Error> {
 fn read_cert_from_args(
     cert_pem: Option<String>,
     cert_path: Option<PathBuf>,
+    data_dir: &Path,
 ) -> Result<ClientTlsConfig, String> {
     // Make sure both cert options are not set.
     if cert_path.is_some() && cert_pem.is_some() {
@@ -452,12 +1367,8 @@ fn read_cert_from_args(
         (None, Some(cert_path)) => std::fs::read_to_string(cert_path)
             .map_err(|e| format!("ERROR reading cert: {:?}", e))?,
         (None, None) => {
-            // If no cert pem string is provided, we'll look for the tls certificate in the
-            // default location.
-            let data_dir = home::home_dir()
-                .unwrap()
-                .join(DEFAULT_LNDK_DIR)
-                .join(DEFAULT_DATA_DIR);
+            // If no cert pem string is provided, we'll look for the tls certificate lndk
+            // generates in its data directory.
             std::fs::read_to_string(data_dir.join(TLS_CERT_FILENAME))
                 .map_err(|e| format!("ERROR reading cert: {:?}", e))?
         }
@@ -468,44 +1379,48 @@ fn read_cert_from_args(
         .domain_name("localhost"))
 }
 
-fn read_cert_from_args_or_exit(
-    cert_pem: Option<String>,
-    cert_path: Option<PathBuf>,
-) -> ClientTlsConfig {
-    match read_cert_from_args(cert_pem, cert_path) {
-        Ok(config) => config,
-        Err(err) => {
-            println!("{}", err);
-            exit(1);
+// Reads the client certificate and key to authenticate to the server with, if they're set.
+fn read_client_identity(
+    cert_path: Option<&Path>,
+    key_path: Option<&Path>,
+) -> Result<Option<Identity>, String> {
+    match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let cert = std::fs::read_to_string(cert_path)
+                .map_err(|e| format!("ERROR reading client cert: {:?}", e))?;
+            let key = std::fs::read_to_string(key_path)
+                .map_err(|e| format!("ERROR reading client key: {:?}", e))?;
+            Ok(Some(Identity::from_pem(cert, key)))
+        }
+        (None, None) => Ok(None),
+        _ => {
+            Err("ERROR: `client_cert_path` and `client_key_path` must be set together.".to_string())
         }
     }
 }
 
 fn read_macaroon_from_args(
+    out: Output,
     macaroon_path: Option<PathBuf>,
     macaroon_hex: Option<String>,
     network: &str,
 ) -> String {
     // Make sure both macaroon options are not set.
     if macaroon_path.is_some() && macaroon_hex.is_some() {
-        println!("ERROR: Only one of `macaroon_path` or `macaroon_hex` should be set.");
-        exit(1)
+        out.user_error("ERROR: Only one of `macaroon_path` or `macaroon_hex` should be set.");
     }
 
     // Let's grab the macaroon string now. If neither macaroon_path nor macaroon_hex are
     // set, use the default macaroon path.
     match macaroon_path {
-        Some(path) => read_macaroon_from_file(path.clone()).unwrap_or_else(|e| {
-            println!("ERROR reading macaroon from file {e:?}");
-            exit(1)
-        }),
+        Some(path) => read_macaroon_from_file(path.clone())
+            .unwrap_or_else(|e| out.user_error(format!("ERROR reading macaroon from file {e:?}"))),
         None => match &macaroon_hex {
             Some(macaroon) => macaroon.clone(),
             None => {
                 let path = get_macaroon_path_default(network);
                 read_macaroon_from_file(path).unwrap_or_else(|e| {
-                    println!("ERROR reading macaroon from file {e:?}");
-                    exit(1)
+                    out.user_error(format!("ERROR reading macaroon from file {e:?}"))
                 })
             }
         },
@@ -534,7 +1449,8 @@ mod tests {
     fn test_read_cert_from_args_both_options() {
         let (temp_path, cert_content, _dir) = create_temp_cert_file();
 
-        let result = read_cert_from_args(Some(cert_content), Some(temp_path));
+        let result =
+            read_cert_from_args(Some(cert_content), Some(temp_path), &get_data_dir_default());
 
         assert!(result.is_err());
         assert_eq!(
@@ -547,7 +1463,11 @@ mod tests {
     fn test_read_cert_from_args_cert_pem_only() {
         let cert_content = "-----BEGIN CERTIFICATE-----\nMIIBCgKCAQEA\n-----END CERTIFICATE-----";
 
-        let result = read_cert_from_args(Some(cert_content.to_string()), None);
+        let result = read_cert_from_args(
+            Some(cert_content.to_string()),
+            None,
+            &get_data_dir_default(),
+        );
 
         assert!(result.is_ok());
     }
@@ -556,7 +1476,7 @@ mod tests {
     fn test_read_cert_from_args_cert_path_only() {
         let (file_path, _cert_content, _dir) = create_temp_cert_file();
 
-        let result = read_cert_from_args(None, Some(file_path));
+        let result = read_cert_from_args(None, Some(file_path), &get_data_dir_default());
 
         assert!(result.is_ok());
     }
@@ -565,7 +1485,7 @@ mod tests {
     fn test_read_cert_from_args_invalid_path() {
         let invalid_path = PathBuf::from("/path/does/not/exist.pem");
 
-        let result = read_cert_from_args(None, Some(invalid_path));
+        let result = read_cert_from_args(None, Some(invalid_path), &get_data_dir_default());
 
         assert!(result.is_err());
         assert!(result.unwrap_err().starts_with("ERROR reading cert:"));
@@ -586,12 +1506,26 @@ mod tests {
 
         let result = {
             let _guard = EnvironmentGuard::new("HOME", temp_home_path.to_str().unwrap());
-            read_cert_from_args(None, None)
+            read_cert_from_args(None, None, &get_data_dir_default())
         };
 
         assert!(result.is_ok());
     }
 
+    #[test]
+    fn test_read_client_identity() {
+        let (cert_path, _cert_content, _dir) = create_temp_cert_file();
+
+        assert!(read_client_identity(None, None).unwrap().is_none());
+        assert!(read_client_identity(Some(&cert_path), Some(&cert_path))
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            read_client_identity(Some(&cert_path), None).unwrap_err(),
+            "ERROR: `client_cert_path` and `client_key_path` must be set together."
+        );
+    }
+
     struct EnvironmentGuard<'a> {
         key: &'a str,
         original_value: Option<String>,
@@ -654,4 +1588,173 @@ mod tests {
 
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_output_format() {
+        let args = Cli::try_parse_from(["lndk-cli", "decode-offer", "lno1qcp4256ypq"]).unwrap();
+        assert_eq!(args.output, OutputFormat::Text);
+
+        let args = Cli::try_parse_from([
+            "lndk-cli",
+            "decode-offer",
+            "lno1qcp4256ypq",
+            "--output",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(args.output, OutputFormat::Json);
+
+        assert!(Cli::try_parse_from([
+            "lndk-cli",
+            "--output",
+            "yaml",
+            "decode-offer",
+            "lno1qcp4256ypq"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(exit_code(Code::InvalidArgument), EXIT_USER_ERROR);
+        assert_eq!(exit_code(Code::PermissionDenied), EXIT_USER_ERROR);
+        assert_eq!(exit_code(Code::Internal), EXIT_SERVER_ERROR);
+        assert_eq!(exit_code(Code::Unavailable), EXIT_SERVER_ERROR);
+    }
+
+    #[test]
+    fn test_read_cert_from_data_dir() {
+        let data_dir = tempdir().unwrap();
+        let cert_content = "-----BEGIN CERTIFICATE-----\nMIIBCgKCAQEA\n-----END CERTIFICATE-----";
+        std::fs::write(data_dir.path().join(TLS_CERT_FILENAME), cert_content).unwrap();
+
+        assert!(read_cert_from_args(None, None, data_dir.path()).is_ok());
+    }
+
+    fn write_cli_config(contents: &str) -> (PathBuf, TempDir) {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(DEFAULT_CLI_CONFIG_FILE_NAME);
+        std::fs::write(&path, contents).unwrap();
+        (path, dir)
+    }
+
+    const CLI_CONFIG: &str = r#"
+default_profile = "regtest"
+
+[profiles.regtest]
+grpc_port = 7001
+
+[profiles.mainnet]
+network = "mainnet"
+grpc_host = "https://10.0.0.2"
+macaroon_path = "/credentials/lndk-pay.macaroon"
+data_dir = "/mnt/lndk/data"
+"#;
+
+    #[test]
+    fn test_load_profile() {
+        let (path, _dir) = write_cli_config(CLI_CONFIG);
+
+        let profile = load_profile(Some(path.clone()), Some("mainnet")).unwrap();
+        assert_eq!(profile.network, Some("mainnet".to_string()));
+        assert_eq!(profile.data_dir, Some(PathBuf::from("/mnt/lndk/data")));
+
+        // Without a profile, the default one is used.
+        let profile = load_profile(Some(path.clone()), None).unwrap();
+        assert_eq!(profile.grpc_port, Some(7001));
+
+        assert!(load_profile(Some(path), Some("testnet")).is_err());
+    }
+
+    #[test]
+    fn test_load_profile_missing_config() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(DEFAULT_CLI_CONFIG_FILE_NAME);
+
+        assert!(load_profile(Some(path.clone()), None).is_err());
+        assert!(load_profile(Some(path), Some("mainnet")).is_err());
+    }
+
+    #[test]
+    fn test_load_profile_unknown_field() {
+        let (path, _dir) = write_cli_config("[profiles.regtest]\ngrpc_prot = 7001\n");
+
+        assert!(load_profile(Some(path), Some("regtest")).is_err());
+    }
+
+    #[test]
+    fn test_resolve_settings() {
+        let (path, _dir) = write_cli_config(CLI_CONFIG);
+        let profile = load_profile(Some(path), Some("mainnet")).unwrap();
+
+        // Options on the command line take precedence over the profile, and anything set in
+        // neither falls back to our defaults.
+        let args = Cli::try_parse_from([
+            "lndk-cli",
+            "--macaroon-hex",
+            "0201",
+            "--grpc-host",
+            "https://10.0.0.3",
+            "decode-offer",
+            "lno1qcp4256ypq",
+        ])
+        .unwrap();
+        let settings = Settings::resolve(&args, profile);
+
+        assert_eq!(settings.network, "mainnet");
+        assert_eq!(settings.grpc_host, "https://10.0.0.3");
+        assert_eq!(settings.grpc_port, DEFAULT_SERVER_PORT);
+        assert_eq!(settings.macaroon_hex, Some("0201".to_string()));
+        assert!(settings.macaroon_path.is_none());
+        assert_eq!(settings.data_dir, PathBuf::from("/mnt/lndk/data"));
+    }
+
+    #[test]
+    fn test_payment_summary() {
+        let summary = PaymentSummary {
+            amount_msats: 20_000,
+            min_fee_msats: 150,
+            max_fee_msats: 150,
+            min_total_time_lock: 900,
+            max_total_time_lock: 900,
+            payee: "02abcd".to_string(),
+            description: None,
+            expires_at: 1_700_007_200,
+        };
+
+        let text = summary.to_string();
+        assert!(text.contains("Amount: 20000 msats"));
+        assert!(text.contains("Estimated fee: 150 msats"));
+        assert!(text.contains("Description: none"));
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["min_fee_msats"], 150);
+
+        let summary = PaymentSummary {
+            max_fee_msats: 300,
+            ..summary
+        };
+        assert!(summary.to_string().contains("Estimated fee: 150 msats"));
+        assert_eq!(json["payee"], "02abcd");
+    }
+
+    #[test]
+    fn test_dry_run_doesnt_pay() {
+        let summary = PaymentSummary {
+            amount_msats: 20_000,
+            min_fee_msats: 150,
+            max_fee_msats: 150,
+            min_total_time_lock: 900,
+            max_total_time_lock: 900,
+            payee: "02abcd".to_string(),
+            description: Some("coffee".to_string()),
+            expires_at: 1_700_007_200,
+        };
+        let out = Output {
+            format: OutputFormat::Json,
+        };
+
+        assert!(!confirm_payment(out, &summary, true, true));
+        assert!(confirm_payment(out, &summary, false, true));
+    }
 }
@@ -0,0 +1,401 @@
+use lightning::blinded_path::message::MessageContext;
+use lightning::io::{self, Cursor, Read};
+use lightning::ln::msgs::DecodeError;
+use lightning::onion_message::messenger::{
+    CustomOnionMessageHandler, Destination, MessageSendInstructions, Responder, ResponseInstruction,
+};
+use lightning::onion_message::packet::OnionMessageContents;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use log::{debug, trace};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// The lowest TLV type that can be used for a message. Types below 64 are reserved for the fields
+/// of the onion message payload itself.
+pub const MIN_CUSTOM_MESSAGE_TYPE: u64 = 64;
+
+// The message types that LDK handles itself: offers (64, 66, 68), async payments (72, 74) and
+// DNS resolution (65536, 65538). These never reach the custom message handler.
+const RESERVED_MESSAGE_TYPES: [u64; 7] = [64, 66, 68, 72, 74, 65536, 65538];
+
+// The number of received messages we buffer for each subscriber before it starts missing them.
+pub(crate) const SUBSCRIBER_BUFFER: usize = 100;
+
+/// CustomMessage is an onion message with a TLV type that isn't handled by lndk itself. The data
+/// is passed along as is, it's up to the sender and recipient to agree on its encoding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomMessage {
+    pub tlv_type: u64,
+    pub data: Vec<u8>,
+}
+
+impl OnionMessageContents for CustomMessage {
+    fn tlv_type(&self) -> u64 {
+        self.tlv_type
+    }
+
+    fn msg_type(&self) -> &'static str {
+        "Custom"
+    }
+}
+
+impl Writeable for CustomMessage {
+    fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+        writer.write_all(&self.data)
+    }
+}
+
+/// ReceivedCustomMessage is a custom message that we received, along with the responder to use
+/// if the sender would like a reply.
+#[derive(Clone, Debug)]
+pub struct ReceivedCustomMessage {
+    pub message: CustomMessage,
+    pub responder: Option<Responder>,
+}
+
+/// CustomMessageHandler is implemented by applications that want to handle custom onion messages
+/// of a TLV type themselves.
+pub trait CustomMessageHandler: Send + Sync {
+    /// Handles a message of the type that the handler was registered for. Any message returned
+    /// is sent back to the sender, if they included a reply path.
+    fn handle_custom_message(
+        &self,
+        message: CustomMessage,
+        context: Option<Vec<u8>>,
+    ) -> Option<CustomMessage>;
+}
+
+#[derive(Debug, PartialEq)]
+/// CustomMessageError is an error that occurs when registering a handler or sending a message.
+pub enum CustomMessageError {
+    /// The TLV type is reserved for the onion message payload, or for messages lndk handles.
+    ReservedType(u64),
+    /// A handler is already registered for the TLV type.
+    AlreadyRegistered(u64),
+}
+
+impl Display for CustomMessageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CustomMessageError::ReservedType(t) => write!(
+                f,
+                "TLV type {t} is reserved, custom messages must use a type of at least \
+                {MIN_CUSTOM_MESSAGE_TYPE} that lndk doesn't handle itself"
+            ),
+            CustomMessageError::AlreadyRegistered(t) => {
+                write!(f, "A handler is already registered for TLV type {t}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for CustomMessageError {}
+
+/// CustomMessenger is the onion messenger's handler for custom messages. Applications can
+/// register handlers for the TLV types they're interested in, subscribe to every message we
+/// receive, and send messages of their own.
+pub struct CustomMessenger {
+    handlers: Mutex<HashMap<u64, Arc<dyn CustomMessageHandler>>>,
+    subscribers: broadcast::Sender<ReceivedCustomMessage>,
+    pending_messages: Mutex<Vec<(CustomMessage, MessageSendInstructions)>>,
+}
+
+impl CustomMessenger {
+    pub fn new() -> Self {
+        let (subscribers, _) = broadcast::channel(SUBSCRIBER_BUFFER);
+        CustomMessenger {
+            handlers: Mutex::new(HashMap::new()),
+            subscribers,
+            pending_messages: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers a handler for messages of the TLV type provided. Only one handler can be
+    /// registered for each type.
+    pub fn register_handler(
+        &self,
+        tlv_type: u64,
+        handler: Arc<dyn CustomMessageHandler>,
+    ) -> Result<(), CustomMessageError> {
+        check_message_type(tlv_type)?;
+
+        let mut handlers = self.handlers.lock().unwrap();
+        if handlers.contains_key(&tlv_type) {
+            return Err(CustomMessageError::AlreadyRegistered(tlv_type));
+        }
+        handlers.insert(tlv_type, handler);
+
+        Ok(())
+    }
+
+    /// Returns a receiver for every custom message that we receive from now on, whether or not a
+    /// handler is registered for its type.
+    pub fn subscribe(&self) -> broadcast::Receiver<ReceivedCustomMessage> {
+        self.subscribers.subscribe()
+    }
+
+    /// Queues a message to be sent to the destination provided. If reply_path is set, we include
+    /// a blinded path that the recipient can respond to us on.
+    pub fn send(
+        &self,
+        message: CustomMessage,
+        destination: Destination,
+        reply_path: bool,
+    ) -> Result<(), CustomMessageError> {
+        check_message_type(message.tlv_type)?;
+
+        let instructions = if reply_path {
+            MessageSendInstructions::WithReplyPath {
+                destination,
+                context: MessageContext::Custom(Vec::new()),
+            }
+        } else {
+            MessageSendInstructions::WithoutReplyPath { destination }
+        };
+        self.pending_messages
+            .lock()
+            .unwrap()
+            .push((message, instructions));
+
+        Ok(())
+    }
+
+    /// Queues a reply to a message that we received, sent along the reply path its sender gave
+    /// us.
+    pub fn respond(
+        &self,
+        message: CustomMessage,
+        responder: Responder,
+        reply_path: bool,
+    ) -> Result<(), CustomMessageError> {
+        check_message_type(message.tlv_type)?;
+
+        let instruction = if reply_path {
+            responder.respond_with_reply_path(MessageContext::Custom(Vec::new()))
+        } else {
+            responder.respond()
+        };
+        self.pending_messages
+            .lock()
+            .unwrap()
+            .push((message, instruction.into_instructions()));
+
+        Ok(())
+    }
+
+    // Returns whether anybody is interested in messages of the type provided.
+    fn wants_message(&self, tlv_type: u64) -> bool {
+        self.subscribers.receiver_count() > 0
+            || self.handlers.lock().unwrap().contains_key(&tlv_type)
+    }
+}
+
+impl Default for CustomMessenger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CustomOnionMessageHandler for CustomMessenger {
+    type CustomMessage = CustomMessage;
+
+    fn handle_custom_message(
+        &self,
+        message: CustomMessage,
+        context: Option<Vec<u8>>,
+        responder: Option<Responder>,
+    ) -> Option<(CustomMessage, ResponseInstruction)> {
+        debug!(
+            "Received custom onion message of type {}.",
+            message.tlv_type
+        );
+
+        // It's fine if there aren't any subscribers, the message may still have a handler.
+        let _ = self.subscribers.send(ReceivedCustomMessage {
+            message: message.clone(),
+            responder: responder.clone(),
+        });
+
+        let handler = self
+            .handlers
+            .lock()
+            .unwrap()
+            .get(&message.tlv_type)
+            .cloned()?;
+        let response = handler.handle_custom_message(message, context)?;
+        match responder {
+            Some(responder) => Some((response, responder.respond())),
+            None => {
+                trace!("Not sending response to custom message without a reply path.");
+                None
+            }
+        }
+    }
+
+    fn read_custom_message<R: Read>(
+        &self,
+        message_type: u64,
+        buffer: &mut R,
+    ) -> Result<Option<CustomMessage>, DecodeError> {
+        if !self.wants_message(message_type) {
+            return Ok(None);
+        }
+
+        let mut data = Vec::new();
+        let mut chunk = [0; 1024];
+        loop {
+            let read = buffer
+                .read(&mut chunk)
+                .map_err(|e| DecodeError::Io(e.kind()))?;
+            if read == 0 {
+                break;
+            }
+            data.extend_from_slice(&chunk[..read]);
+        }
+
+        Ok(Some(CustomMessage {
+            tlv_type: message_type,
+            data,
+        }))
+    }
+
+    fn release_pending_custom_messages(&self) -> Vec<(CustomMessage, MessageSendInstructions)> {
+        core::mem::take(&mut self.pending_messages.lock().unwrap())
+    }
+}
+
+fn check_message_type(tlv_type: u64) -> Result<(), CustomMessageError> {
+    if tlv_type < MIN_CUSTOM_MESSAGE_TYPE || RESERVED_MESSAGE_TYPES.contains(&tlv_type) {
+        return Err(CustomMessageError::ReservedType(tlv_type));
+    }
+
+    Ok(())
+}
+
+/// Encodes a responder as an opaque token, so that clients can reply to a message later on.
+pub fn encode_reply_token(responder: &Responder) -> Vec<u8> {
+    responder.encode()
+}
+
+/// Decodes a reply token created with encode_reply_token.
+pub fn decode_reply_token(token: &[u8]) -> Result<Responder, DecodeError> {
+    Responder::read(&mut Cursor::new(token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_utils::pubkey;
+
+    struct EchoHandler {}
+
+    impl CustomMessageHandler for EchoHandler {
+        fn handle_custom_message(
+            &self,
+            message: CustomMessage,
+            _context: Option<Vec<u8>>,
+        ) -> Option<CustomMessage> {
+            Some(message)
+        }
+    }
+
+    fn message(tlv_type: u64) -> CustomMessage {
+        CustomMessage {
+            tlv_type,
+            data: vec![1, 2, 3],
+        }
+    }
+
+    #[test]
+    fn test_register_handler() {
+        let messenger = CustomMessenger::new();
+        assert!(messenger
+            .register_handler(101, Arc::new(EchoHandler {}))
+            .is_ok());
+        assert_eq!(
+            messenger.register_handler(101, Arc::new(EchoHandler {})),
+            Err(CustomMessageError::AlreadyRegistered(101))
+        );
+
+        // Types used for the onion message payload or handled by lndk can't be registered.
+        for tlv_type in [2, 64, 65536] {
+            assert_eq!(
+                messenger.register_handler(tlv_type, Arc::new(EchoHandler {})),
+                Err(CustomMessageError::ReservedType(tlv_type))
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_custom_message() {
+        let messenger = CustomMessenger::new();
+
+        // We don't read messages that nobody is interested in.
+        let mut data = Cursor::new(vec![1, 2, 3]);
+        assert_eq!(messenger.read_custom_message(101, &mut data), Ok(None));
+
+        messenger
+            .register_handler(101, Arc::new(EchoHandler {}))
+            .unwrap();
+        let mut data = Cursor::new(vec![1, 2, 3]);
+        assert_eq!(
+            messenger.read_custom_message(101, &mut data),
+            Ok(Some(message(101)))
+        );
+
+        // Subscribers receive messages of every type.
+        let _receiver = messenger.subscribe();
+        let mut data = Cursor::new(vec![1, 2, 3]);
+        assert_eq!(
+            messenger.read_custom_message(103, &mut data),
+            Ok(Some(message(103)))
+        );
+    }
+
+    #[test]
+    fn test_handle_custom_message() {
+        let messenger = CustomMessenger::new();
+        messenger
+            .register_handler(101, Arc::new(EchoHandler {}))
+            .unwrap();
+        let mut receiver = messenger.subscribe();
+
+        // Without a reply path, the handler's response can't be sent, but subscribers still see
+        // the message.
+        assert!(messenger
+            .handle_custom_message(message(101), None, None)
+            .is_none());
+        let received = receiver.try_recv().unwrap();
+        assert_eq!(received.message, message(101));
+        assert!(received.responder.is_none());
+    }
+
+    #[test]
+    fn test_send() {
+        let messenger = CustomMessenger::new();
+        let destination = Destination::Node(pubkey(0));
+        assert_eq!(
+            messenger.send(message(66), destination.clone(), false),
+            Err(CustomMessageError::ReservedType(66))
+        );
+
+        messenger
+            .send(message(101), destination.clone(), false)
+            .unwrap();
+        messenger.send(message(103), destination, true).unwrap();
+
+        let pending = messenger.release_pending_custom_messages();
+        assert_eq!(pending.len(), 2);
+        assert!(matches!(
+            pending[0].1,
+            MessageSendInstructions::WithoutReplyPath { .. }
+        ));
+        assert!(matches!(
+            pending[1].1,
+            MessageSendInstructions::WithReplyPath { .. }
+        ));
+        assert!(messenger.release_pending_custom_messages().is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+use crate::clock::TokioClock;
+use crate::rate_limit::{RateLimiter, TokenLimiter};
+use async_trait::async_trait;
+use bitcoin::secp256k1::PublicKey;
+use core::ops::Deref;
+use dnssec_prover::query::build_txt_proof_async;
+use dnssec_prover::rr::Name;
+use lightning::blinded_path::message::{BlindedMessagePath, DNSResolverContext};
+use lightning::blinded_path::IntroductionNode;
+use lightning::onion_message::dns_resolution::{
+    DNSResolverMessage, DNSResolverMessageHandler, DNSSECProof, DNSSECQuery,
+};
+use lightning::onion_message::messenger::{
+    MessageSendInstructions, Responder, ResponseInstruction,
+};
+use lightning::util::ser::{BigSize, Readable, Writeable};
+use log::{debug, warn};
+use std::io::Cursor;
+use std::iter;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::time::Duration;
+
+/// DnsResolverCfg configures the bLIP-32 resolver service that we offer to other nodes.
+#[derive(Clone, Debug)]
+pub struct DnsResolverCfg {
+    /// The DNS server we query over TCP to build proofs.
+    pub upstream: SocketAddr,
+    /// The number of queries we'll answer from each node within each rate limit period.
+    pub rate_limit_count: u8,
+    /// The number of queries we'll answer within each rate limit period, across all nodes.
+    pub global_rate_limit_count: u8,
+    pub rate_limit_period_secs: u64,
+}
+
+/// DnssecProver builds DNSSEC proofs of TXT records, which we send back to nodes that ask us to
+/// resolve a name.
+#[async_trait]
+pub trait DnssecProver: Send + Sync {
+    async fn build_txt_proof(&self, name: &Name) -> Result<Vec<u8>, std::io::Error>;
+}
+
+/// UpstreamProver builds proofs by querying a DNS server over TCP.
+pub struct UpstreamProver {
+    upstream: SocketAddr,
+}
+
+impl UpstreamProver {
+    pub fn new(upstream: SocketAddr) -> Self {
+        UpstreamProver { upstream }
+    }
+}
+
+#[async_trait]
+impl DnssecProver for UpstreamProver {
+    async fn build_txt_proof(&self, name: &Name) -> Result<Vec<u8>, std::io::Error> {
+        let (proof, _ttl) = build_txt_proof_async(self.upstream, name).await?;
+        Ok(proof)
+    }
+}
+
+/// DnsResolver answers DNSSECQuery onion messages from other nodes with DNSSECProofs, if we're
+/// configured to offer the resolver service. Proofs sent in response to our own queries are
+/// passed on to the proof handler provided.
+pub struct DnsResolver<H: Deref>
+where
+    H::Target: DNSResolverMessageHandler,
+{
+    prover: Option<Arc<dyn DnssecProver>>,
+    proof_handler: H,
+    // Queries arrive over blinded paths, so we can't tell which node sent them. Instead, we rate
+    // limit queries by the introduction node of the path we reply over. Queries whose reply path
+    // starts at a short channel id share a single allowance, keyed by our own node id.
+    node_id: PublicKey,
+    rate_limiter: Mutex<TokenLimiter<TokioClock>>,
+    // Senders pick the introduction node, so a single node can claim to be many. We also cap the
+    // queries we answer across all nodes, as a single peer keyed by our own node id, so that they
+    // can't use us to flood our upstream DNS server.
+    global_rate_limiter: Mutex<TokenLimiter<TokioClock>>,
+    pending_messages: Arc<Mutex<Vec<(DNSResolverMessage, MessageSendInstructions)>>>,
+}
+
+impl<H: Deref> DnsResolver<H>
+where
+    H::Target: DNSResolverMessageHandler,
+{
+    pub fn new(
+        prover: Option<Arc<dyn DnssecProver>>,
+        proof_handler: H,
+        node_id: PublicKey,
+        rate_limit_count: u8,
+        global_rate_limit_count: u8,
+        rate_limit_period: Duration,
+    ) -> Self {
+        let rate_limiter = TokenLimiter::new(
+            iter::empty(),
+            rate_limit_count,
+            rate_limit_period,
+            TokioClock::new(),
+        );
+        let global_rate_limiter = TokenLimiter::new(
+            iter::once(node_id),
+            global_rate_limit_count,
+            rate_limit_period,
+            TokioClock::new(),
+        );
+
+        DnsResolver {
+            prover,
+            proof_handler,
+            node_id,
+            rate_limiter: Mutex::new(rate_limiter),
+            global_rate_limiter: Mutex::new(global_rate_limiter),
+            pending_messages: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // Returns whether both the node provided and all nodes together have enough of their rate
+    // limit left for us to answer another query.
+    fn allow_query(&self, peer: PublicKey) -> bool {
+        // We don't look at the node's own limit once we've hit the global one, so that nodes we
+        // wouldn't answer anyway don't add to the limiter.
+        let mut global_rate_limiter = self.global_rate_limiter.lock().unwrap();
+        if !global_rate_limiter.has_remaining(self.node_id) {
+            return false;
+        }
+
+        let mut rate_limiter = self.rate_limiter.lock().unwrap();
+        // We only track a node until the end of the period it last queried us in, so that the
+        // limiter doesn't grow with every node that has ever sent us a query. Nodes are still
+        // held to the calls they have left until then.
+        rate_limiter.peer_connected(peer);
+        let allowed = rate_limiter.query_peer(peer);
+        rate_limiter.peer_disconnected(peer);
+
+        // A node that has used up its own limit doesn't use up the global one.
+        allowed && global_rate_limiter.query_peer(self.node_id)
+    }
+
+    // Returns the node that we rate limit a query answered through the responder provided as.
+    fn requesting_peer(&self, responder: &Responder) -> PublicKey {
+        match reply_path(responder)
+            .as_ref()
+            .map(|path| path.introduction_node())
+        {
+            Some(IntroductionNode::NodeId(node_id)) => *node_id,
+            _ => self.node_id,
+        }
+    }
+}
+
+// Returns the path that a responder replies over. Responder doesn't expose it, but serializes it
+// as the only record of a length-prefixed TLV stream, so we read it back out of that.
+fn reply_path(responder: &Responder) -> Option<BlindedMessagePath> {
+    let mut reader = Cursor::new(responder.encode());
+    let _stream_len = BigSize::read(&mut reader).ok()?;
+    let BigSize(0) = BigSize::read(&mut reader).ok()? else {
+        return None;
+    };
+    let _record_len = BigSize::read(&mut reader).ok()?;
+    BlindedMessagePath::read(&mut reader).ok()
+}
+
+// Builds the proof for the name queried, or returns None if we couldn't.
+async fn build_proof(prover: &dyn DnssecProver, query: DNSSECQuery) -> Option<DNSSECProof> {
+    let name = query.0;
+    match prover.build_txt_proof(&name).await {
+        Ok(proof) => Some(DNSSECProof { name, proof }),
+        Err(e) => {
+            warn!("Could not build DNSSEC proof for {}: {e}", &*name);
+            None
+        }
+    }
+}
+
+impl<H: Deref> DNSResolverMessageHandler for DnsResolver<H>
+where
+    H::Target: DNSResolverMessageHandler,
+{
+    fn handle_dnssec_query(
+        &self,
+        message: DNSSECQuery,
+        responder: Option<Responder>,
+    ) -> Option<(DNSResolverMessage, ResponseInstruction)> {
+        let (Some(prover), Some(responder)) = (&self.prover, responder) else {
+            return None;
+        };
+        let peer = self.requesting_peer(&responder);
+        if !self.allow_query(peer) {
+            debug!("Dropping DNSSEC query via {peer}, it has reached its rate limit.");
+            return None;
+        }
+
+        // Building a proof takes several round trips to the upstream server, so we answer once
+        // it's done rather than holding up the onion messenger.
+        let prover = Arc::clone(prover);
+        let pending_messages = Arc::clone(&self.pending_messages);
+        tokio::spawn(async move {
+            if let Some(proof) = build_proof(prover.as_ref(), message).await {
+                let instructions = responder.respond().into_instructions();
+                pending_messages
+                    .lock()
+                    .unwrap()
+                    .push((DNSResolverMessage::DNSSECProof(proof), instructions));
+            }
+        });
+
+        None
+    }
+
+    fn handle_dnssec_proof(&self, message: DNSSECProof, context: DNSResolverContext) {
+        self.proof_handler.handle_dnssec_proof(message, context)
+    }
+
+    fn release_pending_messages(&self) -> Vec<(DNSResolverMessage, MessageSendInstructions)> {
+        let mut messages = core::mem::take(&mut *self.pending_messages.lock().unwrap());
+        messages.extend(self.proof_handler.release_pending_messages());
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offers::handler::OfferHandler;
+    use crate::onion_messenger::MessengerUtilities;
+    use crate::tests::test_utils::pubkey;
+    use bitcoin::secp256k1::Secp256k1;
+    use lightning::blinded_path::message::MessageContext;
+
+    struct StubProver {
+        proof: Option<Vec<u8>>,
+    }
+
+    #[async_trait]
+    impl DnssecProver for StubProver {
+        async fn build_txt_proof(&self, _name: &Name) -> Result<Vec<u8>, std::io::Error> {
+            self.proof
+                .clone()
+                .ok_or_else(|| std::io::Error::other("no such name"))
+        }
+    }
+
+    fn query() -> DNSSECQuery {
+        DNSSECQuery(Name::try_from("alice.user._bitcoin-payment.example.com.").unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_build_proof() {
+        let prover = StubProver {
+            proof: Some(vec![1, 2, 3]),
+        };
+        let proof = build_proof(&prover, query()).await.unwrap();
+        assert_eq!(proof.name, query().0);
+        assert_eq!(proof.proof, vec![1, 2, 3]);
+
+        let prover = StubProver { proof: None };
+        assert!(build_proof(&prover, query()).await.is_none());
+    }
+
+    // Encodes a responder for the reply path provided the way we expect LDK to: as a
+    // length-prefixed TLV stream with the path as record 0.
+    fn encode_responder(path: &BlindedMessagePath) -> Vec<u8> {
+        let path = path.encode();
+        let mut record = BigSize(0).encode();
+        record.extend(BigSize(path.len() as u64).encode());
+        record.extend(path);
+        let mut encoded = BigSize(record.len() as u64).encode();
+        encoded.extend(record);
+        encoded
+    }
+
+    // Builds a responder that replies over a path introduced by the node provided.
+    fn responder(introduction_node: PublicKey) -> Responder {
+        let path = BlindedMessagePath::one_hop(
+            introduction_node,
+            MessageContext::Custom(vec![]),
+            &MessengerUtilities::default(),
+            &Secp256k1::new(),
+        )
+        .unwrap();
+        Responder::read(&mut Cursor::new(encode_responder(&path))).unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_query_rate_limit() {
+        let resolver = DnsResolver::new(
+            Some(Arc::new(StubProver { proof: None })),
+            Arc::new(OfferHandler::default()),
+            pubkey(0),
+            2,
+            10,
+            Duration::from_secs(1),
+        );
+
+        assert!(resolver.allow_query(pubkey(1)));
+        assert!(resolver.allow_query(pubkey(1)));
+        assert!(!resolver.allow_query(pubkey(1)));
+
+        // Once the period is over, we can answer queries again.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(resolver.allow_query(pubkey(1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_per_peer() {
+        let resolver = DnsResolver::new(
+            Some(Arc::new(StubProver { proof: None })),
+            Arc::new(OfferHandler::default()),
+            pubkey(0),
+            1,
+            10,
+            Duration::from_secs(1),
+        );
+        let alice = resolver.requesting_peer(&responder(pubkey(1)));
+        let bob = resolver.requesting_peer(&responder(pubkey(2)));
+        assert_eq!(alice, pubkey(1));
+        assert_eq!(bob, pubkey(2));
+
+        // A node using up its rate limit doesn't stop us from answering another.
+        assert!(resolver.allow_query(alice));
+        assert!(!resolver.allow_query(alice));
+        assert!(resolver.allow_query(bob));
+        assert!(!resolver.allow_query(bob));
+
+        // Both get a fresh allowance in the next period.
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(resolver.allow_query(alice));
+        assert!(resolver.allow_query(bob));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_global_rate_limit() {
+        let resolver = DnsResolver::new(
+            Some(Arc::new(StubProver { proof: None })),
+            Arc::new(OfferHandler::default()),
+            pubkey(0),
+            1,
+            2,
+            Duration::from_secs(1),
+        );
+
+        // A node that's over its own limit doesn't use up the global one.
+        assert!(resolver.allow_query(pubkey(1)));
+        assert!(!resolver.allow_query(pubkey(1)));
+        assert!(resolver.allow_query(pubkey(2)));
+
+        // Nodes we haven't heard from are refused once the global limit is reached, however many
+        // node ids a sender rotates through.
+        for byte in 3..10 {
+            assert!(!resolver.allow_query(pubkey(byte)));
+        }
+        assert!(resolver.rate_limiter.lock().unwrap().peers().is_empty());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(resolver.allow_query(pubkey(3)));
+    }
+
+    #[test]
+    fn test_responder_encoding() {
+        // We read the reply path out of LDK's encoding of a responder, so this fails if that
+        // encoding changes: LDK has to accept our encoding and write it back out the same way.
+        let path = BlindedMessagePath::one_hop(
+            pubkey(1),
+            MessageContext::Custom(vec![]),
+            &MessengerUtilities::default(),
+            &Secp256k1::new(),
+        )
+        .unwrap();
+        let encoded = encode_responder(&path);
+        let responder = Responder::read(&mut Cursor::new(&encoded)).unwrap();
+        assert_eq!(responder.encode(), encoded);
+        assert_eq!(reply_path(&responder), Some(path));
+    }
+
+    #[test]
+    fn test_ignore_queries_without_prover() {
+        let resolver = DnsResolver::new(
+            None,
+            Arc::new(OfferHandler::default()),
+            pubkey(0),
+            2,
+            10,
+            Duration::from_secs(1),
+        );
+
+        assert!(resolver.handle_dnssec_query(query(), None).is_none());
+        assert!(resolver.release_pending_messages().is_empty());
+    }
+}
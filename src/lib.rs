@@ -1,17 +1,39 @@
+pub mod auth;
 mod clock;
+pub mod custom_messages;
+pub mod dns_resolver;
 mod grpc;
 #[allow(dead_code)]
 pub mod lnd;
 mod message_router;
+pub mod node;
 pub mod offers;
 pub mod onion_messenger;
+pub mod policy;
 mod rate_limit;
+pub mod seeds;
 pub mod server;
+pub mod store;
+pub mod tls;
+pub mod webhooks;
+
+pub use node::{LndkHandle, LndkNode, LndkNodeBuilder};
 
 pub mod lndkrpc {
     tonic::include_proto!("lndkrpc");
 }
 
+// Serializes the binary fields of our grpc messages as hex, which is how they're displayed
+// everywhere else.
+fn serialize_hex<T: AsRef<[u8]>, S: serde::Serializer>(
+    bytes: &T,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&hex::encode(bytes))
+}
+
+use crate::custom_messages::CustomMessenger;
+use crate::dns_resolver::{DnsResolver, DnsResolverCfg, DnssecProver, UpstreamProver};
 use crate::lnd::{
     features_support_onion_messages, get_lnd_client, get_network, has_build_tags, has_version,
     LndCfg, LndNodeSigner, MIN_LND_MAJOR_VER, MIN_LND_MINOR_VER, MIN_LND_PATCH_VER,
@@ -23,6 +45,7 @@ use home::home_dir;
 use lightning::ln::msgs::DecodeError;
 use lightning::ln::peer_handler::IgnoringMessageHandler;
 use lightning::offers::invoice::Bolt12Invoice;
+use lightning::onion_message::dns_resolution::DNSResolverMessageHandler;
 use lightning::onion_message::messenger::{DefaultMessageRouter, OnionMessenger};
 use lightning::onion_message::offers::OffersMessageHandler;
 use lightning::routing::gossip::NetworkGraph;
@@ -37,17 +60,21 @@ use offers::handler::OfferHandler;
 use rate_limit::RateLimiterCfg;
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::{Arc, Once};
+use std::sync::{Arc, Once, OnceLock};
+use tokio::sync::watch;
 use tokio::time::Duration;
 use tonic_lnd::lnrpc::GetInfoRequest;
 use tonic_lnd::verrpc::VersionRequest;
 use triggered::{Listener, Trigger};
 
 static INIT: Once = Once::new();
+// Lets us swap in a new logging config once the logger is running, see reload_logger.
+static LOG_HANDLE: OnceLock<log4rs::Handle> = OnceLock::new();
 
 pub fn init_logger(config: LogConfig) {
     INIT.call_once(|| {
-        log4rs::init_config(config).expect("failed to initialize logger");
+        let handle = log4rs::init_config(config).expect("failed to initialize logger");
+        let _ = LOG_HANDLE.set(handle);
     });
 }
 
@@ -61,20 +88,53 @@ pub const DEFAULT_CONFIG_FILE_NAME: &str = "lndk.conf";
 
 pub const TLS_CERT_FILENAME: &str = "tls-cert.pem";
 pub const TLS_KEY_FILENAME: &str = "tls-key.pem";
+pub const CLIENT_CA_CERT_FILENAME: &str = "client-ca-cert.pem";
+pub const CLIENT_CA_KEY_FILENAME: &str = "client-ca-key.pem";
+
+pub const MACAROON_ROOT_KEY_FILENAME: &str = "macaroon-root-key";
+pub const ADMIN_MACAROON_FILENAME: &str = "admin.macaroon";
+
+pub const PAYMENT_STORE_FILENAME: &str = "payments.json";
+pub const WEBHOOK_QUEUE_FILENAME: &str = "webhooks.json";
+pub const SEED_STORE_FILENAME: &str = "seeds.json";
 
 #[allow(clippy::result_unit_err)]
 pub fn setup_logger(log_level: Option<String>, log_file: Option<PathBuf>) -> Result<(), ()> {
+    let config = build_log_config(log_level, log_file).map_err(|e| {
+        // Since the logger isn't set up yet, we use a println just this once.
+        println!("{e}");
+    })?;
+    init_logger(config);
+
+    Ok(())
+}
+
+/// reload_logger applies a new log level and log file to the logger that setup_logger started,
+/// without restarting lndk.
+#[allow(clippy::result_unit_err)]
+pub fn reload_logger(log_level: Option<String>, log_file: Option<PathBuf>) -> Result<(), ()> {
+    let config = build_log_config(log_level, log_file).map_err(|e| error!("{e}"))?;
+    match LOG_HANDLE.get() {
+        Some(handle) => handle.set_config(config),
+        None => init_logger(config),
+    }
+
+    Ok(())
+}
+
+fn build_log_config(
+    log_level: Option<String>,
+    log_file: Option<PathBuf>,
+) -> Result<LogConfig, String> {
     let log_level = match log_level {
         Some(level_str) => match LevelFilter::from_str(&level_str) {
             Ok(level) => level,
             Err(_) => {
-                // Since the logger isn't set up yet, we use a println just this once.
-                println!(
+                return Err(format!(
                     "User provided log level '{}' is invalid. Make sure it is set to either 'error',
                     'warn', 'info', 'debug' or 'trace'",
                     level_str
-                );
-                return Err(());
+                ));
             }
         },
         None => LevelFilter::Trace,
@@ -112,9 +172,7 @@ pub fn setup_logger(log_level: Option<String>, log_file: Option<PathBuf>) -> Res
         )
         .unwrap();
 
-    init_logger(config);
-
-    Ok(())
+    Ok(config)
 }
 
 #[derive(Clone)]
@@ -124,6 +182,8 @@ pub struct Cfg {
     pub skip_version_check: bool,
     pub rate_limit_count: u8,
     pub rate_limit_period_secs: u64,
+    // If set, we answer DNS resolution queries from other nodes.
+    pub dns_resolver: Option<DnsResolverCfg>,
 }
 
 #[derive(Clone)]
@@ -134,17 +194,36 @@ pub struct LifecycleSignals {
     pub listener: Listener,
 }
 
-pub struct LndkOnionMessenger {}
+pub struct LndkOnionMessenger {
+    custom_messenger: Arc<CustomMessenger>,
+    // Rate limits that replace the ones we were started with, see set_rate_limit.
+    rate_limits: Arc<watch::Sender<Option<RateLimiterCfg>>>,
+}
 
 impl LndkOnionMessenger {
     pub fn new() -> Self {
-        LndkOnionMessenger {}
+        LndkOnionMessenger {
+            custom_messenger: Arc::new(CustomMessenger::new()),
+            rate_limits: Arc::new(watch::channel(None).0),
+        }
+    }
+
+    /// Changes the number of onion messages each peer may send us per period, and the length of
+    /// the period, without restarting the messenger.
+    pub fn set_rate_limit(&self, call_count: u8, call_period: Duration) {
+        set_rate_limit(&self.rate_limits, call_count, call_period)
+    }
+
+    /// Returns the handler for onion messages of types that lndk doesn't handle itself, which
+    /// applications can register their own handlers with and send messages through.
+    pub fn custom_messenger(&self) -> Arc<CustomMessenger> {
+        Arc::clone(&self.custom_messenger)
     }
 
     pub async fn run(
         &self,
         args: Cfg,
-        offer_handler: Arc<impl OffersMessageHandler>,
+        offer_handler: Arc<impl OffersMessageHandler + DNSResolverMessageHandler>,
     ) -> Result<(), ()> {
         let mut client = get_lnd_client(args.lnd).expect("failed to connect");
         let info = client
@@ -196,6 +275,30 @@ impl LndkOnionMessenger {
         let message_router =
             &MessageRouter::new(default_message_router, client.clone().lightning_read_only());
         let node_id_lookup = LndkNodeIdLookUp::new(client.clone(), pubkey);
+        // We always handle the proofs sent back for our own DNS queries, but only answer queries
+        // from other nodes if we're configured to.
+        let (prover, dns_rate_limit_count, dns_global_rate_limit_count, dns_rate_limit_period_secs) =
+            match args.dns_resolver {
+                Some(cfg) => {
+                    info!("Answering DNS resolution queries using {}.", cfg.upstream);
+                    let prover: Arc<dyn DnssecProver> = Arc::new(UpstreamProver::new(cfg.upstream));
+                    (
+                        Some(prover),
+                        cfg.rate_limit_count,
+                        cfg.global_rate_limit_count,
+                        cfg.rate_limit_period_secs,
+                    )
+                }
+                None => (None, 0, 0, 0),
+            };
+        let dns_resolver = DnsResolver::new(
+            prover,
+            Arc::clone(&offer_handler),
+            pubkey,
+            dns_rate_limit_count,
+            dns_global_rate_limit_count,
+            Duration::from_secs(dns_rate_limit_period_secs),
+        );
         let onion_messenger = OnionMessenger::new(
             &messenger_utils,
             &node_signer,
@@ -203,9 +306,10 @@ impl LndkOnionMessenger {
             &node_id_lookup,
             message_router,
             offer_handler,
+            // We can't receive payments to static invoices, so nobody should hold an HTLC for us.
             IgnoringMessageHandler {}, // AsyncPaymentsMessageHandler
-            IgnoringMessageHandler {}, // DNSResolverMessageHandler
-            IgnoringMessageHandler {}, // CustomOnionMessageHandler
+            &dns_resolver,             // DNSResolverMessageHandler
+            Arc::clone(&self.custom_messenger),
         );
 
         let mut peers_client = client.lightning().clone();
@@ -223,6 +327,18 @@ impl LndkOnionMessenger {
     }
 }
 
+// Shared with LndkHandle, which can change the rate limits of a running messenger.
+pub(crate) fn set_rate_limit(
+    rate_limits: &watch::Sender<Option<RateLimiterCfg>>,
+    call_count: u8,
+    call_period: Duration,
+) {
+    rate_limits.send_replace(Some(RateLimiterCfg {
+        call_count,
+        call_period_secs: call_period,
+    }));
+}
+
 impl Default for LndkOnionMessenger {
     fn default() -> Self {
         Self::new()
@@ -23,11 +23,12 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
 use std::path::PathBuf;
+use std::sync::Mutex;
 use std::{fmt, fs};
 use tonic_lnd::lnrpc::AddInvoiceResponse;
 use tonic_lnd::lnrpc::PayReq;
 use tonic_lnd::lnrpc::{
-    FeeLimit, GetInfoResponse, HtlcAttempt, ListPeersResponse, NodeInfo, Payment,
+    FeeLimit, GetInfoRequest, GetInfoResponse, HtlcAttempt, ListPeersResponse, NodeInfo, Payment,
     QueryRoutesResponse, Route,
 };
 use tonic_lnd::signrpc::KeyLocator;
@@ -47,8 +48,9 @@ pub(crate) const BUILD_TAGS_REQUIRED: [&str; 3] = ["peersrpc", "signrpc", "walle
 // They were "randomly" chosen, could be changed.
 // Family 4 as we used to use family 3 for other message signing.
 // Index 425 as it's the sum of "l n d k" ascii values.
+// Rotating the seed moves on to the following indexes, see crate::seeds.
 const SEED_KEY_FAMILY: i32 = 4;
-const SEED_KEY_INDEX: i32 = 425;
+pub const SEED_KEY_INDEX: i32 = 425;
 
 /// get_lnd_client connects to LND's grpc api using the config provided, blocking until a connection
 /// is established.
@@ -74,6 +76,106 @@ impl LndCfg {
     }
 }
 
+/// The maximum number of distinct macaroons we'll hold open connections for. Callers may forward
+/// arbitrary LND macaroons, so we bound the pool rather than let it grow forever.
+const MAX_POOLED_CLIENTS: usize = 64;
+
+/// LndClientPool holds connections to LND keyed by the macaroon they were created with, so that
+/// repeated requests from the same caller reuse an existing channel rather than paying for a new
+/// TLS handshake every time. Only macaroons that LND has accepted are pooled, so that junk
+/// macaroons can't push out the clients of real callers.
+pub struct LndClientPool {
+    address: String,
+    cert: String,
+    clients: Mutex<HashMap<String, Client>>,
+    // Clients added with insert, such as lndk's own, which are never evicted.
+    pinned: Mutex<HashMap<String, Client>>,
+}
+
+impl LndClientPool {
+    pub fn new(address: String, cert: String) -> Self {
+        LndClientPool {
+            address,
+            cert,
+            clients: Mutex::new(HashMap::new()),
+            pinned: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a client authenticated with the macaroon provided, connecting to LND if we don't
+    /// have one pooled already. New clients are checked with a GetInfo call before they're
+    /// pooled, so the macaroon needs permission to call it.
+    pub async fn get_client(&self, macaroon: &str) -> Result<Client, ClientPoolError> {
+        if let Some(client) = self.pinned.lock().unwrap().get(macaroon) {
+            return Ok(client.clone());
+        }
+        if let Some(client) = self.clients.lock().unwrap().get(macaroon) {
+            return Ok(client.clone());
+        }
+
+        // We don't hold the lock while connecting, so that a slow handshake doesn't block
+        // requests that already have a pooled client.
+        let mut client = tonic_lnd::connect_from_memory(
+            self.address.clone(),
+            self.cert.clone(),
+            macaroon.to_string(),
+        )
+        .await
+        .map_err(ClientPoolError::Connect)?;
+        client
+            .lightning()
+            .get_info(GetInfoRequest {})
+            .await
+            .map_err(ClientPoolError::Rejected)?;
+
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= MAX_POOLED_CLIENTS && !clients.contains_key(macaroon) {
+            if let Some(evict) = clients.keys().next().cloned() {
+                clients.remove(&evict);
+            }
+        }
+        clients.insert(macaroon.to_string(), client.clone());
+
+        Ok(client)
+    }
+
+    /// Adds an existing client to the pool, for instance the one lndk connected with at startup.
+    /// It's never evicted.
+    pub fn insert(&self, macaroon: String, client: Client) {
+        self.pinned.lock().unwrap().insert(macaroon, client);
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.lock().unwrap().len() + self.pinned.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// An error that occurs when we can't get a client from the LndClientPool.
+#[derive(Debug)]
+pub enum ClientPoolError {
+    /// We couldn't connect to LND.
+    Connect(ConnectError),
+    /// LND refused the macaroon we connected with.
+    Rejected(Status),
+}
+
+impl Display for ClientPoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientPoolError::Connect(e) => write!(f, "Couldn't connect to lnd: {e}"),
+            ClientPoolError::Rejected(status) => {
+                write!(f, "lnd refused the macaroon: {}", status.message())
+            }
+        }
+    }
+}
+
+impl Error for ClientPoolError {}
+
 pub fn validate_lnd_creds(
     cert_path: Option<PathBuf>,
     cert_pem: Option<String>,
@@ -192,6 +294,18 @@ impl Creds {
         };
         Ok(cert)
     }
+
+    /// Returns the macaroon as a hex-encoded string, reading it from disk if necessary.
+    #[allow(clippy::result_unit_err)]
+    pub fn get_macaroon_string(&self) -> Result<String, ()> {
+        let macaroon = match self {
+            Creds::Path { macaroon, cert: _ } => fs::read(macaroon)
+                .map(hex::encode)
+                .map_err(|e| error!("Error reading macaroon from file {e:?}"))?,
+            Creds::String { macaroon, cert: _ } => macaroon.clone(),
+        };
+        Ok(macaroon)
+    }
 }
 
 pub struct VersionRequirement {
@@ -387,12 +501,15 @@ pub fn string_to_network(network_str: &str) -> Result<Network, NetworkParseError
     }
 }
 
-/// build_seed_from_lnd_node builds a seed from the LND node.
-pub async fn build_seed_from_lnd_node(signer: &mut impl MessageSigner) -> Result<[u8; 32], ()> {
-    // TODO: We should be able to rotate the key if needed.
+/// build_seed_from_lnd_node builds a seed from the LND node, using the key with the index provided
+/// to sign. Each generation of our seed uses a different key index.
+pub async fn build_seed_from_lnd_node(
+    signer: &mut impl MessageSigner,
+    key_index: i32,
+) -> Result<[u8; 32], ()> {
     let key_loc = KeyLocator {
         key_family: SEED_KEY_FAMILY,
-        key_index: SEED_KEY_INDEX,
+        key_index,
     };
 
     // Derives a seed using LND's message signing API to ensure LNDK remains stateless and avoids
@@ -801,7 +918,9 @@ mod tests {
         signer
             .expect_sign_message()
             .returning(|_msg, _key_loc, _double_hash, _schnorr_sig| Ok(vec![0; 64]));
-        let seed = build_seed_from_lnd_node(&mut signer).await.unwrap();
+        let seed = build_seed_from_lnd_node(&mut signer, SEED_KEY_INDEX)
+            .await
+            .unwrap();
         let expected_seed = [
             245, 165, 253, 66, 209, 106, 32, 48, 39, 152, 239, 110, 211, 9, 151, 155, 67, 0, 61,
             35, 32, 217, 240, 232, 234, 152, 49, 169, 39, 89, 251, 75,
@@ -816,7 +935,17 @@ mod tests {
             .expect_sign_message()
             .returning(|_msg, _key_loc, _double_hash, _schnorr_sig| Err(()));
 
-        let result = build_seed_from_lnd_node(&mut signer).await;
+        let result = build_seed_from_lnd_node(&mut signer, SEED_KEY_INDEX).await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_client_pool_skips_failed_connections() {
+        let pool = LndClientPool::new("https://127.0.0.1:1".to_string(), get_tls_cert_string());
+
+        // A macaroon is only pooled once LND has accepted it.
+        let result = pool.get_client(&get_macaroon_string()).await;
+        assert!(matches!(result, Err(ClientPoolError::Connect(_))));
+        assert!(pool.is_empty());
+    }
 }
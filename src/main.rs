@@ -11,12 +11,21 @@ mod internal {
 
 use home::home_dir;
 use internal::*;
-use lndk::lnd::{build_seed_from_lnd_node, get_lnd_client, validate_lnd_creds, LndCfg};
-use lndk::offers::handler::OfferHandler;
-use lndk::server::{generate_tls_creds, read_tls, LNDKServer};
+use lndk::auth::MacaroonAuth;
+use lndk::dns_resolver::DnsResolverCfg;
+use lndk::lnd::{validate_lnd_creds, LndCfg, LndClientPool};
+use lndk::offers::handler::DEFAULT_RESPONSE_INVOICE_TIMEOUT;
+use lndk::policy::{parse_pubkey_list, PaymentPolicy, PolicyCfg};
+use lndk::seeds::SeedStore;
+use lndk::server::LNDKServer;
+use lndk::store::PaymentStore;
+use lndk::tls::{client_verifier, tls_incoming, ReloadableCert, TlsSource};
+use lndk::webhooks::{HttpSender, WebhookCfg, WebhookQueue, Webhooks};
 use lndk::{
-    lndkrpc, setup_logger, Cfg, LifecycleSignals, LndkOnionMessenger, DEFAULT_CONFIG_FILE_NAME,
-    DEFAULT_DATA_DIR, DEFAULT_LNDK_DIR, DEFAULT_LOG_FILE, DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT,
+    lndkrpc, reload_logger, setup_logger, Cfg, LifecycleSignals, LndkHandle, LndkNodeBuilder,
+    DEFAULT_CONFIG_FILE_NAME, DEFAULT_DATA_DIR, DEFAULT_LNDK_DIR, DEFAULT_LOG_FILE,
+    DEFAULT_SERVER_HOST, DEFAULT_SERVER_PORT, PAYMENT_STORE_FILENAME, SEED_STORE_FILENAME,
+    WEBHOOK_QUEUE_FILENAME,
 };
 use lndkrpc::offers_server::OffersServer;
 use log::{error, info};
@@ -25,10 +34,10 @@ use std::fs::create_dir_all;
 use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::select;
 use tokio::signal::unix::SignalKind;
-use tonic::transport::{Server, ServerTlsConfig};
-use tonic_lnd::lnrpc::GetInfoRequest;
+use tonic::transport::Server;
 
 #[macro_use]
 extern crate configure_me;
@@ -42,9 +51,7 @@ async fn main() -> Result<(), ()> {
     let data_dir = create_data_dir(&config.data_dir).map_err(|e| {
         println!("Error creating LNDK's data dir: {:?}", e);
     })?;
-    let log_file = config.log_file.map(PathBuf::from).or(config
-        .data_dir
-        .map(|data_dir| PathBuf::from(data_dir).join(DEFAULT_LOG_FILE)));
+    let log_file = get_log_file(config.log_file, config.data_dir);
 
     setup_logger(config.log_level, log_file)?;
 
@@ -65,18 +72,32 @@ async fn main() -> Result<(), ()> {
         shutdown: shutdown.clone(),
         listener: listener.clone(),
     };
+    let dns_resolver = match config.dns_resolver_upstream {
+        Some(upstream) => Some(DnsResolverCfg {
+            upstream: upstream.parse().map_err(|e| {
+                error!("Error parsing dns_resolver_upstream: {e}");
+            })?,
+            rate_limit_count: config.dns_resolver_rate_limit_count,
+            global_rate_limit_count: config.dns_resolver_global_rate_limit_count,
+            rate_limit_period_secs: config.dns_resolver_rate_limit_period_secs,
+        }),
+        None => None,
+    };
     let args = Cfg {
         lnd: lnd_args,
         signals,
         skip_version_check: config.skip_version_check,
         rate_limit_count: config.rate_limit_count,
         rate_limit_period_secs: config.rate_limit_period_secs,
+        dns_resolver,
     };
 
     let mut sigterm_stream = tokio::signal::unix::signal(SignalKind::terminate())
         .map_err(|e| error!("Error initializing sigterm signal: {e}."))?;
     let mut sigint_stream = tokio::signal::unix::signal(SignalKind::interrupt())
         .map_err(|e| error!("Error initializing sigint signal: {e}."))?;
+    let mut sighup_stream = tokio::signal::unix::signal(SignalKind::hangup())
+        .map_err(|e| error!("Error initializing sighup signal: {e}."))?;
 
     tokio::spawn(async move {
         tokio::select! {
@@ -99,13 +120,60 @@ async fn main() -> Result<(), ()> {
         }
     };
 
-    let mut client = get_lnd_client(args.lnd.clone()).expect("failed to connect to lnd");
-    let info = client
-        .lightning()
-        .get_info(GetInfoRequest {})
-        .await
-        .expect("failed to get info")
-        .into_inner();
+    if config.default_fee_limit.is_some() && config.default_fee_limit_percent.is_some() {
+        error!("Error: only one of default_fee_limit or default_fee_limit_percent can be set.");
+        exit(1);
+    }
+    let allowed_issuers = parse_pubkey_list(&config.allowed_issuers.unwrap_or_default())
+        .map_err(|e| error!("Error parsing allowed_issuers: {e}"))?;
+    let denied_issuers = parse_pubkey_list(&config.denied_issuers.unwrap_or_default())
+        .map_err(|e| error!("Error parsing denied_issuers: {e}"))?;
+    let policy = PaymentPolicy::new(PolicyCfg {
+        max_payment_msats: config.max_payment_msats,
+        hourly_budget_msats: config.hourly_budget_msats,
+        daily_budget_msats: config.daily_budget_msats,
+        default_fee_limit: config.default_fee_limit,
+        default_fee_limit_percent: config.default_fee_limit_percent,
+        allowed_issuers,
+        denied_issuers,
+    });
+
+    let dns_resolvers = parse_pubkey_list(&config.dns_resolvers.unwrap_or_default())
+        .map_err(|e| error!("Error parsing dns_resolvers: {e}"))?;
+    let seed_store = SeedStore::open(
+        &data_dir.join(SEED_STORE_FILENAME),
+        Duration::from_secs(config.seed_grace_period_secs),
+    )
+    .map_err(|e| {
+        error!("Error opening seed store: {e}");
+    })?;
+    let seed_store = Arc::new(seed_store);
+    let mut builder = LndkNodeBuilder::new(args)
+        .dns_resolvers(dns_resolvers)
+        .seed_store(Arc::clone(&seed_store));
+    if let Some(timeout) = response_invoice_timeout {
+        builder = builder.response_invoice_timeout(timeout);
+    }
+    let node = builder.build().await.map_err(|e| {
+        error!("Error setting up lndk: {e}");
+    })?;
+    let handle = node.handle();
+
+    // Some settings can be changed without a restart, by editing the config file and sending us a
+    // SIGHUP.
+    let reload_handle = handle.clone();
+    let reload_listener = listener.clone();
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                _ = reload_listener.clone() => return,
+                _ = sighup_stream.recv() => {
+                    info!("Received SIGHUP, reloading config..");
+                    reload_config(&reload_handle);
+                }
+            }
+        }
+    });
 
     let grpc_host = match config.grpc_host {
         Some(host) => host,
@@ -119,47 +187,141 @@ async fn main() -> Result<(), ()> {
         error!("Error parsing API address: {e}");
     })?;
     let lnd_tls_str = creds.get_certificate_string()?;
+    let lnd_macaroon_str = creds.get_macaroon_string()?;
 
     // The user passed in a TLS cert to help us establish a secure connection to LND. But now we
-    // need to generate a TLS credentials for connecting securely to the LNDK server.
-    generate_tls_creds(data_dir.clone(), config.tls_ip).map_err(|e| {
-        error!("Error generating tls credentials: {e}");
-    })?;
-    let identity = read_tls(data_dir).map_err(|e| {
-        error!("Error reading tls credentials: {e}");
+    // need TLS credentials for connecting securely to the LNDK server: either ones the operator
+    // manages, or ones we generate and renew ourselves.
+    let tls_source = match (config.tls_cert_path, config.tls_key_path) {
+        (Some(cert_path), Some(key_path)) => TlsSource::Provided {
+            cert_path,
+            key_path,
+        },
+        (None, None) => TlsSource::Generated {
+            data_dir: data_dir.clone(),
+            tls_ips: config.tls_ip,
+        },
+        _ => {
+            error!("Error: tls_cert_path and tls_key_path must be set together.");
+            exit(1);
+        }
+    };
+    let tls_cert = ReloadableCert::load(tls_source).map_err(|e| {
+        error!("Error loading tls credentials: {e}");
     })?;
+    let tls_cert = Arc::new(tls_cert);
 
-    let mut signer = client.clone();
-    let seed = build_seed_from_lnd_node(&mut signer).await.map_err(|e| {
-        error!("Error creating seed: {:?}", e);
+    // Optionally require clients to authenticate with a certificate as well as a macaroon, for
+    // deployments that expose the server beyond localhost.
+    let client_auth = match (config.tls_client_ca_path, config.tls_client_fingerprints) {
+        (Some(ca_path), fingerprints) => {
+            let verifier = client_verifier(&ca_path, fingerprints.as_deref()).map_err(|e| {
+                error!("Error loading tls client ca: {e}");
+            })?;
+            info!("Requiring grpc clients to present a certificate issued by {ca_path:?}.");
+            Some(verifier)
+        }
+        (None, Some(_)) => {
+            error!("Error: tls_client_fingerprints requires tls_client_ca_path to be set.");
+            exit(1);
+        }
+        (None, None) => None,
+    };
+
+    // Load (or create) the root key used to bake lndk's own macaroons, which let clients call
+    // lndk with a restricted set of permissions rather than handing over an LND macaroon.
+    let macaroon_auth = MacaroonAuth::load_or_create(&data_dir).map_err(|e| {
+        error!("Error setting up macaroon authentication: {e}");
     })?;
-    let handler = Arc::new(OfferHandler::new(
-        config.response_invoice_timeout,
-        Some(seed),
-        Some(client.clone()),
-    ));
-    let messenger = LndkOnionMessenger::new();
+
+    let payment_store =
+        PaymentStore::open(&data_dir.join(PAYMENT_STORE_FILENAME)).map_err(|e| {
+            error!("Error opening payment store: {e}");
+        })?;
+
+    // Callers that authenticate with an lndk macaroon are served using our own connection, so
+    // seed the pool with it.
+    let lnd_clients = LndClientPool::new(address, lnd_tls_str);
+    lnd_clients.insert(lnd_macaroon_str.clone(), handle.lnd_client());
 
     let server = LNDKServer::new(
-        Arc::clone(&handler),
-        &info.identity_pubkey,
-        lnd_tls_str,
-        address,
+        handle.offer_handler(),
+        &handle.node_id().to_string(),
+        handle.network(),
+        lnd_clients,
+        lnd_macaroon_str,
+        Arc::new(macaroon_auth),
+        policy,
+        payment_store,
+        handle.custom_messenger(),
+        seed_store,
     )
     .await;
 
+    let webhooks = match config.webhook_url {
+        Some(url) => {
+            let Some(secret) = config.webhook_secret else {
+                error!("Error: webhook_secret must be set to use webhook_url.");
+                exit(1);
+            };
+            let queue =
+                WebhookQueue::open(&data_dir.join(WEBHOOK_QUEUE_FILENAME)).map_err(|e| {
+                    error!("Error opening webhook queue: {e}");
+                })?;
+            let cfg = WebhookCfg {
+                url,
+                secret,
+                max_attempts: config.webhook_max_attempts,
+            };
+            Some(Arc::new(Webhooks::new(
+                cfg,
+                queue,
+                Arc::new(HttpSender::new()),
+            )))
+        }
+        None => None,
+    };
+    let webhooks_listener = listener.clone();
+    let webhooks_fut = async {
+        match &webhooks {
+            Some(webhooks) => {
+                webhooks
+                    .clone()
+                    .run(
+                        handle.offer_handler(),
+                        handle.lnd_client(),
+                        webhooks_listener,
+                    )
+                    .await
+            }
+            None => std::future::pending().await,
+        }
+    };
+
+    // We terminate TLS ourselves rather than leaving it to tonic, so that we can swap in renewed
+    // credentials without restarting the server.
+    let incoming = tls_incoming(addr, tls_cert.server_config(client_auth))
+        .await
+        .map_err(|e| {
+            error!("Error listening on {addr}: {e}");
+        })?;
+    let tls_listener = listener.clone();
     let server_fut = Server::builder()
-        .tls_config(ServerTlsConfig::new().identity(identity))
-        .expect("couldn't configure tls")
         .add_service(OffersServer::new(server))
-        .serve_with_shutdown(addr, listener);
+        .serve_with_incoming_shutdown(incoming, listener);
 
     info!("Starting lndk's grpc server at address {grpc_host}:{grpc_port}");
 
     select! {
-       _ = messenger.run(args, Arc::clone(&handler)) => {
+       _ = node.run() => {
            info!("Onion messenger completed");
        },
+       _ = webhooks_fut => {
+           info!("Webhooks completed");
+       },
+       _ = tls_cert.run(tls_listener) => {
+           info!("TLS reloading completed");
+       },
        result2 = server_fut => {
             match result2 {
                 Ok(_) => info!("API completed"),
@@ -171,6 +333,47 @@ async fn main() -> Result<(), ()> {
     Ok(())
 }
 
+// Re-reads the config files and applies the settings that can be changed while lndk is running:
+// the onion message rate limit, the default invoice timeout and the log level. Changes to other
+// settings take effect the next time lndk is started.
+fn reload_config(handle: &LndkHandle) {
+    let config = match Config::including_optional_config_files(get_conf_file_paths()) {
+        Ok((config, _)) => config,
+        Err(e) => {
+            error!("Error reloading config, keeping the current settings: {e}");
+            return;
+        }
+    };
+
+    let log_file = get_log_file(config.log_file, config.data_dir);
+    if reload_logger(config.log_level, log_file).is_err() {
+        error!("Error reloading log level, keeping the current one.");
+    }
+
+    handle.set_rate_limit(
+        config.rate_limit_count,
+        Duration::from_secs(config.rate_limit_period_secs),
+    );
+
+    match config.response_invoice_timeout {
+        Some(0) => {
+            error!("Error: response_invoice_timeout must be more than 0 seconds, keeping the current one.")
+        }
+        timeout => {
+            handle.set_response_invoice_timeout(timeout.unwrap_or(DEFAULT_RESPONSE_INVOICE_TIMEOUT))
+        }
+    }
+
+    info!("Config reloaded.");
+}
+
+// The log file is placed in the data directory unless it's set explicitly.
+fn get_log_file(log_file: Option<String>, data_dir: Option<String>) -> Option<PathBuf> {
+    log_file
+        .map(PathBuf::from)
+        .or(data_dir.map(|data_dir| PathBuf::from(data_dir).join(DEFAULT_LOG_FILE)))
+}
+
 // Creates lndk's data directory at the specified directory, or ~/.lndk/data if not specified.
 // Process must have write access to the directory.
 fn create_data_dir(data_dir: &Option<String>) -> Result<PathBuf, std::io::Error> {
@@ -0,0 +1,387 @@
+use crate::custom_messages::{CustomMessage, CustomMessageError, CustomMessenger};
+use crate::lnd::{build_seed_from_lnd_node, get_lnd_client, get_network, SEED_KEY_INDEX};
+use crate::offers::get_destination;
+use crate::offers::handler::{CreateOfferParams, OfferHandler, PayOfferParams, PaymentResult};
+use crate::offers::OfferError;
+use crate::rate_limit::RateLimiterCfg;
+use crate::seeds::SeedStore;
+use crate::{set_rate_limit, Cfg, LndkOnionMessenger};
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Network;
+use lightning::offers::offer::Offer;
+use lightning::onion_message::messenger::Destination;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::watch;
+use tonic_lnd::lnrpc::{FeeLimit, GetInfoRequest, ListPeersRequest, Peer};
+use tonic_lnd::tonic::Status;
+use tonic_lnd::{Client, ConnectError};
+use triggered::Trigger;
+
+#[derive(Debug)]
+/// NodeError is an error that occurs while setting up an LndkNode.
+pub enum NodeError {
+    /// We couldn't connect to LND.
+    Connect(ConnectError),
+    /// A call to LND failed.
+    Lnd(Status),
+    /// LND is running on a network we don't support.
+    UnknownNetwork,
+    /// We couldn't derive our seed from LND's node key.
+    DeriveSeed,
+}
+
+impl Display for NodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeError::Connect(e) => write!(f, "Could not connect to lnd: {e}"),
+            NodeError::Lnd(e) => write!(f, "Error calling lnd: {e:?}"),
+            NodeError::UnknownNetwork => write!(f, "Could not get network from lnd"),
+            NodeError::DeriveSeed => write!(f, "Could not derive seed from lnd's node key"),
+        }
+    }
+}
+
+impl std::error::Error for NodeError {}
+
+/// LndkNodeBuilder sets up an LndkNode, for applications that embed lndk rather than running the
+/// lndk binary.
+pub struct LndkNodeBuilder {
+    cfg: Cfg,
+    response_invoice_timeout: Option<u32>,
+    dns_resolvers: Vec<PublicKey>,
+    seed_store: Option<Arc<SeedStore>>,
+}
+
+impl LndkNodeBuilder {
+    pub fn new(cfg: Cfg) -> Self {
+        LndkNodeBuilder {
+            cfg,
+            response_invoice_timeout: None,
+            dns_resolvers: Vec::new(),
+            seed_store: None,
+        }
+    }
+
+    /// Sets the default amount of time in seconds that we'll wait for an invoice when paying an
+    /// offer.
+    pub fn response_invoice_timeout(mut self, timeout: u32) -> Self {
+        self.response_invoice_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the nodes we'll ask to resolve BIP-353 human-readable names.
+    pub fn dns_resolvers(mut self, dns_resolvers: Vec<PublicKey>) -> Self {
+        self.dns_resolvers = dns_resolvers;
+        self
+    }
+
+    /// Sets the store that tracks the generations of our seed, so that the seed can be rotated. If
+    /// it isn't set, we always use the seed lndk was first released with.
+    pub fn seed_store(mut self, seed_store: Arc<SeedStore>) -> Self {
+        self.seed_store = Some(seed_store);
+        self
+    }
+
+    /// Connects to LND and sets up the node. Onion messages aren't processed until the node is
+    /// run.
+    pub async fn build(self) -> Result<LndkNode, NodeError> {
+        let mut client = get_lnd_client(self.cfg.lnd.clone()).map_err(NodeError::Connect)?;
+        let info = client
+            .lightning()
+            .get_info(GetInfoRequest {})
+            .await
+            .map_err(NodeError::Lnd)?
+            .into_inner();
+        let network = get_network(info.clone())
+            .await
+            .map_err(|_| NodeError::UnknownNetwork)?;
+        let node_id = PublicKey::from_str(&info.identity_pubkey).unwrap();
+
+        let mut signer = client.clone();
+        let (key_index, retired) = self.seed_key_indexes();
+        let seed = build_seed_from_lnd_node(&mut signer, key_index)
+            .await
+            .map_err(|_| NodeError::DeriveSeed)?;
+
+        // Offers created with seeds we've rotated away from are accepted until their grace period
+        // is over, so we derive those seeds again too.
+        let mut retired_seeds = Vec::new();
+        for (key_index, expiry) in retired {
+            let retired_seed = build_seed_from_lnd_node(&mut signer, key_index)
+                .await
+                .map_err(|_| NodeError::DeriveSeed)?;
+            retired_seeds.push((retired_seed, expiry));
+        }
+        let offer_handler = Arc::new(self.offer_handler(seed, Some(client.clone()), retired_seeds));
+        let messenger = LndkOnionMessenger::new();
+
+        let handle = LndkHandle {
+            client,
+            node_id,
+            network,
+            offer_handler,
+            custom_messenger: messenger.custom_messenger(),
+            rate_limits: Arc::clone(&messenger.rate_limits),
+            shutdown: self.cfg.signals.shutdown.clone(),
+        };
+
+        Ok(LndkNode {
+            cfg: self.cfg,
+            messenger,
+            handle,
+        })
+    }
+
+    // Returns the key index of the seed we derive from LND's node key, and those of the seeds
+    // we've rotated away from that are still accepted, along with when they stop being accepted.
+    fn seed_key_indexes(&self) -> (i32, Vec<(i32, SystemTime)>) {
+        let Some(store) = &self.seed_store else {
+            return (SEED_KEY_INDEX, Vec::new());
+        };
+        let retired = store
+            .retired()
+            .into_iter()
+            .filter_map(|generation| {
+                store
+                    .expiry(&generation)
+                    .map(|expiry| (generation.key_index, expiry))
+            })
+            .collect();
+
+        (store.current().key_index, retired)
+    }
+
+    // Sets up the offer handler with the options the builder was given.
+    fn offer_handler(
+        &self,
+        seed: [u8; 32],
+        client: Option<Client>,
+        retired_seeds: Vec<([u8; 32], SystemTime)>,
+    ) -> OfferHandler {
+        OfferHandler::new(
+            self.response_invoice_timeout,
+            Some(seed),
+            client,
+            self.dns_resolvers.clone(),
+        )
+        .with_retired_seeds(retired_seeds)
+    }
+}
+
+/// LndkNode processes onion messages on behalf of an LND node once it's run. Use its handle to
+/// make payments and send messages while it's running.
+pub struct LndkNode {
+    cfg: Cfg,
+    messenger: LndkOnionMessenger,
+    handle: LndkHandle,
+}
+
+impl LndkNode {
+    /// Returns a handle to the node, which can be cloned and shared across tasks.
+    pub fn handle(&self) -> LndkHandle {
+        self.handle.clone()
+    }
+
+    /// Processes onion messages until the node is shut down.
+    pub async fn run(&self) -> Result<(), ()> {
+        self.messenger
+            .run(self.cfg.clone(), Arc::clone(&self.handle.offer_handler))
+            .await
+    }
+}
+
+/// LndkHandle lets applications use a running LndkNode.
+#[derive(Clone)]
+pub struct LndkHandle {
+    client: Client,
+    node_id: PublicKey,
+    network: Network,
+    offer_handler: Arc<OfferHandler>,
+    custom_messenger: Arc<CustomMessenger>,
+    rate_limits: Arc<watch::Sender<Option<RateLimiterCfg>>>,
+    shutdown: Trigger,
+}
+
+impl LndkHandle {
+    /// The node id of the LND node we're running on behalf of.
+    pub fn node_id(&self) -> PublicKey {
+        self.node_id
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Returns our connection to LND.
+    pub fn lnd_client(&self) -> Client {
+        self.client.clone()
+    }
+
+    /// Returns the offer handler, for anything that the handle doesn't provide directly.
+    pub fn offer_handler(&self) -> Arc<OfferHandler> {
+        Arc::clone(&self.offer_handler)
+    }
+
+    /// Returns the handler for onion messages of custom types, to register handlers with.
+    pub fn custom_messenger(&self) -> Arc<CustomMessenger> {
+        Arc::clone(&self.custom_messenger)
+    }
+
+    /// Fetches an invoice for the offer and pays it. The amount is required if the offer doesn't
+    /// set one.
+    pub async fn pay_offer(
+        &self,
+        offer: Offer,
+        amount: Option<u64>,
+        payer_note: Option<String>,
+        fee_limit: Option<FeeLimit>,
+    ) -> Result<PaymentResult, OfferError> {
+        let destination = get_destination(&offer).await?;
+        self.offer_handler
+            .pay_offer(PayOfferParams {
+                offer,
+                amount,
+                payer_note,
+                network: self.network,
+                client: self.client.clone(),
+                destination,
+                reply_path: None,
+                response_invoice_timeout: None,
+                fee_limit,
+                payment_id: None,
+            })
+            .await
+    }
+
+    /// Creates an offer that can be paid to our node.
+    pub async fn create_offer(
+        &self,
+        amount_msats: u64,
+        description: Option<String>,
+        expiry: Option<Duration>,
+    ) -> Result<Offer, OfferError> {
+        self.offer_handler
+            .create_offer(CreateOfferParams {
+                client: self.client.clone(),
+                amount_msats,
+                chain: self.network,
+                description,
+                issuer: None,
+                quantity: None,
+                expiry,
+            })
+            .await
+    }
+
+    /// Lists the peers our node is currently connected to.
+    pub async fn peers(&self) -> Result<Vec<Peer>, Status> {
+        let mut client = self.client.clone();
+        let response = client
+            .lightning()
+            .list_peers(ListPeersRequest {
+                latest_error: false,
+            })
+            .await?;
+
+        Ok(response.into_inner().peers)
+    }
+
+    /// Queues an onion message of a custom type to be sent to the destination provided. If
+    /// reply_path is set, the recipient can respond, and its response is delivered to the custom
+    /// messenger.
+    pub fn send_onion_message(
+        &self,
+        message: CustomMessage,
+        destination: Destination,
+        reply_path: bool,
+    ) -> Result<(), CustomMessageError> {
+        self.custom_messenger.send(message, destination, reply_path)
+    }
+
+    /// Changes the number of onion messages each peer may send us per period, and the length of
+    /// the period, while the node is running.
+    pub fn set_rate_limit(&self, call_count: u8, call_period: Duration) {
+        set_rate_limit(&self.rate_limits, call_count, call_period);
+    }
+
+    /// Changes the default amount of time in seconds that we wait for an invoice when paying an
+    /// offer.
+    pub fn set_response_invoice_timeout(&self, timeout: u32) {
+        self.offer_handler.set_response_invoice_timeout(timeout);
+    }
+
+    /// Shuts the node down.
+    pub fn shutdown(&self) {
+        self.shutdown.trigger();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lnd::{Creds, LndCfg};
+    use crate::offers::handler::DEFAULT_RESPONSE_INVOICE_TIMEOUT;
+    use crate::tests::test_utils::pubkey;
+    use crate::LifecycleSignals;
+
+    fn test_cfg() -> Cfg {
+        let (shutdown, listener) = triggered::trigger();
+        let creds = Creds::String {
+            cert: String::new(),
+            macaroon: String::new(),
+        };
+        Cfg {
+            lnd: LndCfg::new("https://127.0.0.1:1".to_string(), creds),
+            signals: LifecycleSignals { shutdown, listener },
+            skip_version_check: false,
+            rate_limit_count: 10,
+            rate_limit_period_secs: 1,
+            dns_resolver: None,
+        }
+    }
+
+    #[test]
+    fn test_builder_defaults() {
+        let builder = LndkNodeBuilder::new(test_cfg());
+        assert_eq!(builder.seed_key_indexes(), (SEED_KEY_INDEX, Vec::new()));
+
+        let handler = builder.offer_handler([1; 32], None, Vec::new());
+        assert_eq!(
+            handler.response_invoice_timeout(),
+            DEFAULT_RESPONSE_INVOICE_TIMEOUT
+        );
+        assert!(handler.dns_resolvers().is_empty());
+    }
+
+    #[test]
+    fn test_builder_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        let seed_store = Arc::new(
+            SeedStore::open(&dir.path().join("seeds.json"), Duration::from_secs(60)).unwrap(),
+        );
+        let retired_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        seed_store.rotate(SEED_KEY_INDEX + 1, retired_at).unwrap();
+
+        let builder = LndkNodeBuilder::new(test_cfg())
+            .response_invoice_timeout(30)
+            .dns_resolvers(vec![pubkey(1)])
+            .seed_store(Arc::clone(&seed_store));
+
+        // The seed is derived with the store's current key index, and the one we rotated away
+        // from is still accepted.
+        let (key_index, retired) = builder.seed_key_indexes();
+        assert_eq!(key_index, SEED_KEY_INDEX + 1);
+        assert_eq!(retired.len(), 1);
+        assert_eq!(retired[0].0, SEED_KEY_INDEX);
+
+        let handler = builder.offer_handler([1; 32], None, Vec::new());
+        assert_eq!(handler.response_invoice_timeout(), 30);
+        assert_eq!(handler.dns_resolvers(), &[pubkey(1)]);
+    }
+}
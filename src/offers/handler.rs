@@ -1,8 +1,9 @@
 use bitcoin::hashes::Hmac;
 use bitcoin::key::Secp256k1;
+use bitcoin::secp256k1::PublicKey;
 use bitcoin::Network;
 use futures::executor::block_on;
-use lightning::blinded_path::message::{BlindedMessagePath, OffersContext};
+use lightning::blinded_path::message::{BlindedMessagePath, DNSResolverContext, OffersContext};
 use lightning::blinded_path::payment::BlindedPaymentPath;
 use lightning::blinded_path::IntroductionNode;
 use lightning::ln::channelmanager::{PaymentId, Verification};
@@ -12,30 +13,91 @@ use lightning::offers::invoice_error::InvoiceError;
 use lightning::offers::invoice_request::InvoiceRequest;
 use lightning::offers::nonce::Nonce;
 use lightning::offers::offer::{Offer, Quantity};
+use lightning::onion_message::dns_resolution::{
+    DNSResolverMessage, DNSResolverMessageHandler, DNSSECProof, DNSSECQuery, HumanReadableName,
+    OMNameResolver,
+};
 use lightning::onion_message::messenger::{
     Destination, MessageSendInstructions, Responder, ResponseInstruction,
 };
 use lightning::onion_message::offers::{OffersMessage, OffersMessageHandler};
 use lightning::sign::EntropySource;
 use log::{debug, error, info, trace, warn};
+use serde::Serialize;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::sync::Mutex;
-use std::time::Duration;
-use tokio::time::{sleep, timeout};
-use tonic_lnd::lnrpc::{FeeLimit, Payment};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::timeout;
+use tonic_lnd::lnrpc::{FeeLimit, GetInfoRequest, Payment};
 use tonic_lnd::Client;
 
 use super::lnd_requests::{
     create_invoice_info_from_request, create_invoice_request, create_offer, get_node_id_from_scid,
-    send_invoice_request, LndkBolt12InvoiceInfo,
+    send_dnssec_query, send_invoice_request, LndkBolt12InvoiceInfo,
+};
+use super::validation::check_invoice_matches_request;
+use super::{validate_invoice, OfferError};
+use crate::offers::lnd_requests::{
+    cheapest_payment_path, estimate_route_fees, send_payment, track_payment, CreateOfferArgs,
 };
-use super::OfferError;
-use crate::offers::lnd_requests::{send_payment, track_payment, CreateOfferArgs};
 use crate::onion_messenger::MessengerUtilities;
 
 pub const DEFAULT_RESPONSE_INVOICE_TIMEOUT: u32 = 15;
 
+// The number of events we buffer for each subscriber before it starts missing them.
+const EVENT_BUFFER: usize = 1000;
+
+/// OfferEvent is a change in the state of a payment we're making, or of an offer we created.
+/// Hashes, ids and preimages are hex-encoded.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OfferEvent {
+    /// We received a valid invoice for an offer we're paying.
+    InvoiceReceived {
+        payment_id: String,
+        payment_hash: String,
+        amount_msats: u64,
+    },
+    /// A payment to an invoice succeeded.
+    PaymentSucceeded {
+        payment_id: String,
+        payment_hash: String,
+        payment_preimage: String,
+        amount_msats: u64,
+        fee_msats: u64,
+    },
+    /// A payment to an invoice failed.
+    PaymentFailed {
+        payment_id: String,
+        payment_hash: String,
+        error: String,
+    },
+    /// We answered an invoice request for one of our offers with an invoice.
+    InvoiceRequestAnswered {
+        payment_hash: String,
+        amount_msats: u64,
+        payer_note: Option<String>,
+        /// When the invoice expires, in seconds since the unix epoch.
+        expires_at: u64,
+    },
+    /// An invoice that we sent in response to an invoice request was paid. OfferHandler doesn't
+    /// see payments coming in, so this is left to whoever watches LND's invoices.
+    PaymentReceived {
+        payment_hash: String,
+        amount_msats: u64,
+    },
+}
+
+// The keys derived from each generation of our seed. New offers and payments use the current key,
+// and the keys we've rotated away from are still accepted until they expire.
+struct SeedKeys {
+    current: ExpandedKey,
+    retired: Vec<(ExpandedKey, SystemTime)>,
+}
+
 pub(crate) enum PaymentState {
     InvoiceRequestCreated,
     InvoiceReceived,
@@ -44,6 +106,11 @@ pub(crate) enum PaymentState {
 pub(crate) struct PaymentInfo {
     state: PaymentState,
     invoice: Option<Bolt12Invoice>,
+    // The invoice request we sent, which any invoice we receive has to be consistent with.
+    invoice_request: InvoiceRequest,
+    // Wakes up whoever is waiting for the invoice, either with the invoice itself or with the
+    // reason that the payment was dropped.
+    invoice_waiter: Option<oneshot::Sender<Result<Bolt12Invoice, OfferError>>>,
 }
 pub struct OfferHandler {
     // active_payments holds a list of payments we're currently attempting to make. When we create
@@ -52,11 +119,19 @@ pub struct OfferHandler {
     active_payments: Mutex<HashMap<PaymentId, PaymentInfo>>,
     pending_messages: Mutex<Vec<(OffersMessage, MessageSendInstructions)>>,
     pub messenger_utils: MessengerUtilities,
-    expanded_key: ExpandedKey,
+    seed_keys: RwLock<SeedKeys>,
     /// The amount of time in seconds that we will wait for the offer creator to respond with
-    /// an invoice. If not provided, we will use the default value of 15 seconds.
-    pub response_invoice_timeout: u32,
+    /// an invoice. If not provided, we will use the default value of 15 seconds. It may be changed
+    /// while we're running with set_response_invoice_timeout.
+    response_invoice_timeout: AtomicU32,
     client: Option<Client>,
+    // The nodes we ask to resolve human-readable names for us over onion messages.
+    dns_resolvers: Vec<PublicKey>,
+    name_resolver: OMNameResolver,
+    // Wakes up whoever is waiting on a name to resolve, keyed by the id we resolved it with.
+    name_waiters: Mutex<HashMap<PaymentId, oneshot::Sender<Offer>>>,
+    pending_dns_messages: Mutex<Vec<(DNSResolverMessage, MessageSendInstructions)>>,
+    events: broadcast::Sender<OfferEvent>,
 }
 
 #[derive(Clone)]
@@ -75,6 +150,8 @@ pub struct PayOfferParams {
     /// an invoice. If not provided, we will use the default value of 15 seconds.
     pub response_invoice_timeout: Option<u32>,
     pub fee_limit: Option<FeeLimit>,
+    /// The id to track the payment with. If not provided, a random one will be generated.
+    pub payment_id: Option<PaymentId>,
 }
 
 #[derive(Clone)]
@@ -89,6 +166,31 @@ pub struct SendPaymentParams {
     pub fee_limit: Option<FeeLimit>,
 }
 
+/// The outcome of paying an offer, along with the invoice we paid.
+#[derive(Clone, Debug)]
+pub struct PaymentResult {
+    /// The payment as LND reports it once it has succeeded.
+    pub payment: Payment,
+    pub invoice: Bolt12Invoice,
+    /// The invoice request we sent for the invoice, which proves that we were the payer.
+    pub invoice_request: InvoiceRequest,
+    pub payment_id: PaymentId,
+}
+
+/// What it would cost to pay an invoice, over the routes we found to its payment paths. Payments
+/// are made over the cheapest route.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeEstimate {
+    /// The fees of the cheapest and most expensive routes, in millisatoshis.
+    pub min_fee_msats: u64,
+    pub max_fee_msats: u64,
+    /// The lowest and highest absolute block heights that the payment's HTLCs would time out at.
+    pub min_total_time_lock: u32,
+    pub max_total_time_lock: u32,
+    /// The number of payment paths we found a route to.
+    pub routable_paths: usize,
+}
+
 pub struct CreateOfferParams {
     /// LND tonic client used to query information from the node.
     pub client: Client,
@@ -113,6 +215,7 @@ impl OfferHandler {
         response_invoice_timeout: Option<u32>,
         seed: Option<[u8; 32]>,
         client: Option<Client>,
+        dns_resolvers: Vec<PublicKey>,
     ) -> Self {
         let messenger_utils = MessengerUtilities::default();
         let random_bytes = match seed {
@@ -127,105 +230,157 @@ impl OfferHandler {
             active_payments: Mutex::new(HashMap::new()),
             pending_messages: Mutex::new(Vec::new()),
             messenger_utils,
-            expanded_key,
-            response_invoice_timeout,
+            seed_keys: RwLock::new(SeedKeys {
+                current: expanded_key,
+                retired: Vec::new(),
+            }),
+            response_invoice_timeout: AtomicU32::new(response_invoice_timeout),
             client,
+            dns_resolvers,
+            // The resolver is brought up to date with the chain before we resolve any names.
+            name_resolver: OMNameResolver::new(0, 0),
+            name_waiters: Mutex::new(HashMap::new()),
+            pending_dns_messages: Mutex::new(Vec::new()),
+            events: broadcast::channel(EVENT_BUFFER).0,
         }
     }
 
-    /// Adds an offer to be paid with the amount specified. May only be called once for a single
-    /// offer.
-    pub async fn pay_offer(&self, cfg: PayOfferParams) -> Result<Payment, OfferError> {
-        let client_clone = cfg.client.clone();
-        let fee_limit = cfg.fee_limit.clone();
-        let (invoice, validated_amount, payment_id) = self.get_invoice(cfg).await?;
+    /// The amount of time in seconds that we wait for an invoice when a payment doesn't set its
+    /// own timeout.
+    pub fn response_invoice_timeout(&self) -> u32 {
+        self.response_invoice_timeout.load(Ordering::Relaxed)
+    }
 
-        self.pay_invoice(
-            client_clone,
-            validated_amount,
-            &invoice,
-            payment_id,
-            fee_limit,
-        )
-        .await
+    /// The nodes we ask to resolve BIP-353 human-readable names.
+    pub fn dns_resolvers(&self) -> &[PublicKey] {
+        &self.dns_resolvers
     }
 
-    /// Sends an invoice request and waits for an invoice to be sent back to us.
-    /// Reminder that if this method returns an error after create_invoice_request is called, we
-    /// *must* remove the payment_id from self.active_payments.
-    pub async fn get_invoice(
-        &self,
-        cfg: PayOfferParams,
-    ) -> Result<(Bolt12Invoice, u64, PaymentId), OfferError> {
-        let (invoice_request, payment_id, validated_amount, offer_context) =
-            create_invoice_request(
-                cfg.offer.clone(),
-                cfg.network,
-                &self.messenger_utils,
-                self.expanded_key,
-                cfg.amount,
-                cfg.payer_note,
+    /// Changes the default amount of time that we wait for an invoice. Payments that are already
+    /// waiting keep the timeout they started with.
+    pub fn set_response_invoice_timeout(&self, timeout: u32) {
+        self.response_invoice_timeout
+            .store(timeout, Ordering::Relaxed);
+    }
+
+    /// Accepts offers and payments made with older generations of our seed until the expiry
+    /// provided for each of them.
+    pub fn with_retired_seeds(self, seeds: Vec<([u8; 32], SystemTime)>) -> Self {
+        self.seed_keys.write().unwrap().retired = seeds
+            .into_iter()
+            .map(|(seed, expiry)| (ExpandedKey::new(seed), expiry))
+            .collect();
+        self
+    }
+
+    /// Switches to a new generation of our seed. The current seed is still accepted when
+    /// verifying offers and payments until the expiry provided.
+    pub fn rotate_seed(&self, seed: [u8; 32], expiry: SystemTime) {
+        let mut seed_keys = self.seed_keys.write().unwrap();
+        let previous = std::mem::replace(&mut seed_keys.current, ExpandedKey::new(seed));
+        seed_keys.retired.push((previous, expiry));
+        let now = SystemTime::now();
+        seed_keys.retired.retain(|(_, expiry)| *expiry > now);
+    }
+
+    // The key that new offers and payments are created with.
+    fn current_key(&self) -> ExpandedKey {
+        self.seed_keys.read().unwrap().current
+    }
+
+    // The keys that we accept offers and payments from, newest first.
+    fn verification_keys(&self) -> Vec<ExpandedKey> {
+        let seed_keys = self.seed_keys.read().unwrap();
+        let now = SystemTime::now();
+        std::iter::once(seed_keys.current)
+            .chain(
+                seed_keys
+                    .retired
+                    .iter()
+                    .rev()
+                    .filter(|(_, expiry)| *expiry > now)
+                    .map(|(key, _)| *key),
             )
-            .await?;
+            .collect()
+    }
 
-        {
-            let mut active_payments = self.active_payments.lock().unwrap();
-            match active_payments.entry(payment_id) {
-                Entry::Occupied(_) => return Err(OfferError::AlreadyProcessing(payment_id)),
-                Entry::Vacant(v) => {
-                    v.insert(PaymentInfo {
-                        state: PaymentState::InvoiceRequestCreated,
-                        invoice: None,
-                    });
-                }
-            };
-        }
+    /// Returns a receiver for the payment and offer events that happen from now on.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<OfferEvent> {
+        self.events.subscribe()
+    }
 
-        let (contents, send_instructions) = send_invoice_request(
-            cfg.destination.clone(),
-            cfg.client.clone(),
-            invoice_request,
-            offer_context,
-            &self.messenger_utils,
-        )
-        .await
-        .inspect_err(|_| {
-            let mut active_payments = self.active_payments.lock().unwrap();
-            active_payments.remove(&payment_id);
-        })?;
+    fn notify(&self, event: OfferEvent) {
+        // Nobody may be listening, which is fine.
+        let _ = self.events.send(event);
+    }
 
-        {
-            let mut pending_messages = self.pending_messages.lock().unwrap();
-            pending_messages.push((contents, send_instructions));
-            std::mem::drop(pending_messages);
-        }
+    fn notify_payment_result(
+        &self,
+        payment_id: PaymentId,
+        payment_hash: [u8; 32],
+        result: &Result<Payment, OfferError>,
+    ) {
+        let payment_id = hex::encode(payment_id.0);
+        let payment_hash = hex::encode(payment_hash);
+        let event = match result {
+            Ok(payment) => OfferEvent::PaymentSucceeded {
+                payment_id,
+                payment_hash,
+                payment_preimage: payment.payment_preimage.clone(),
+                amount_msats: payment.value_msat as u64,
+                fee_msats: payment.fee_msat as u64,
+            },
+            Err(e) => OfferEvent::PaymentFailed {
+                payment_id,
+                payment_hash,
+                error: e.to_string(),
+            },
+        };
+        self.notify(event);
+    }
+
+    /// Adds an offer to be paid with the amount specified. May only be called once for a single
+    /// offer.
+    pub async fn pay_offer(&self, cfg: PayOfferParams) -> Result<PaymentResult, OfferError> {
+        let client_clone = cfg.client.clone();
+        let fee_limit = cfg.fee_limit.clone();
+        let network = cfg.network;
+        let (invoice, validated_amount, payment_id) = self.get_invoice(cfg).await?;
 
-        let cfg_timeout = cfg
-            .response_invoice_timeout
-            .unwrap_or(self.response_invoice_timeout);
+        if let Err(e) = validate_invoice(&invoice, network, validated_amount) {
+            self.remove_active_payment(payment_id);
+            return Err(e);
+        }
 
-        let invoice = match timeout(
-            Duration::from_secs(cfg_timeout as u64),
-            self.wait_for_invoice(payment_id),
-        )
-        .await
-        {
-            Ok(invoice) => invoice,
-            Err(_) => {
-                error!("Did not receive invoice in {cfg_timeout} seconds.");
-                let mut active_payments = self.active_payments.lock().unwrap();
-                active_payments.remove(&payment_id);
-                return Err(OfferError::InvoiceTimeout(cfg_timeout));
+        // The payment may have been cancelled after we received the invoice, in which case we
+        // mustn't pay it.
+        let invoice_request = {
+            let mut active_payments = self.active_payments.lock().unwrap();
+            match active_payments.get_mut(&payment_id) {
+                Some(pay_info) => {
+                    pay_info.state = PaymentState::PaymentDispatched;
+                    pay_info.invoice_request.clone()
+                }
+                None => return Err(OfferError::PaymentCancelled(payment_id)),
             }
         };
-        {
-            let mut active_payments = self.active_payments.lock().unwrap();
-            active_payments
-                .entry(payment_id)
-                .and_modify(|entry| entry.state = PaymentState::InvoiceReceived);
-        }
 
-        Ok((invoice, validated_amount, payment_id))
+        let payment = self
+            .pay_invoice(
+                client_clone,
+                validated_amount,
+                &invoice,
+                payment_id,
+                fee_limit,
+            )
+            .await?;
+
+        Ok(PaymentResult {
+            payment,
+            invoice,
+            invoice_request,
+            payment_id,
+        })
     }
 
     /// Sends an invoice request and waits for an invoice to be sent back to us.
@@ -239,10 +394,38 @@ impl OfferHandler {
         payment_id: PaymentId,
         fee_limit: Option<FeeLimit>,
     ) -> Result<Payment, OfferError> {
-        let payment_hash = invoice.payment_hash();
-        let payment_path = &invoice.payment_paths()[0];
+        let payment_hash = invoice.payment_hash().0;
+        let result = self
+            .send_invoice_payment(client, amount, invoice, payment_id, fee_limit)
+            .await;
+
+        // However the payment ends, it's no longer active and subscribers hear about it.
+        self.active_payments.lock().unwrap().remove(&payment_id);
+        self.notify_payment_result(payment_id, payment_hash, &result);
+        result
+    }
+
+    // Pays an invoice and tracks the payment until it settles.
+    async fn send_invoice_payment(
+        &self,
+        client: Client,
+        amount: u64,
+        invoice: &Bolt12Invoice,
+        payment_id: PaymentId,
+        fee_limit: Option<FeeLimit>,
+    ) -> Result<Payment, OfferError> {
+        let payment_hash = invoice.payment_hash().0;
+
+        // We pay over the path with the cheapest route, which is the fee that estimate_fee
+        // reports as the minimum.
+        let payment_path = cheapest_payment_path(
+            &mut client.clone(),
+            invoice.payment_paths(),
+            amount,
+            fee_limit.clone(),
+        )
+        .await?;
 
-        let payment_hash = payment_hash.0;
         let params = SendPaymentParams {
             path: payment_path.clone(),
             cltv_expiry_delta: payment_path.payinfo.cltv_expiry_delta,
@@ -266,12 +449,7 @@ impl OfferHandler {
             intro_node_id
         );
 
-        send_payment(client.clone(), params)
-            .await
-            .inspect_err(|_| {
-                let mut active_payments = self.active_payments.lock().unwrap();
-                active_payments.remove(&payment_id);
-            })?;
+        send_payment(client.clone(), params).await?;
 
         {
             let mut active_payments = self.active_payments.lock().unwrap();
@@ -281,36 +459,137 @@ impl OfferHandler {
         }
 
         // We'll track the payment until it settles.
-        track_payment(client, payment_hash)
+        track_payment(client, payment_hash).await
+    }
+
+    /// Estimates the fee for paying an invoice, by querying for a route to each of its payment
+    /// paths the same way pay_invoice does. pay_invoice uses the cheapest of these routes, so the
+    /// minimum fee is what we expect to pay. Nothing is paid.
+    pub(crate) async fn estimate_fee(
+        &self,
+        mut client: Client,
+        amount: u64,
+        invoice: &Bolt12Invoice,
+        fee_limit: Option<FeeLimit>,
+    ) -> Result<FeeEstimate, OfferError> {
+        estimate_route_fees(&mut client, invoice.payment_paths(), amount, fee_limit).await
+    }
+
+    /// Resolves a BIP-353 human-readable name to the offer in its DNS TXT record. We ask each of
+    /// our DNS resolvers for a DNSSEC proof of the record over onion messages (bLIP-32), and use
+    /// the first proof that checks out.
+    pub async fn resolve_name(
+        &self,
+        mut client: Client,
+        name: HumanReadableName,
+        response_timeout: Option<u32>,
+    ) -> Result<Offer, OfferError> {
+        if self.dns_resolvers.is_empty() {
+            return Err(OfferError::NoDnsResolvers);
+        }
+
+        // Proofs are checked against the latest block time, so the resolver needs to know about
+        // the chain tip.
+        let info = client
+            .lightning()
+            .get_info(GetInfoRequest {})
+            .await
+            .map_err(|e| OfferError::ResolveNameFailure(format!("couldn't get chain tip: {e}")))?
+            .into_inner();
+        self.name_resolver
+            .new_best_block(info.block_height, info.best_header_timestamp as u32);
+
+        let payment_id = PaymentId(self.messenger_utils.get_secure_random_bytes());
+        let (name_waiter, name_receiver) = oneshot::channel();
+        self.name_waiters
+            .lock()
+            .unwrap()
+            .insert(payment_id, name_waiter);
+
+        let mut queried = false;
+        for resolver in &self.dns_resolvers {
+            let (query, context) = match self.name_resolver.resolve_name(
+                payment_id,
+                name.clone(),
+                &self.messenger_utils,
+            ) {
+                Ok(query) => query,
+                Err(()) => {
+                    self.name_waiters.lock().unwrap().remove(&payment_id);
+                    return Err(OfferError::ResolveNameFailure(
+                        "invalid human-readable name".to_string(),
+                    ));
+                }
+            };
+
+            match send_dnssec_query(
+                *resolver,
+                client.clone(),
+                query,
+                context,
+                &self.messenger_utils,
+            )
             .await
-            .inspect(|_| {
-                let mut active_payments = self.active_payments.lock().unwrap();
-                active_payments.remove(&payment_id);
-            })
-            .inspect_err(|_| {
-                let mut active_payments = self.active_payments.lock().unwrap();
-                active_payments.remove(&payment_id);
-            })
-    }
-
-    /// wait_for_invoice waits for the offer creator to respond with an invoice.
-    async fn wait_for_invoice(&self, payment_id: PaymentId) -> Bolt12Invoice {
-        loop {
             {
-                let active_payments = self.active_payments.lock().unwrap();
-                if let Some(pay_info) = active_payments.get(&payment_id) {
-                    if let Some(invoice) = pay_info.invoice.clone() {
-                        return invoice;
-                    }
-                };
+                Ok(message) => {
+                    self.pending_dns_messages.lock().unwrap().push(message);
+                    queried = true;
+                }
+                Err(e) => warn!("Could not query DNS resolver {resolver}: {e}"),
+            }
+        }
+        if !queried {
+            self.name_waiters.lock().unwrap().remove(&payment_id);
+            return Err(OfferError::ResolveNameFailure(
+                "couldn't reach any DNS resolvers".to_string(),
+            ));
+        }
+
+        let cfg_timeout = response_timeout.unwrap_or(self.response_invoice_timeout());
+        match timeout(Duration::from_secs(cfg_timeout as u64), name_receiver).await {
+            Ok(Ok(offer)) => Ok(offer),
+            Ok(Err(_)) => Err(OfferError::ResolveNameFailure(
+                "name resolution was dropped".to_string(),
+            )),
+            Err(_) => {
+                error!("Did not receive a DNSSEC proof in {cfg_timeout} seconds.");
+                self.name_waiters.lock().unwrap().remove(&payment_id);
+                Err(OfferError::ResolveNameTimeout(cfg_timeout))
             }
-            sleep(Duration::from_secs(2)).await;
         }
     }
 
-    pub(crate) fn remove_active_payment(&self, payment_id: PaymentId) {
+    /// Stops tracking a payment, returning the invoice request that we sent for it.
+    pub(crate) fn remove_active_payment(&self, payment_id: PaymentId) -> Option<InvoiceRequest> {
         let mut active_payments = self.active_payments.lock().unwrap();
-        active_payments.remove(&payment_id);
+        active_payments
+            .remove(&payment_id)
+            .map(|pay_info| pay_info.invoice_request)
+    }
+
+    /// Cancels a payment that's still waiting on an invoice, or that has received an invoice that
+    /// we haven't paid yet. Whoever is waiting on the invoice is woken up with a cancellation
+    /// error, and any invoice that arrives later on is ignored. Payments that have already been
+    /// dispatched can't be cancelled.
+    pub fn cancel_payment(&self, payment_id: PaymentId) -> Result<(), OfferError> {
+        let mut active_payments = self.active_payments.lock().unwrap();
+        match active_payments.get(&payment_id) {
+            None => return Err(OfferError::PaymentNotFound(payment_id)),
+            Some(PaymentInfo {
+                state: PaymentState::PaymentDispatched,
+                ..
+            }) => return Err(OfferError::PaymentAlreadyDispatched(payment_id)),
+            Some(_) => {}
+        }
+
+        if let Some(mut pay_info) = active_payments.remove(&payment_id) {
+            info!("Cancelling payment {payment_id}.");
+            if let Some(waiter) = pay_info.invoice_waiter.take() {
+                let _ = waiter.send(Err(OfferError::PaymentCancelled(payment_id)));
+            }
+        }
+
+        Ok(())
     }
 
     /// Handles an invoice error for a payment ID.
@@ -321,17 +600,27 @@ impl OfferHandler {
         nonce: Nonce,
         hmac: Hmac<bitcoin::hashes::sha256::Hash>,
     ) {
-        if let Ok(()) = payment_id.verify_for_offer_payment(hmac, nonce, &self.expanded_key) {
+        let verified = self.verification_keys().iter().any(|key| {
+            payment_id
+                .verify_for_offer_payment(hmac, nonce, key)
+                .is_ok()
+        });
+        if verified {
             error!("Received an invoice error for payment_id {payment_id}. Payment is abandoned.");
             let mut active_payments = self.active_payments.lock().unwrap();
-            active_payments.remove(&payment_id);
+            if let Some(mut pay_info) = active_payments.remove(&payment_id) {
+                if let Some(waiter) = pay_info.invoice_waiter.take() {
+                    let _ = waiter.send(Err(OfferError::PaymentAbandoned(payment_id)));
+                }
+            }
         }
     }
 
     pub async fn create_offer(&self, mut params: CreateOfferParams) -> Result<Offer, OfferError> {
         let args = CreateOfferArgs::from_params(&params);
         let client = params.client.lightning().clone();
-        create_offer(client, args, &self.messenger_utils, &self.expanded_key).await
+        let expanded_key = self.current_key();
+        create_offer(client, args, &self.messenger_utils, &expanded_key).await
     }
 
     pub async fn create_invoice(
@@ -345,7 +634,7 @@ impl OfferHandler {
 
 impl Default for OfferHandler {
     fn default() -> Self {
-        Self::new(None, None, None)
+        Self::new(None, None, None, vec![])
     }
 }
 
@@ -370,17 +659,15 @@ impl OffersMessageHandler for OfferHandler {
 
                 let secp_ctx = &Secp256k1::new();
 
-                // Clone invoice_request before verification since it consumes the value.
+                // Verification consumes the invoice request, so we verify clones of it. Offers
+                // created with an older generation of our seed are still accepted.
                 // TODO: create_invoice should use VerifiedInvoiceRequest instead of InvoiceRequest.
-                let invoice_request_clone = invoice_request.clone();
-                let verfied_invoice = match invoice_request.verify_using_recipient_data(
-                    nonce,
-                    &self.expanded_key,
-                    secp_ctx,
-                ) {
-                    Ok(invoice) => invoice,
-                    Err(_) => return None,
-                };
+                let verfied_invoice = self.verification_keys().iter().find_map(|key| {
+                    invoice_request
+                        .clone()
+                        .verify_using_recipient_data(nonce, key, secp_ctx)
+                        .ok()
+                })?;
 
                 let client = match self.client {
                     Some(_) => self.client.clone().unwrap(),
@@ -390,14 +677,13 @@ impl OffersMessageHandler for OfferHandler {
                     }
                 };
                 log::trace!("Creating invoice");
-                let invoice_info =
-                    match block_on(self.create_invoice(client, invoice_request_clone)) {
-                        Ok(invoice) => invoice,
-                        Err(e) => {
-                            error!("Error creating invoice: {e}");
-                            return None;
-                        }
-                    };
+                let invoice_info = match block_on(self.create_invoice(client, invoice_request)) {
+                    Ok(invoice) => invoice,
+                    Err(e) => {
+                        error!("Error creating invoice: {e}");
+                        return None;
+                    }
+                };
                 log::trace!("Invoice created: {:?}", invoice_info);
 
                 let invoice_builder = match verfied_invoice.respond_using_derived_keys(
@@ -419,6 +705,13 @@ impl OffersMessageHandler for OfferHandler {
 
                 match invoice_result {
                     Ok(invoice) => {
+                        self.notify(OfferEvent::InvoiceRequestAnswered {
+                            payment_hash: hex::encode(invoice.payment_hash().0),
+                            amount_msats: invoice.amount_msats(),
+                            payer_note: invoice.payer_note().map(|note| note.to_string()),
+                            expires_at: (invoice.created_at() + invoice.relative_expiry())
+                                .as_secs(),
+                        });
                         {
                             let mut pending_messages = self.pending_messages.lock().unwrap();
                             pending_messages.push((
@@ -449,13 +742,13 @@ impl OffersMessageHandler for OfferHandler {
                         return None;
                     }
                 };
-                match invoice.verify_using_payer_data(
-                    payment_id,
-                    nonce,
-                    &self.expanded_key,
-                    secp_ctx,
-                ) {
-                    Ok(payment_id) => {
+                let verified = self.verification_keys().iter().find_map(|key| {
+                    invoice
+                        .verify_using_payer_data(payment_id, nonce, key, secp_ctx)
+                        .ok()
+                });
+                match verified {
+                    Some(payment_id) => {
                         info!("Successfully verified invoice for payment_id {payment_id}");
                         let mut active_payments = self.active_payments.lock().unwrap();
                         let Some(pay_info) = active_payments.get_mut(&payment_id) else {
@@ -466,12 +759,36 @@ impl OffersMessageHandler for OfferHandler {
                             warn!("We already received an invoice with this payment id. Invoice is ignored.");
                             return None;
                         }
+                        // The invoice is genuinely a response to our request, but we still need to
+                        // make sure the payee didn't change its terms (for instance, asking for a
+                        // higher amount) before we accept it.
+                        if let Err(e) =
+                            check_invoice_matches_request(&invoice, &pay_info.invoice_request)
+                        {
+                            error!("Invoice for payment_id {payment_id} is inconsistent with our request: {e}. Payment is abandoned.");
+                            let reply = InvoiceError::from_string(e.to_string());
+                            if let Some(mut pay_info) = active_payments.remove(&payment_id) {
+                                if let Some(waiter) = pay_info.invoice_waiter.take() {
+                                    let _ = waiter.send(Err(e));
+                                }
+                            }
+                            return responder
+                                .map(|r| (OffersMessage::InvoiceError(reply), r.respond()));
+                        }
                         pay_info.state = PaymentState::InvoiceReceived;
                         pay_info.invoice = Some(invoice.clone());
+                        self.notify(OfferEvent::InvoiceReceived {
+                            payment_id: hex::encode(payment_id.0),
+                            payment_hash: hex::encode(invoice.payment_hash().0),
+                            amount_msats: invoice.amount_msats(),
+                        });
+                        if let Some(waiter) = pay_info.invoice_waiter.take() {
+                            let _ = waiter.send(Ok(invoice));
+                        }
 
                         None
                     }
-                    Err(()) => responder.map(|r| {
+                    None => responder.map(|r| {
                         (
                             OffersMessage::InvoiceError(InvoiceError::from_string(String::from(
                                 "invoice verification failure",
@@ -501,14 +818,73 @@ impl OffersMessageHandler for OfferHandler {
     }
 }
 
+impl DNSResolverMessageHandler for OfferHandler {
+    // We only resolve names for ourselves, so we don't answer queries from others.
+    fn handle_dnssec_query(
+        &self,
+        _message: DNSSECQuery,
+        _responder: Option<Responder>,
+    ) -> Option<(DNSResolverMessage, ResponseInstruction)> {
+        None
+    }
+
+    fn handle_dnssec_proof(&self, message: DNSSECProof, context: DNSResolverContext) {
+        let Some((names, offer)) = self
+            .name_resolver
+            .handle_dnssec_proof_for_offer(message, context)
+        else {
+            warn!("Received a DNSSEC proof that doesn't resolve to an offer.");
+            return;
+        };
+
+        let mut name_waiters = self.name_waiters.lock().unwrap();
+        for (name, payment_id) in names {
+            if let Some(name_waiter) = name_waiters.remove(&payment_id) {
+                info!("Resolved {}@{} to an offer.", name.user(), name.domain());
+                let _ = name_waiter.send(offer.clone());
+            }
+        }
+    }
+
+    fn release_pending_messages(&self) -> Vec<(DNSResolverMessage, MessageSendInstructions)> {
+        core::mem::take(&mut self.pending_dns_messages.lock().unwrap())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::PaymentInfo;
     use super::PaymentState;
     use super::*;
+    use crate::tests::test_utils::{build_invoice_request, build_offer};
 
     const NONCE_BYTES: &[u8] = &[42u8; 16];
 
+    fn test_invoice_request() -> InvoiceRequest {
+        build_invoice_request(&build_offer(20_000), 20_000)
+    }
+
+    #[test]
+    fn test_notify_payment_failed() {
+        let handler = OfferHandler::default();
+        let mut events = handler.subscribe_events();
+
+        handler.notify_payment_result(
+            PaymentId([42; 32]),
+            [1; 32],
+            &Err(OfferError::PaymentFailure),
+        );
+
+        assert_eq!(
+            events.try_recv().unwrap(),
+            OfferEvent::PaymentFailed {
+                payment_id: hex::encode([42; 32]),
+                payment_hash: hex::encode([1; 32]),
+                error: OfferError::PaymentFailure.to_string(),
+            }
+        );
+    }
+
     #[test]
     fn test_handle_invoice_error_existing_payment() {
         // Create an OfferHandler with a payment ID in active_payments
@@ -524,12 +900,14 @@ mod tests {
                 PaymentInfo {
                     state: PaymentState::InvoiceRequestCreated,
                     invoice: None,
+                    invoice_request: test_invoice_request(),
+                    invoice_waiter: None,
                 },
             );
         }
 
         // Calculate a valid HMAC
-        let hmac = payment_id.hmac_for_offer_payment(nonce, &handler.expanded_key);
+        let hmac = payment_id.hmac_for_offer_payment(nonce, &handler.current_key());
 
         // Call handle_invoice_error
         handler.handle_invoice_error(payment_id, nonce, hmac);
@@ -539,6 +917,32 @@ mod tests {
         assert!(!active_payments.contains_key(&payment_id));
     }
 
+    #[test]
+    fn test_verify_with_rotated_seed() {
+        let handler = OfferHandler::default();
+        let nonce = Nonce::try_from(NONCE_BYTES).unwrap();
+        let payment_id = PaymentId([42; 32]);
+        let first_key = handler.current_key();
+        let hmac = payment_id.hmac_for_offer_payment(nonce, &first_key);
+
+        // Payments authenticated with the previous seed are accepted until it expires.
+        handler.rotate_seed([1; 32], SystemTime::now() + Duration::from_secs(3600));
+        assert_eq!(handler.current_key(), ExpandedKey::new([1; 32]));
+        assert!(payment_id
+            .verify_for_offer_payment(hmac, nonce, &handler.current_key())
+            .is_err());
+        assert!(handler.verification_keys().iter().any(|key| payment_id
+            .verify_for_offer_payment(hmac, nonce, key)
+            .is_ok()));
+
+        // Seeds whose grace period is over are no longer accepted.
+        handler.rotate_seed([2; 32], SystemTime::now() - Duration::from_secs(1));
+        assert_eq!(
+            handler.verification_keys(),
+            vec![ExpandedKey::new([2; 32]), first_key]
+        );
+    }
+
     #[test]
     fn test_handle_invoice_error_nonexistent_payment() {
         // Create an OfferHandler with an empty active_payments
@@ -547,9 +951,75 @@ mod tests {
         let nonce = Nonce::try_from(NONCE_BYTES).unwrap();
 
         // Calculate a valid HMAC
-        let hmac = payment_id.hmac_for_offer_payment(nonce, &handler.expanded_key);
+        let hmac = payment_id.hmac_for_offer_payment(nonce, &handler.current_key());
 
         // Call handle_invoice_error and nothing should happen.
         handler.handle_invoice_error(payment_id, nonce, hmac);
     }
+
+    #[test]
+    fn test_cancel_payment() {
+        let handler = OfferHandler::default();
+        let payment_id = PaymentId([42; 32]);
+        let (invoice_waiter, mut invoice_receiver) = oneshot::channel();
+        {
+            let mut active_payments = handler.active_payments.lock().unwrap();
+            active_payments.insert(
+                payment_id,
+                PaymentInfo {
+                    state: PaymentState::InvoiceRequestCreated,
+                    invoice: None,
+                    invoice_request: test_invoice_request(),
+                    invoice_waiter: Some(invoice_waiter),
+                },
+            );
+        }
+
+        assert!(handler.cancel_payment(payment_id).is_ok());
+        assert!(!handler
+            .active_payments
+            .lock()
+            .unwrap()
+            .contains_key(&payment_id));
+
+        // The waiter should be woken up with a cancellation error.
+        assert!(matches!(
+            invoice_receiver.try_recv(),
+            Ok(Err(OfferError::PaymentCancelled(_)))
+        ));
+
+        // Cancelling again should fail, since the payment no longer exists.
+        assert!(matches!(
+            handler.cancel_payment(payment_id),
+            Err(OfferError::PaymentNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_cancel_dispatched_payment() {
+        let handler = OfferHandler::default();
+        let payment_id = PaymentId([42; 32]);
+        {
+            let mut active_payments = handler.active_payments.lock().unwrap();
+            active_payments.insert(
+                payment_id,
+                PaymentInfo {
+                    state: PaymentState::PaymentDispatched,
+                    invoice: None,
+                    invoice_request: test_invoice_request(),
+                    invoice_waiter: None,
+                },
+            );
+        }
+
+        assert!(matches!(
+            handler.cancel_payment(payment_id),
+            Err(OfferError::PaymentAlreadyDispatched(_))
+        ));
+        assert!(handler
+            .active_payments
+            .lock()
+            .unwrap()
+            .contains_key(&payment_id));
+    }
 }
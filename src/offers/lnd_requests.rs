@@ -6,7 +6,7 @@ use std::{
 use bitcoin::{hashes::Hash, key::Secp256k1, secp256k1::PublicKey, Network};
 use lightning::{
     blinded_path::{
-        message::{BlindedMessagePath, MessageContext, OffersContext},
+        message::{BlindedMessagePath, DNSResolverContext, MessageContext, OffersContext},
         payment::BlindedPaymentPath,
         Direction, IntroductionNode,
     },
@@ -20,6 +20,7 @@ use lightning::{
         offer::{Offer, OfferBuilder, Quantity},
     },
     onion_message::{
+        dns_resolution::{DNSResolverMessage, DNSSECQuery},
         messenger::{Destination, MessageSendInstructions},
         offers::OffersMessage,
     },
@@ -28,7 +29,8 @@ use lightning::{
 };
 use log::{debug, error, trace};
 use tonic_lnd::{
-    lnrpc::{ChanInfoRequest, GetInfoRequest, Payment},
+    lnrpc::{ChanInfoRequest, FeeLimit, GetInfoRequest, Payment, Route},
+    tonic::Status,
     Client,
 };
 
@@ -37,7 +39,7 @@ use crate::{
         features_support_onion_messages, parse_blinded_paths, Bolt12InvoiceCreator, InvoicePayer,
         OfferCreator, PeerConnector,
     },
-    offers::handler::{CreateOfferParams, SendPaymentParams},
+    offers::handler::{CreateOfferParams, FeeEstimate, SendPaymentParams},
     onion_messenger::MessengerUtilities,
 };
 
@@ -56,10 +58,14 @@ pub(super) async fn create_invoice_request(
     expanded_key: ExpandedKey,
     msats: Option<u64>,
     payer_note: Option<String>,
+    payment_id: Option<PaymentId>,
 ) -> Result<(InvoiceRequest, PaymentId, u64, OffersContext), OfferError> {
     let validated_amount = validate_amount(offer.amount().as_ref(), msats).await?;
 
-    let payment_id = PaymentId(entropy_source.get_secure_random_bytes());
+    // Callers may choose their own payment id so that they're able to refer to the payment (for
+    // instance, to cancel it) before it completes.
+    let payment_id =
+        payment_id.unwrap_or_else(|| PaymentId(entropy_source.get_secure_random_bytes()));
 
     // We need to add some metadata to the invoice request to help with verification of the
     // invoice once returned from the offer maker. Once we get an invoice back, this metadata
@@ -105,24 +111,131 @@ pub(crate) async fn send_payment(
     mut payer: impl InvoicePayer + std::marker::Send + 'static,
     params: SendPaymentParams,
 ) -> Result<(), OfferError> {
+    let route = query_route(
+        &mut payer,
+        params.path,
+        params.cltv_expiry_delta,
+        params.fee_base_msat,
+        params.fee_ppm,
+        params.msats,
+        params.fee_limit,
+    )
+    .await?;
+
+    let _ = payer
+        .send_to_route(params.payment_hash, route)
+        .await
+        .map_err(OfferError::RouteFailure)?;
+
+    Ok(())
+}
+
+/// Queries LND for a route to the blinded payment path provided, which is the route we'd pay
+/// over. It's used both to send payments and to estimate what they'll cost.
+pub(crate) async fn query_route(
+    payer: &mut (impl InvoicePayer + std::marker::Send),
+    path: BlindedPaymentPath,
+    cltv_expiry_delta: u16,
+    fee_base_msat: u32,
+    fee_ppm: u32,
+    msats: u64,
+    fee_limit: Option<FeeLimit>,
+) -> Result<Route, OfferError> {
     let resp = payer
         .query_routes(
-            params.path,
-            params.cltv_expiry_delta,
-            params.fee_base_msat,
-            params.fee_ppm,
-            params.msats,
-            params.fee_limit,
+            path,
+            cltv_expiry_delta,
+            fee_base_msat,
+            fee_ppm,
+            msats,
+            fee_limit,
         )
         .await
         .map_err(OfferError::RouteFailure)?;
 
-    let _ = payer
-        .send_to_route(params.payment_hash, resp.routes[0].clone())
+    resp.routes
+        .into_iter()
+        .next()
+        .ok_or_else(|| OfferError::RouteFailure(Status::not_found("No routes found")))
+}
+
+// Queries for a route to each of the payment paths provided, and returns the paths we found a route
+// to along with their routes. Paths we can't find a route to are skipped, but if there's no route
+// to any of them we return the last error.
+async fn query_path_routes<'a>(
+    payer: &mut (impl InvoicePayer + std::marker::Send),
+    paths: &'a [BlindedPaymentPath],
+    msats: u64,
+    fee_limit: Option<FeeLimit>,
+) -> Result<Vec<(&'a BlindedPaymentPath, Route)>, OfferError> {
+    let mut routes = vec![];
+    let mut last_error = OfferError::RouteFailure(Status::not_found("No payment paths"));
+    for path in paths {
+        match query_route(
+            payer,
+            path.clone(),
+            path.payinfo.cltv_expiry_delta,
+            path.payinfo.fee_base_msat,
+            path.payinfo.fee_proportional_millionths,
+            msats,
+            fee_limit.clone(),
+        )
         .await
-        .map_err(OfferError::RouteFailure)?;
+        {
+            Ok(route) => routes.push((path, route)),
+            Err(e) => {
+                debug!("Couldn't find a route to payment path: {e}");
+                last_error = e;
+            }
+        }
+    }
+    if routes.is_empty() {
+        return Err(last_error);
+    }
+    Ok(routes)
+}
 
-    Ok(())
+/// Queries for a route to each of the payment paths provided, and returns the range of fees and
+/// time locks across the routes we found. Paths we can't find a route to are skipped, but if
+/// there's no route to any of them we return the last error.
+pub(crate) async fn estimate_route_fees(
+    payer: &mut (impl InvoicePayer + std::marker::Send),
+    paths: &[BlindedPaymentPath],
+    msats: u64,
+    fee_limit: Option<FeeLimit>,
+) -> Result<FeeEstimate, OfferError> {
+    let routes = query_path_routes(payer, paths, msats, fee_limit).await?;
+
+    let fees = routes.iter().map(|(_, route)| route.total_fees_msat as u64);
+    let time_locks = routes.iter().map(|(_, route)| route.total_time_lock);
+    Ok(FeeEstimate {
+        min_fee_msats: fees.clone().min().unwrap_or_default(),
+        max_fee_msats: fees.max().unwrap_or_default(),
+        min_total_time_lock: time_locks.clone().min().unwrap_or_default(),
+        max_total_time_lock: time_locks.max().unwrap_or_default(),
+        routable_paths: routes.len(),
+    })
+}
+
+/// Returns the payment path with the cheapest route out of the ones provided. This is the path we
+/// pay over, so a payment costs the minimum fee that estimate_route_fees reports.
+pub(crate) async fn cheapest_payment_path<'a>(
+    payer: &mut (impl InvoicePayer + std::marker::Send),
+    paths: &'a [BlindedPaymentPath],
+    msats: u64,
+    fee_limit: Option<FeeLimit>,
+) -> Result<&'a BlindedPaymentPath, OfferError> {
+    // There's nothing to choose between, and sending the payment will find the route anyway.
+    if let [path] = paths {
+        return Ok(path);
+    }
+
+    query_path_routes(payer, paths, msats, fee_limit)
+        .await?
+        .into_iter()
+        .min_by_key(|(_, route)| route.total_fees_msat)
+        .map(|(path, _)| path)
+        .ok_or_else(|| OfferError::RouteFailure(Status::not_found("No routes found")))
 }
 
 pub(super) async fn track_payment(
@@ -364,6 +477,42 @@ pub async fn send_invoice_request(
     Ok((contents, send_instructions))
 }
 
+/// Connects to the DNS resolver provided and prepares a DNSSEC query for it, with a reply path
+/// that it can send its proof back to us over.
+pub(crate) async fn send_dnssec_query(
+    resolver: PublicKey,
+    mut client: Client,
+    query: DNSSECQuery,
+    context: DNSResolverContext,
+    messenger_utils: &MessengerUtilities,
+) -> Result<(DNSResolverMessage, MessageSendInstructions), OfferError> {
+    connect_to_peer(client.lightning().clone(), resolver).await?;
+
+    let info = client
+        .lightning()
+        .get_info(GetInfoRequest {})
+        .await
+        .expect("failed to get info")
+        .into_inner();
+
+    let pubkey = PublicKey::from_str(&info.identity_pubkey).unwrap();
+    let reply_path = create_reply_path(
+        client.lightning().clone(),
+        pubkey,
+        MessageContext::DNSResolver(context),
+        messenger_utils,
+    )
+    .await?;
+
+    trace!("Sending DNSSEC query to {resolver} with reply path");
+    let send_instructions = MessageSendInstructions::WithSpecifiedReplyPath {
+        destination: Destination::Node(resolver),
+        reply_path,
+    };
+
+    Ok((DNSResolverMessage::DNSSECQuery(query), send_instructions))
+}
+
 pub(crate) async fn connect_to_peer(
     mut connector: impl PeerConnector,
     node_id: PublicKey,
@@ -513,6 +662,7 @@ mod tests {
             expanded_key,
             Some(amount),
             Some("".to_string()),
+            None,
         )
         .await;
         assert!(resp.is_ok())
@@ -964,6 +1114,180 @@ mod tests {
         assert!(send_payment(payer_mock, params).await.is_err());
     }
 
+    #[tokio::test]
+    async fn test_query_route() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| {
+                let route = Route {
+                    total_fees_msat: 150,
+                    total_time_lock: 900,
+                    ..Default::default()
+                };
+                Ok(QueryRoutesResponse {
+                    routes: vec![route],
+                    ..Default::default()
+                })
+            });
+
+        let route = query_route(
+            &mut payer_mock,
+            get_blinded_payment_path(),
+            200,
+            1,
+            0,
+            2000,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(route.total_fees_msat, 150);
+        assert_eq!(route.total_time_lock, 900);
+    }
+
+    #[tokio::test]
+    async fn test_query_route_no_routes() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| Ok(QueryRoutesResponse::default()));
+
+        let result = query_route(
+            &mut payer_mock,
+            get_blinded_payment_path(),
+            200,
+            1,
+            0,
+            2000,
+            None,
+        )
+        .await;
+        assert!(matches!(result, Err(OfferError::RouteFailure(_))));
+    }
+
+    // Returns a route whose fee and time lock depend on the payment path's payinfo, so that each
+    // path gets a different route.
+    fn route_for_path(fee_base_msat: u32, cltv_expiry_delta: u16) -> QueryRoutesResponse {
+        QueryRoutesResponse {
+            routes: vec![Route {
+                total_fees_msat: fee_base_msat as i64 + 100,
+                total_time_lock: 800 + cltv_expiry_delta as u32,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn payment_path_with_fees(fee_base_msat: u32, cltv_expiry_delta: u16) -> BlindedPaymentPath {
+        let mut path = get_blinded_payment_path();
+        path.payinfo.fee_base_msat = fee_base_msat;
+        path.payinfo.cltv_expiry_delta = cltv_expiry_delta;
+        path
+    }
+
+    #[tokio::test]
+    async fn test_estimate_route_fees() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        payer_mock.expect_query_routes().returning(
+            |_, cltv_expiry_delta, fee_base_msat, _, _, _| {
+                Ok(route_for_path(fee_base_msat, cltv_expiry_delta))
+            },
+        );
+
+        let paths = vec![
+            payment_path_with_fees(50, 144),
+            payment_path_with_fees(10, 200),
+            payment_path_with_fees(30, 40),
+        ];
+        let estimate = estimate_route_fees(&mut payer_mock, &paths, 2000, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            estimate,
+            FeeEstimate {
+                min_fee_msats: 110,
+                max_fee_msats: 150,
+                min_total_time_lock: 840,
+                max_total_time_lock: 1000,
+                routable_paths: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_estimate_route_fees_unroutable_path() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        // We can't find a route to the cheapest path, so it's left out of the estimate.
+        payer_mock.expect_query_routes().returning(
+            |_, cltv_expiry_delta, fee_base_msat, _, _, _| {
+                if fee_base_msat == 10 {
+                    return Err(Status::not_found("no route"));
+                }
+                Ok(route_for_path(fee_base_msat, cltv_expiry_delta))
+            },
+        );
+
+        let paths = vec![
+            payment_path_with_fees(50, 144),
+            payment_path_with_fees(10, 200),
+        ];
+        let estimate = estimate_route_fees(&mut payer_mock, &paths, 2000, None)
+            .await
+            .unwrap();
+        assert_eq!(estimate.min_fee_msats, 150);
+        assert_eq!(estimate.max_fee_msats, 150);
+        assert_eq!(estimate.routable_paths, 1);
+    }
+
+    #[tokio::test]
+    async fn test_cheapest_payment_path() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        // We can't find a route to the path with the lowest fee rate, so it's skipped.
+        payer_mock.expect_query_routes().returning(
+            |_, cltv_expiry_delta, fee_base_msat, _, _, _| {
+                if fee_base_msat == 5 {
+                    return Err(Status::not_found("no route"));
+                }
+                Ok(route_for_path(fee_base_msat, cltv_expiry_delta))
+            },
+        );
+
+        let paths = vec![
+            payment_path_with_fees(50, 144),
+            payment_path_with_fees(5, 40),
+            payment_path_with_fees(10, 200),
+            payment_path_with_fees(30, 40),
+        ];
+        let path = cheapest_payment_path(&mut payer_mock, &paths, 2000, None)
+            .await
+            .unwrap();
+        assert_eq!(path, &paths[2]);
+
+        let estimate = estimate_route_fees(&mut payer_mock, &paths, 2000, None)
+            .await
+            .unwrap();
+        assert_eq!(estimate.min_fee_msats, 110);
+    }
+
+    #[tokio::test]
+    async fn test_estimate_route_fees_no_routes() {
+        let mut payer_mock = MockTestInvoicePayer::new();
+
+        payer_mock
+            .expect_query_routes()
+            .returning(|_, _, _, _, _, _| Err(Status::not_found("no route")));
+
+        let paths = vec![payment_path_with_fees(50, 144)];
+        let result = estimate_route_fees(&mut payer_mock, &paths, 2000, None).await;
+        assert!(matches!(result, Err(OfferError::RouteFailure(_))));
+    }
+
     #[tokio::test]
     // Test that a new key is created with each call to create_invoice_request. Transient keys
     // improve privacy and we also need them to successfully make multiple payments to the same CLN
@@ -985,6 +1309,7 @@ mod tests {
             expanded_key,
             Some(amount),
             Some("".to_string()),
+            None,
         )
         .await;
         let resp_2 = create_invoice_request(
@@ -994,6 +1319,7 @@ mod tests {
             expanded_key,
             Some(amount),
             Some("".to_string()),
+            None,
         )
         .await;
         assert_ne!(
@@ -1254,6 +1580,7 @@ mod tests {
             expanded_key,
             Some(amount),
             None,
+            None,
         )
         .await
         .unwrap();
@@ -1,5 +1,6 @@
 use std::{error::Error, fmt::Display};
 
+use bitcoin::Network;
 use lightning::{
     ln::channelmanager::PaymentId,
     offers::{merkle::SignError, parse::Bolt12SemanticError},
@@ -10,10 +11,14 @@ mod client_impls;
 pub mod handler;
 mod lnd_requests;
 mod parse;
+mod proof;
+mod validation;
 
 pub(crate) use lnd_requests::connect_to_peer;
 pub use lnd_requests::create_reply_path;
 pub use parse::{decode, get_destination, validate_amount};
+pub use proof::{EncodedPaymentProof, PaymentProof};
+pub use validation::validate_invoice;
 
 #[derive(Debug)]
 /// OfferError is an error that occurs during the process of paying an offer.
@@ -60,6 +65,35 @@ pub enum OfferError {
     DecodePaymentRequestFailure(Status),
     /// Failed to parse payment hash.
     ParsePaymentHashFailure(String),
+    /// The payment was cancelled before it was dispatched.
+    PaymentCancelled(PaymentId),
+    /// The payment was abandoned because the offer creator responded with an invoice error.
+    PaymentAbandoned(PaymentId),
+    /// No in-flight payment exists with this id.
+    PaymentNotFound(PaymentId),
+    /// The payment has already been dispatched, so it can no longer be cancelled.
+    PaymentAlreadyDispatched(PaymentId),
+    /// The invoice expired at the time provided, in seconds since the unix epoch.
+    InvoiceExpired(u64),
+    /// The invoice is for a different chain than the network we're running on.
+    InvoiceChainMismatch(Network),
+    /// The invoice amount doesn't match the amount we intend to pay.
+    InvoiceAmountMismatch {
+        invoice_msats: u64,
+        requested_msats: u64,
+    },
+    /// The invoice requires features that we don't support.
+    UnsupportedInvoiceFeatures(String),
+    /// The invoice we received doesn't match the offer or the invoice request we sent.
+    InvoiceMismatch(String),
+    /// The payment proof couldn't be decoded, or doesn't prove that the offer was paid.
+    InvalidPaymentProof(String),
+    /// No DNS resolvers are configured, so we can't resolve human-readable names.
+    NoDnsResolvers,
+    /// We couldn't resolve a human-readable name to an offer.
+    ResolveNameFailure(String),
+    /// None of our DNS resolvers sent back a proof before the timeout, in seconds.
+    ResolveNameTimeout(u32),
 }
 
 impl Display for OfferError {
@@ -103,6 +137,48 @@ impl Display for OfferError {
             OfferError::ParsePaymentHashFailure(e) => {
                 write!(f, "Could not parse payment hash: {e:?}")
             }
+            OfferError::PaymentCancelled(id) => write!(f, "Payment {id} was cancelled"),
+            OfferError::PaymentAbandoned(id) => {
+                write!(
+                    f,
+                    "Payment {id} was abandoned after receiving an invoice error"
+                )
+            }
+            OfferError::PaymentNotFound(id) => write!(f, "No active payment with id {id}"),
+            OfferError::PaymentAlreadyDispatched(id) => {
+                write!(
+                    f,
+                    "Payment {id} has already been dispatched and can't be cancelled"
+                )
+            }
+            OfferError::InvoiceExpired(expires_at) => {
+                write!(f, "Invoice expired at {expires_at}")
+            }
+            OfferError::InvoiceChainMismatch(network) => {
+                write!(f, "Invoice is not for the {network} network")
+            }
+            OfferError::InvoiceAmountMismatch {
+                invoice_msats,
+                requested_msats,
+            } => write!(
+                f,
+                "Invoice amount {invoice_msats} msats doesn't match requested amount {requested_msats} msats"
+            ),
+            OfferError::UnsupportedInvoiceFeatures(e) => {
+                write!(f, "Unsupported invoice features: {e}")
+            }
+            OfferError::InvoiceMismatch(e) => {
+                write!(f, "Invoice doesn't match our invoice request: {e}")
+            }
+            OfferError::InvalidPaymentProof(e) => write!(f, "Invalid payment proof: {e}"),
+            OfferError::NoDnsResolvers => write!(
+                f,
+                "No DNS resolvers are configured to resolve human-readable names"
+            ),
+            OfferError::ResolveNameFailure(e) => write!(f, "Could not resolve name: {e}"),
+            OfferError::ResolveNameTimeout(e) => {
+                write!(f, "Did not receive a DNSSEC proof in {e} seconds")
+            }
         }
     }
 }
@@ -0,0 +1,177 @@
+use super::validation::check_invoice_matches_request;
+use super::OfferError;
+use bitcoin::hashes::{sha256, Hash};
+use lightning::offers::invoice::Bolt12Invoice;
+use lightning::offers::invoice_request::InvoiceRequest;
+use lightning::offers::offer::Offer;
+use lightning::types::payment::PaymentPreimage;
+use lightning::util::ser::Writeable;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// PaymentProof shows that an offer was paid. It bundles the offer, the invoice request the payer
+/// signed, the invoice the recipient signed in response and the preimage that the recipient only
+/// releases once it has been paid.
+#[derive(Clone, Debug)]
+pub struct PaymentProof {
+    pub offer: Offer,
+    pub invoice_request: InvoiceRequest,
+    pub invoice: Bolt12Invoice,
+    pub payment_preimage: PaymentPreimage,
+}
+
+/// EncodedPaymentProof is a PaymentProof in the form we store and share it in. The offer is
+/// bech32-encoded, and the rest is hex-encoded.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncodedPaymentProof {
+    pub offer: String,
+    pub invoice_request: String,
+    pub invoice: String,
+    pub payment_preimage: String,
+}
+
+impl PaymentProof {
+    /// Decodes a proof. The invoice request and invoice signatures are checked as they're
+    /// parsed, so a proof that decodes successfully is signed by the payer and the recipient.
+    pub fn decode(proof: &EncodedPaymentProof) -> Result<Self, OfferError> {
+        let offer = Offer::from_str(&proof.offer)
+            .map_err(|e| OfferError::InvalidPaymentProof(format!("invalid offer: {e:?}")))?;
+
+        let bytes = decode_hex("invoice request", &proof.invoice_request)?;
+        let invoice_request = InvoiceRequest::try_from(bytes).map_err(|e| {
+            OfferError::InvalidPaymentProof(format!("invalid invoice request: {e:?}"))
+        })?;
+
+        let bytes = decode_hex("invoice", &proof.invoice)?;
+        let invoice = Bolt12Invoice::try_from(bytes)
+            .map_err(|e| OfferError::InvalidPaymentProof(format!("invalid invoice: {e:?}")))?;
+
+        let payment_preimage = decode_hex("payment preimage", &proof.payment_preimage)?
+            .try_into()
+            .map(PaymentPreimage)
+            .map_err(|_| {
+                OfferError::InvalidPaymentProof("payment preimage must be 32 bytes".to_string())
+            })?;
+
+        Ok(PaymentProof {
+            offer,
+            invoice_request,
+            invoice,
+            payment_preimage,
+        })
+    }
+
+    pub fn encode(&self) -> EncodedPaymentProof {
+        EncodedPaymentProof {
+            offer: self.offer.to_string(),
+            invoice_request: hex::encode(self.invoice_request.encode()),
+            invoice: hex::encode(self.invoice.encode()),
+            payment_preimage: hex::encode(self.payment_preimage.0),
+        }
+    }
+
+    /// Checks that the invoice request is for the offer, that the invoice responds to the invoice
+    /// request and is signed by the offer's issuer, and that the preimage is the one the invoice
+    /// was paid for.
+    pub fn verify(&self) -> Result<(), OfferError> {
+        let offer = &self.offer;
+        let invoice_request = &self.invoice_request;
+        let request_matches_offer = invoice_request.chains() == offer.chains()
+            && invoice_request.metadata() == offer.metadata()
+            && invoice_request.amount() == offer.amount()
+            && invoice_request.description() == offer.description()
+            && invoice_request.offer_features() == offer.offer_features()
+            && invoice_request.absolute_expiry() == offer.absolute_expiry()
+            && invoice_request.issuer() == offer.issuer()
+            && invoice_request.paths() == offer.paths()
+            && invoice_request.supported_quantity() == offer.supported_quantity()
+            && invoice_request.issuer_signing_pubkey() == offer.issuer_signing_pubkey();
+        if !request_matches_offer {
+            return Err(OfferError::InvalidPaymentProof(
+                "invoice request isn't for the offer".to_string(),
+            ));
+        }
+
+        check_invoice_matches_request(&self.invoice, invoice_request)?;
+
+        let payment_hash = sha256::Hash::hash(&self.payment_preimage.0).to_byte_array();
+        if payment_hash != self.invoice.payment_hash().0 {
+            return Err(OfferError::InvalidPaymentProof(
+                "payment preimage doesn't match the invoice's payment hash".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn decode_hex(name: &str, value: &str) -> Result<Vec<u8>, OfferError> {
+    hex::decode(value)
+        .map_err(|e| OfferError::InvalidPaymentProof(format!("invalid {name} hex: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_utils::{
+        build_invoice, build_invoice_request, build_offer, PAYMENT_PREIMAGE,
+    };
+
+    fn build_proof() -> PaymentProof {
+        let offer = build_offer(20_000);
+        let invoice_request = build_invoice_request(&offer, 20_000);
+        let invoice = build_invoice(&invoice_request);
+        PaymentProof {
+            offer,
+            invoice_request,
+            invoice,
+            payment_preimage: PAYMENT_PREIMAGE,
+        }
+    }
+
+    #[test]
+    fn test_verify_proof() {
+        let proof = build_proof();
+        assert!(proof.verify().is_ok());
+
+        // The proof should survive being encoded and decoded.
+        let decoded = PaymentProof::decode(&proof.encode()).unwrap();
+        assert!(decoded.verify().is_ok());
+        assert_eq!(decoded.encode(), proof.encode());
+    }
+
+    #[test]
+    fn test_verify_proof_wrong_preimage() {
+        let mut proof = build_proof();
+        proof.payment_preimage = PaymentPreimage([3; 32]);
+
+        assert!(matches!(
+            proof.verify(),
+            Err(OfferError::InvalidPaymentProof(_))
+        ));
+    }
+
+    #[test]
+    fn test_verify_proof_wrong_offer() {
+        let mut proof = build_proof();
+        proof.offer = build_offer(30_000);
+
+        assert!(matches!(
+            proof.verify(),
+            Err(OfferError::InvalidPaymentProof(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_tampered_invoice() {
+        let mut encoded = build_proof().encode();
+
+        // Changing a byte in the middle of the invoice invalidates the recipient's signature.
+        let mut bytes = hex::decode(&encoded.invoice).unwrap();
+        let middle = bytes.len() / 2;
+        bytes[middle] ^= 1;
+        encoded.invoice = hex::encode(bytes);
+
+        assert!(PaymentProof::decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use super::OfferError;
+use bitcoin::constants::ChainHash;
+use bitcoin::Network;
+use lightning::offers::invoice::Bolt12Invoice;
+use lightning::offers::invoice_request::InvoiceRequest;
+use lightning::offers::offer::Amount;
+use lightning::types::features::Bolt12InvoiceFeatures;
+use std::time::{Duration, SystemTime};
+
+/// Checks that an invoice is one we're willing to pay the amount provided for, on the network we
+/// are running on. It should be called before paying any invoice. Invoices that require features
+/// we don't know are refused, while those that require basic_mpp are paid in a single part.
+pub fn validate_invoice(
+    invoice: &Bolt12Invoice,
+    network: Network,
+    amount_msats: u64,
+) -> Result<(), OfferError> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    validate_invoice_at(invoice, network, amount_msats, now)
+}
+
+/// Performs the checks in validate_invoice as of the time provided, which is a duration since
+/// the unix epoch.
+pub(crate) fn validate_invoice_at(
+    invoice: &Bolt12Invoice,
+    network: Network,
+    amount_msats: u64,
+    now: Duration,
+) -> Result<(), OfferError> {
+    let expires_at = invoice
+        .created_at()
+        .saturating_add(invoice.relative_expiry());
+    if now >= expires_at {
+        return Err(OfferError::InvoiceExpired(expires_at.as_secs()));
+    }
+
+    if invoice.chain() != ChainHash::using_genesis_block(network) {
+        return Err(OfferError::InvoiceChainMismatch(network));
+    }
+
+    // The invoice amount is exactly what the recipient expects to receive, so we shouldn't pay
+    // anything else.
+    if invoice.amount_msats() != amount_msats {
+        return Err(OfferError::InvoiceAmountMismatch {
+            invoice_msats: invoice.amount_msats(),
+            requested_msats: amount_msats,
+        });
+    }
+
+    check_invoice_features(invoice.invoice_features())
+}
+
+// Checks that we support every feature an invoice requires. Invoices that require basic_mpp are
+// fine: we pay them in a single part over one blinded path, which is a multi-part payment with
+// one part, since LND sets the total amount for the recipient along with it.
+fn check_invoice_features(features: &Bolt12InvoiceFeatures) -> Result<(), OfferError> {
+    if features.requires_unknown_bits() {
+        return Err(OfferError::UnsupportedInvoiceFeatures(
+            "invoice requires unknown features".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Checks that an invoice we received is consistent with the invoice request we sent, and with
+/// the offer that request was for. An invoice that passes verify_using_payer_data is known to be
+/// a response to our request, but the payee still chooses its contents.
+pub(crate) fn check_invoice_matches_request(
+    invoice: &Bolt12Invoice,
+    invoice_request: &InvoiceRequest,
+) -> Result<(), OfferError> {
+    let requested_msats = match invoice_request.amount_msats() {
+        Some(amount) => amount,
+        None => match invoice_request.amount() {
+            Some(Amount::Bitcoin { amount_msats }) => amount_msats,
+            _ => {
+                return Err(OfferError::InvoiceMismatch(
+                    "invoice request has no amount".to_string(),
+                ))
+            }
+        },
+    };
+    if invoice.amount_msats() != requested_msats {
+        return Err(OfferError::InvoiceMismatch(format!(
+            "invoice amount {} msats doesn't match the {requested_msats} msats requested",
+            invoice.amount_msats()
+        )));
+    }
+
+    if invoice.chain() != invoice_request.chain() {
+        return Err(OfferError::InvoiceMismatch(
+            "invoice chain doesn't match the chain requested".to_string(),
+        ));
+    }
+
+    if invoice.quantity() != invoice_request.quantity() {
+        return Err(OfferError::InvoiceMismatch(
+            "invoice quantity doesn't match the quantity requested".to_string(),
+        ));
+    }
+
+    if invoice.payer_signing_pubkey() != invoice_request.payer_signing_pubkey() {
+        return Err(OfferError::InvoiceMismatch(
+            "invoice payer key doesn't match the invoice request".to_string(),
+        ));
+    }
+
+    // The invoice must be signed by the offer's issuer. If the offer doesn't set a signing
+    // pubkey, the recipient signs with the final blinded node id of one of the offer's paths.
+    let signing_pubkey = invoice.signing_pubkey();
+    let signer_matches = match invoice_request.issuer_signing_pubkey() {
+        Some(issuer_signing_pubkey) => signing_pubkey == issuer_signing_pubkey,
+        None => invoice_request.paths().iter().any(|path| {
+            path.blinded_hops()
+                .last()
+                .is_some_and(|hop| hop.blinded_node_id == signing_pubkey)
+        }),
+    };
+    if !signer_matches {
+        return Err(OfferError::InvoiceMismatch(format!(
+            "invoice signing pubkey {signing_pubkey} doesn't belong to the offer issuer"
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_utils::{
+        build_invoice, build_invoice_at, build_invoice_request, build_offer, pubkey,
+    };
+    use lightning::offers::offer::OfferBuilder;
+
+    const CREATED_AT: Duration = Duration::from_secs(1_700_000_000);
+
+    #[test]
+    fn test_validate_invoice() {
+        let offer = build_offer(20_000);
+        let invoice = build_invoice_at(&build_invoice_request(&offer, 20_000), CREATED_AT);
+
+        assert!(validate_invoice_at(&invoice, Network::Regtest, 20_000, CREATED_AT).is_ok());
+    }
+
+    #[test]
+    fn test_check_invoice_features() {
+        assert!(check_invoice_features(&Bolt12InvoiceFeatures::empty()).is_ok());
+
+        // Payees that require multi-part payments get paid in a single part.
+        let mut features = Bolt12InvoiceFeatures::empty();
+        features.set_basic_mpp_required();
+        assert!(check_invoice_features(&features).is_ok());
+
+        // An unassigned even bit (100) is one we don't know how to handle.
+        let mut le_flags = vec![0; 13];
+        le_flags[12] |= 1 << 4;
+        assert!(matches!(
+            check_invoice_features(&Bolt12InvoiceFeatures::from_le_bytes(le_flags)),
+            Err(OfferError::UnsupportedInvoiceFeatures(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_invoice_expired() {
+        let offer = build_offer(20_000);
+        let invoice = build_invoice_at(&build_invoice_request(&offer, 20_000), CREATED_AT);
+
+        let now = CREATED_AT + invoice.relative_expiry();
+        assert!(matches!(
+            validate_invoice_at(&invoice, Network::Regtest, 20_000, now),
+            Err(OfferError::InvoiceExpired(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_invoice_wrong_chain() {
+        let offer = build_offer(20_000);
+        let invoice = build_invoice_at(&build_invoice_request(&offer, 20_000), CREATED_AT);
+
+        assert!(matches!(
+            validate_invoice_at(&invoice, Network::Bitcoin, 20_000, CREATED_AT),
+            Err(OfferError::InvoiceChainMismatch(Network::Bitcoin))
+        ));
+    }
+
+    #[test]
+    fn test_validate_invoice_amount_mismatch() {
+        let offer = build_offer(20_000);
+        let invoice = build_invoice_at(&build_invoice_request(&offer, 30_000), CREATED_AT);
+
+        assert!(matches!(
+            validate_invoice_at(&invoice, Network::Regtest, 20_000, CREATED_AT),
+            Err(OfferError::InvoiceAmountMismatch {
+                invoice_msats: 30_000,
+                requested_msats: 20_000
+            })
+        ));
+    }
+
+    #[test]
+    fn test_invoice_matches_request() {
+        let offer = build_offer(20_000);
+        let invoice_request = build_invoice_request(&offer, 20_000);
+        let invoice = build_invoice(&invoice_request);
+
+        assert!(check_invoice_matches_request(&invoice, &invoice_request).is_ok());
+    }
+
+    #[test]
+    fn test_invoice_amount_higher_than_requested() {
+        let offer = build_offer(20_000);
+        let invoice_request = build_invoice_request(&offer, 20_000);
+        let invoice = build_invoice(&build_invoice_request(&offer, 50_000));
+
+        assert!(matches!(
+            check_invoice_matches_request(&invoice, &invoice_request),
+            Err(OfferError::InvoiceMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_invoice_signed_by_other_issuer() {
+        let invoice = build_invoice(&build_invoice_request(&build_offer(20_000), 20_000));
+
+        // An invoice request for the same amount, but for an offer from someone else.
+        let other_offer = OfferBuilder::new(pubkey(5))
+            .chain(Network::Regtest)
+            .amount_msats(20_000)
+            .build()
+            .unwrap();
+        let invoice_request = build_invoice_request(&other_offer, 20_000);
+
+        assert!(matches!(
+            check_invoice_matches_request(&invoice, &invoice_request),
+            Err(OfferError::InvoiceMismatch(_))
+        ));
+    }
+}
@@ -35,7 +35,7 @@ use std::marker::Copy;
 use std::str::FromStr;
 use std::sync::Arc;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Notify;
+use tokio::sync::{watch, Notify};
 use tokio::time::{sleep, timeout, Duration, Interval};
 use tokio::{select, time};
 use tonic_lnd::lnrpc::{ChanInfoRequest, ListPeersRequest, ListPeersResponse};
@@ -327,6 +327,17 @@ impl LndkOnionMessenger {
             }
         });
 
+        // Pass on changes to our rate limits, which may be updated while we're running. If they
+        // were changed before we started, the latest ones replace the ones we were started with.
+        let mut rate_limits = self.rate_limits.subscribe();
+        let rate_limiter_cfg = rate_limits.borrow_and_update().unwrap_or(rate_limiter_cfg);
+        let rate_limit_sender = sender.clone();
+        let rate_limit_listener = signals.listener.clone();
+        set.spawn(async move {
+            produce_rate_limit_events(rate_limits, rate_limit_sender, rate_limit_listener).await;
+            debug!("Rate limit events producer exited.");
+        });
+
         // Consume events is our main controlling loop, so we run it inline here. We use a RefCell
         // in onion_messenger to allow interior mutability (see LndNodeSigner) so this
         // function can't safely be passed off to another thread. This function is expected
@@ -380,6 +391,31 @@ impl LndkOnionMessenger {
     }
 }
 
+/// produce_rate_limit_events sends an event whenever our rate limits are changed, until shutdown
+/// is signaled.
+async fn produce_rate_limit_events(
+    mut rate_limits: watch::Receiver<Option<RateLimiterCfg>>,
+    events: Sender<MessengerEvents>,
+    listener: Listener,
+) {
+    loop {
+        select! {
+            _ = listener.clone() => return,
+            changed = rate_limits.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+                let cfg = *rate_limits.borrow_and_update();
+                if let Some(cfg) = cfg {
+                    if events.send(MessengerEvents::UpdateRateLimit(cfg)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
 async fn get_current_peers(
     client: &mut tonic_lnd::LightningClient,
 ) -> Result<HashMap<PublicKey, bool>, ()> {
@@ -828,6 +864,7 @@ enum MessengerEvents {
     IncomingMessage(PublicKey, OnionMessage),
     SendOutgoing,
     ProducerExit(ConsumerError),
+    UpdateRateLimit(RateLimiterCfg),
 }
 
 impl fmt::Display for MessengerEvents {
@@ -847,6 +884,11 @@ impl fmt::Display for MessengerEvents {
                 write!(f, "messenger event: poll for new outgoing onion messages")
             }
             MessengerEvents::ProducerExit(s) => write!(f, "messenger event: {s} exited"),
+            MessengerEvents::UpdateRateLimit(cfg) => write!(
+                f,
+                "messenger event: rate limit changed to {} calls per {:?}",
+                cfg.call_count, cfg.call_period_secs
+            ),
         }
     }
 }
@@ -930,6 +972,9 @@ async fn consume_messenger_events(
                 error!("ProducerExit {e}");
                 continue;
             }
+            MessengerEvents::UpdateRateLimit(cfg) => {
+                rate_limiter.set_limits(cfg.call_count, cfg.call_period_secs);
+            }
         }
     }
 
@@ -1168,6 +1213,7 @@ mod tests {
             fn peer_connected(&mut self, peer_key: PublicKey);
             fn peer_disconnected(&mut self, peer_key: PublicKey);
             fn peers(&self) -> Vec<PublicKey>;
+            fn set_limits(&mut self, call_count: u8, call_frequency: Duration);
             fn query_peer(&mut self, peer_key: PublicKey) -> bool;
         }
     }
@@ -0,0 +1,348 @@
+use crate::clock::{Clock, TokioClock};
+use bitcoin::secp256k1::PublicKey;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// PolicyCfg holds the spending controls that the server enforces before making any payment.
+/// Every control is optional, and an empty config allows all payments.
+#[derive(Clone, Debug, Default)]
+pub struct PolicyCfg {
+    /// The maximum amount that a single payment may send, in millisatoshis.
+    pub max_payment_msats: Option<u64>,
+    /// The maximum amount that may be sent over any rolling hour, in millisatoshis.
+    pub hourly_budget_msats: Option<u64>,
+    /// The maximum amount that may be sent over any rolling day, in millisatoshis.
+    pub daily_budget_msats: Option<u64>,
+    /// A fixed fee limit in millisatoshis, used when a request doesn't provide one.
+    pub default_fee_limit: Option<u32>,
+    /// A percentage fee limit, used when a request doesn't provide one. Only one of
+    /// default_fee_limit and default_fee_limit_percent should be set.
+    pub default_fee_limit_percent: Option<u32>,
+    /// If not empty, only offers issued by these pubkeys may be paid.
+    pub allowed_issuers: Vec<PublicKey>,
+    /// Offers issued by these pubkeys are never paid.
+    pub denied_issuers: Vec<PublicKey>,
+}
+
+/// Parses a comma-separated list of hex-encoded pubkeys, as provided in lndk's config.
+pub fn parse_pubkey_list(list: &str) -> Result<Vec<PublicKey>, bitcoin::secp256k1::Error> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(PublicKey::from_str)
+        .collect()
+}
+
+/// An error that occurs when a payment doesn't comply with the configured policy.
+#[derive(Debug, PartialEq)]
+pub enum PolicyViolation {
+    /// The payment amount is over the maximum allowed for a single payment.
+    AmountTooLarge { amount_msats: u64, max_msats: u64 },
+    /// The payment would take us over one of our rolling budgets.
+    BudgetExceeded {
+        window: &'static str,
+        spent_msats: u64,
+        budget_msats: u64,
+    },
+    /// An allow list is configured and the issuer isn't on it.
+    IssuerNotAllowed(Option<PublicKey>),
+    /// The issuer is on the deny list.
+    IssuerDenied(PublicKey),
+}
+
+impl Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyViolation::AmountTooLarge {
+                amount_msats,
+                max_msats,
+            } => write!(
+                f,
+                "Payment of {amount_msats} msats is over the maximum of {max_msats} msats per payment"
+            ),
+            PolicyViolation::BudgetExceeded {
+                window,
+                spent_msats,
+                budget_msats,
+            } => write!(
+                f,
+                "Payment would exceed the {window} budget of {budget_msats} msats, {spent_msats} msats already spent"
+            ),
+            PolicyViolation::IssuerNotAllowed(Some(issuer)) => {
+                write!(f, "Issuer {issuer} is not on the allow list")
+            }
+            PolicyViolation::IssuerNotAllowed(None) => write!(
+                f,
+                "Issuer doesn't provide a signing pubkey and an allow list is configured"
+            ),
+            PolicyViolation::IssuerDenied(issuer) => {
+                write!(f, "Issuer {issuer} is on the deny list")
+            }
+        }
+    }
+}
+
+impl Error for PolicyViolation {}
+
+/// Spend is a payment that counts towards our rolling budgets.
+struct Spend {
+    id: u64,
+    time: Instant,
+    amount_msats: u64,
+}
+
+/// SpendReservation identifies an amount reserved against our budgets, which should be released
+/// if the payment fails.
+#[derive(Debug, PartialEq)]
+pub struct SpendReservation(u64);
+
+/// PaymentPolicy enforces a PolicyCfg. Budgets are tracked in memory over rolling windows, so
+/// they reset when lndk restarts.
+pub struct PaymentPolicy {
+    cfg: PolicyCfg,
+    clock: Box<dyn Clock + Send + Sync>,
+    spends: Mutex<(u64, VecDeque<Spend>)>,
+}
+
+impl PaymentPolicy {
+    pub fn new(cfg: PolicyCfg) -> Self {
+        Self::with_clock(cfg, TokioClock::new())
+    }
+
+    pub(crate) fn with_clock(cfg: PolicyCfg, clock: impl Clock + Send + Sync + 'static) -> Self {
+        PaymentPolicy {
+            cfg,
+            clock: Box::new(clock),
+            spends: Mutex::new((0, VecDeque::new())),
+        }
+    }
+
+    /// Checks the issuer of an offer or invoice against our allow and deny lists.
+    pub fn check_issuer(&self, issuer: Option<PublicKey>) -> Result<(), PolicyViolation> {
+        if let Some(issuer) = issuer {
+            if self.cfg.denied_issuers.contains(&issuer) {
+                return Err(PolicyViolation::IssuerDenied(issuer));
+            }
+        }
+
+        if self.cfg.allowed_issuers.is_empty() {
+            return Ok(());
+        }
+        match issuer {
+            Some(issuer) if self.cfg.allowed_issuers.contains(&issuer) => Ok(()),
+            _ => Err(PolicyViolation::IssuerNotAllowed(issuer)),
+        }
+    }
+
+    /// Checks that a payment of the amount provided is allowed and, if so, reserves it against
+    /// our budgets. The reservation should be released if the payment doesn't go through.
+    pub fn reserve(&self, amount_msats: u64) -> Result<SpendReservation, PolicyViolation> {
+        if let Some(max_msats) = self.cfg.max_payment_msats {
+            if amount_msats > max_msats {
+                return Err(PolicyViolation::AmountTooLarge {
+                    amount_msats,
+                    max_msats,
+                });
+            }
+        }
+
+        let now = self.clock.now();
+        let mut guard = self.spends.lock().unwrap();
+        let (next_id, spends) = &mut *guard;
+
+        // We never need to look further back than a day, so we can forget older spends.
+        while let Some(spend) = spends.front() {
+            if now.duration_since(spend.time) < DAY {
+                break;
+            }
+            spends.pop_front();
+        }
+
+        for (window, period, budget) in [
+            ("hourly", HOUR, self.cfg.hourly_budget_msats),
+            ("daily", DAY, self.cfg.daily_budget_msats),
+        ] {
+            let budget_msats = match budget {
+                Some(budget) => budget,
+                None => continue,
+            };
+            let spent_msats: u64 = spends
+                .iter()
+                .filter(|spend| now.duration_since(spend.time) < period)
+                .map(|spend| spend.amount_msats)
+                .sum();
+            if spent_msats.saturating_add(amount_msats) > budget_msats {
+                return Err(PolicyViolation::BudgetExceeded {
+                    window,
+                    spent_msats,
+                    budget_msats,
+                });
+            }
+        }
+
+        let id = *next_id;
+        *next_id += 1;
+        spends.push_back(Spend {
+            id,
+            time: now,
+            amount_msats,
+        });
+
+        Ok(SpendReservation(id))
+    }
+
+    /// Releases a reservation made for a payment that failed, so that it no longer counts
+    /// towards our budgets.
+    pub fn release(&self, reservation: SpendReservation) {
+        let mut guard = self.spends.lock().unwrap();
+        guard.1.retain(|spend| spend.id != reservation.0);
+    }
+
+    /// Returns the fee limit to use for a payment, falling back to our defaults if the request
+    /// didn't set one.
+    pub fn fee_limit(
+        &self,
+        fee_limit: Option<u32>,
+        fee_limit_percent: Option<u32>,
+    ) -> (Option<u32>, Option<u32>) {
+        if fee_limit.is_some() || fee_limit_percent.is_some() {
+            return (fee_limit, fee_limit_percent);
+        }
+        (
+            self.cfg.default_fee_limit,
+            self.cfg.default_fee_limit_percent,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_utils::pubkey;
+
+    fn test_policy(cfg: PolicyCfg) -> PaymentPolicy {
+        PaymentPolicy::with_clock(cfg, TokioClock::new())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_payment() {
+        let policy = test_policy(PolicyCfg {
+            max_payment_msats: Some(1_000),
+            ..Default::default()
+        });
+
+        assert!(policy.reserve(1_000).is_ok());
+        assert_eq!(
+            policy.reserve(1_001),
+            Err(PolicyViolation::AmountTooLarge {
+                amount_msats: 1_001,
+                max_msats: 1_000
+            })
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rolling_budgets() {
+        let policy = test_policy(PolicyCfg {
+            hourly_budget_msats: Some(1_000),
+            daily_budget_msats: Some(1_500),
+            ..Default::default()
+        });
+
+        assert!(policy.reserve(600).is_ok());
+        assert!(policy.reserve(400).is_ok());
+        assert!(matches!(
+            policy.reserve(1),
+            Err(PolicyViolation::BudgetExceeded {
+                window: "hourly",
+                ..
+            })
+        ));
+
+        // Once the hour has passed, we're limited by what's left of the daily budget.
+        tokio::time::advance(HOUR).await;
+        assert!(policy.reserve(500).is_ok());
+        assert!(matches!(
+            policy.reserve(1),
+            Err(PolicyViolation::BudgetExceeded {
+                window: "daily",
+                ..
+            })
+        ));
+
+        // After a day, everything is available again.
+        tokio::time::advance(DAY).await;
+        assert!(policy.reserve(1_000).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_release_reservation() {
+        let policy = test_policy(PolicyCfg {
+            hourly_budget_msats: Some(1_000),
+            ..Default::default()
+        });
+
+        let reservation = policy.reserve(1_000).unwrap();
+        assert!(policy.reserve(1).is_err());
+
+        policy.release(reservation);
+        assert!(policy.reserve(1_000).is_ok());
+    }
+
+    #[test]
+    fn test_issuer_lists() {
+        let policy = test_policy(PolicyCfg {
+            denied_issuers: vec![pubkey(1)],
+            ..Default::default()
+        });
+        assert_eq!(
+            policy.check_issuer(Some(pubkey(1))),
+            Err(PolicyViolation::IssuerDenied(pubkey(1)))
+        );
+        assert!(policy.check_issuer(Some(pubkey(2))).is_ok());
+        assert!(policy.check_issuer(None).is_ok());
+
+        let policy = test_policy(PolicyCfg {
+            allowed_issuers: vec![pubkey(2)],
+            ..Default::default()
+        });
+        assert!(policy.check_issuer(Some(pubkey(2))).is_ok());
+        assert_eq!(
+            policy.check_issuer(Some(pubkey(3))),
+            Err(PolicyViolation::IssuerNotAllowed(Some(pubkey(3))))
+        );
+        assert_eq!(
+            policy.check_issuer(None),
+            Err(PolicyViolation::IssuerNotAllowed(None))
+        );
+    }
+
+    #[test]
+    fn test_default_fee_limit() {
+        let policy = test_policy(PolicyCfg {
+            default_fee_limit_percent: Some(2),
+            ..Default::default()
+        });
+
+        assert_eq!(policy.fee_limit(None, None), (None, Some(2)));
+        assert_eq!(policy.fee_limit(Some(100), None), (Some(100), None));
+    }
+
+    #[test]
+    fn test_parse_pubkey_list() {
+        let list = format!("{}, {}", pubkey(1), pubkey(2));
+        assert_eq!(
+            parse_pubkey_list(&list).unwrap(),
+            vec![pubkey(1), pubkey(2)]
+        );
+        assert!(parse_pubkey_list("").unwrap().is_empty());
+        assert!(parse_pubkey_list("notakey").is_err());
+    }
+}
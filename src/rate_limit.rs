@@ -26,6 +26,7 @@ pub(crate) trait RateLimiter {
     fn peer_disconnected(&mut self, peer_key: PublicKey);
     fn peers(&self) -> Vec<PublicKey>;
     fn query_peer(&mut self, peer_key: PublicKey) -> bool;
+    fn set_limits(&mut self, call_count: u8, call_frequency: Duration);
 }
 
 /// TokenLimiter keeps track of our set of peers, and provides token bucket rate limiting on a
@@ -50,6 +51,7 @@ pub(crate) struct TokenLimiter<C: Clock> {
     last_update: Instant,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub(crate) struct RateLimiterCfg {
     pub(crate) call_count: u8,
     pub(crate) call_period_secs: Duration,
@@ -103,6 +105,18 @@ impl<C: Clock> TokenLimiter<C> {
         self.last_update = self.clock.now();
     }
 
+    /// has_remaining returns a boolean indicating whether a peer has any calls of the rate limited
+    /// operation remaining, without using one up.
+    pub(crate) fn has_remaining(&mut self, peer_key: PublicKey) -> bool {
+        if self.needs_update() {
+            self.update();
+        };
+
+        self.peer_map
+            .get(&peer_key)
+            .is_some_and(|v| v.remaining_calls > 0)
+    }
+
     /// hit returns a boolean indicating whether a peer should be permitted another call of the rate
     /// limited operation. It will return true if the peer is known and has remaining calls
     /// allowed (and decrement their call count), and false otherwise.
@@ -160,6 +174,17 @@ impl<C: Clock> RateLimiter for TokenLimiter<C> {
 
         self.hit(peer_key)
     }
+
+    /// set_limits changes the number of calls each peer is allowed per period, and the length of
+    /// the period. Peers keep the calls they have left in the current period, up to the new
+    /// call_count, so that lowering the limit takes effect straight away.
+    fn set_limits(&mut self, call_count: u8, call_frequency: Duration) {
+        self.call_count = call_count;
+        self.call_frequency = call_frequency;
+        for (_, v) in self.peer_map.iter_mut() {
+            v.remaining_calls = v.remaining_calls.min(call_count);
+        }
+    }
 }
 
 #[cfg(test)]
@@ -249,6 +274,26 @@ mod tests {
         assert!(!rate_limiter.query_peer(pk_1));
     }
 
+    #[tokio::test(start_paused = true)]
+    async fn test_set_limits() {
+        let pk_0 = pubkey(0);
+        let clock = TokioClock::new();
+        let mut rate_limiter =
+            TokenLimiter::new(vec![pk_0].into_iter(), TEST_COUNT, TEST_FREQUENCY, clock);
+
+        // Lowering the limit applies to the current period straight away.
+        rate_limiter.set_limits(1, TEST_FREQUENCY * 2);
+        assert!(rate_limiter.query_peer(pk_0));
+        assert!(!rate_limiter.query_peer(pk_0));
+
+        // The new period is used to decide when to refill.
+        tokio::time::advance(TEST_FREQUENCY).await;
+        assert!(!rate_limiter.query_peer(pk_0));
+        tokio::time::advance(TEST_FREQUENCY).await;
+        assert!(rate_limiter.query_peer(pk_0));
+        assert!(!rate_limiter.query_peer(pk_0));
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_query_peer_unknown_peer() {
         let pk_0 = pubkey(0);
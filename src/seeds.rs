@@ -0,0 +1,237 @@
+use crate::lnd::{build_seed_from_lnd_node, MessageSigner, SEED_KEY_INDEX};
+use crate::offers::handler::OfferHandler;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// SeedGeneration is one of the seeds we've derived from LND, identified by the key index that
+/// LND signed with to derive it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SeedGeneration {
+    pub key_index: i32,
+    /// When we rotated away from this seed, in seconds since the unix epoch. The current seed
+    /// hasn't been retired.
+    pub retired_at: Option<u64>,
+}
+
+/// An error that occurs while rotating our seed.
+#[derive(Debug)]
+pub enum SeedError {
+    /// LND couldn't sign the message that we derive the new seed from.
+    DeriveSeed(i32),
+    /// Another rotation finished while we were deriving the seed.
+    ConcurrentRotation,
+    /// We couldn't write the seed generations to disk.
+    Persist(std::io::Error),
+}
+
+impl Display for SeedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SeedError::DeriveSeed(index) => {
+                write!(f, "Could not derive seed with key index {index} from lnd")
+            }
+            SeedError::ConcurrentRotation => write!(f, "The seed was rotated concurrently"),
+            SeedError::Persist(e) => write!(f, "Error persisting seed generations: {e}"),
+        }
+    }
+}
+
+impl Error for SeedError {}
+
+/// SeedStore keeps track of the generations of our seed, so that offers created with an older
+/// seed keep working for a grace period after we rotate it. It only stores key indexes, never the
+/// seeds themselves, which we derive from LND again on startup.
+pub struct SeedStore {
+    path: PathBuf,
+    grace_period: Duration,
+    generations: Mutex<Vec<SeedGeneration>>,
+}
+
+impl SeedStore {
+    /// Opens the store at the path provided, starting out with the seed lndk has always used if
+    /// it doesn't exist. Generations whose grace period has passed are dropped.
+    pub fn open(path: &Path, grace_period: Duration) -> Result<Self, std::io::Error> {
+        let generations: Vec<SeedGeneration> = if path.exists() {
+            let contents = fs::read_to_string(path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            vec![SeedGeneration {
+                key_index: SEED_KEY_INDEX,
+                retired_at: None,
+            }]
+        };
+
+        let store = SeedStore {
+            path: path.to_path_buf(),
+            grace_period,
+            generations: Mutex::new(generations),
+        };
+        let mut generations = store.generations.lock().unwrap();
+        store.prune(&mut generations, now());
+        store.persist(&generations)?;
+        drop(generations);
+
+        Ok(store)
+    }
+
+    /// The generation that new offers and payments are created with.
+    pub fn current(&self) -> SeedGeneration {
+        self.generations.lock().unwrap().last().cloned().unwrap()
+    }
+
+    /// The generations we've rotated away from that are still within their grace period.
+    pub fn retired(&self) -> Vec<SeedGeneration> {
+        let mut generations = self.generations.lock().unwrap();
+        self.prune(&mut generations, now());
+        generations
+            .iter()
+            .filter(|generation| generation.retired_at.is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// The time at which a retired generation stops being accepted.
+    pub fn expiry(&self, generation: &SeedGeneration) -> Option<SystemTime> {
+        generation
+            .retired_at
+            .map(|retired_at| UNIX_EPOCH + Duration::from_secs(retired_at) + self.grace_period)
+    }
+
+    /// The key index that the next generation will be derived with.
+    pub fn next_key_index(&self) -> i32 {
+        self.current().key_index + 1
+    }
+
+    /// Retires the current generation and makes the one with the key index provided current.
+    /// Fails if the key index isn't the one that comes next, which means that someone else
+    /// rotated the seed first.
+    pub fn rotate(&self, key_index: i32, now: u64) -> Result<SeedGeneration, SeedError> {
+        let mut generations = self.generations.lock().unwrap();
+        if generations.last().map(|current| current.key_index + 1) != Some(key_index) {
+            return Err(SeedError::ConcurrentRotation);
+        }
+
+        if let Some(current) = generations.last_mut() {
+            current.retired_at = Some(now);
+        }
+        generations.push(SeedGeneration {
+            key_index,
+            retired_at: None,
+        });
+        self.prune(&mut generations, now);
+        self.persist(&generations).map_err(SeedError::Persist)?;
+
+        Ok(generations.last().cloned().unwrap())
+    }
+
+    fn prune(&self, generations: &mut Vec<SeedGeneration>, now: u64) {
+        let grace_period = self.grace_period.as_secs();
+        generations.retain(|generation| match generation.retired_at {
+            Some(retired_at) => retired_at.saturating_add(grace_period) > now,
+            None => true,
+        });
+    }
+
+    // Writes the store to a temporary file and moves it into place, so that we never leave a
+    // partially written store behind.
+    fn persist(&self, generations: &[SeedGeneration]) -> Result<(), std::io::Error> {
+        let contents = serde_json::to_string(generations)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+/// rotate_seed derives the next generation of our seed from LND and switches the offer handler
+/// over to it. Offers and payments made with the previous seed are still accepted until the
+/// store's grace period runs out.
+pub async fn rotate_seed(
+    store: &SeedStore,
+    offer_handler: &OfferHandler,
+    signer: &mut impl MessageSigner,
+) -> Result<SeedGeneration, SeedError> {
+    let key_index = store.next_key_index();
+    let seed = build_seed_from_lnd_node(signer, key_index)
+        .await
+        .map_err(|_| SeedError::DeriveSeed(key_index))?;
+
+    // Persist the rotation before we start using the new seed, so that offers created with it
+    // still work after a restart.
+    let mut previous = store.current();
+    let retired_at = now();
+    let generation = store.rotate(key_index, retired_at)?;
+    previous.retired_at = Some(retired_at);
+    if let Some(expiry) = store.expiry(&previous) {
+        offer_handler.rotate_seed(seed, expiry);
+    }
+
+    log::info!("Rotated seed to key index {key_index}.");
+    Ok(generation)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const GRACE_PERIOD: Duration = Duration::from_secs(100);
+
+    #[test]
+    fn test_rotate_seed_generations() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("seeds.json");
+        let store = SeedStore::open(&path, GRACE_PERIOD).unwrap();
+        assert_eq!(store.current().key_index, SEED_KEY_INDEX);
+        assert!(store.retired().is_empty());
+
+        let now = now();
+        let next = store.next_key_index();
+        assert_eq!(store.rotate(next, now).unwrap().key_index, next);
+        assert_eq!(store.current().key_index, SEED_KEY_INDEX + 1);
+        assert_eq!(
+            store.retired(),
+            vec![SeedGeneration {
+                key_index: SEED_KEY_INDEX,
+                retired_at: Some(now),
+            }]
+        );
+
+        // Rotating to an index that doesn't come next means someone beat us to it.
+        assert!(matches!(
+            store.rotate(next, now),
+            Err(SeedError::ConcurrentRotation)
+        ));
+
+        // Generations survive a restart.
+        let store = SeedStore::open(&path, GRACE_PERIOD).unwrap();
+        assert_eq!(store.current().key_index, SEED_KEY_INDEX + 1);
+        assert_eq!(store.retired().len(), 1);
+    }
+
+    #[test]
+    fn test_prune_expired_generations() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("seeds.json");
+        let store = SeedStore::open(&path, GRACE_PERIOD).unwrap();
+
+        // A generation retired longer than the grace period ago is no longer accepted.
+        let retired_at = now() - GRACE_PERIOD.as_secs() - 1;
+        store.rotate(store.next_key_index(), retired_at).unwrap();
+        assert!(store.retired().is_empty());
+        assert_eq!(store.current().key_index, SEED_KEY_INDEX + 1);
+    }
+}
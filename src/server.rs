@@ -1,65 +1,161 @@
-use crate::lnd::{get_lnd_client, get_network, Creds, LndCfg};
-use crate::lndkrpc::{CreateOfferRequest, CreateOfferResponse};
+use crate::auth::{
+    is_lndk_macaroon, parse_permissions, LndkMacaroon, MacaroonAuth, MacaroonError, Permission,
+};
+use crate::custom_messages::{
+    decode_reply_token, encode_reply_token, CustomMessage, CustomMessenger, ReceivedCustomMessage,
+};
+use crate::lnd::{ClientPoolError, LndClientPool};
+use crate::lndkrpc::{
+    BakeMacaroonRequest, BakeMacaroonResponse, CancelPaymentRequest, CancelPaymentResponse,
+    CreateOfferRequest, CreateOfferResponse, CustomOnionMessage, EstimateFeeRequest,
+    EstimateFeeResponse, ExportPaymentProofRequest, RotateSeedRequest, RotateSeedResponse,
+    SendCustomOnionMessageRequest, SendCustomOnionMessageResponse,
+    SubscribeCustomOnionMessagesRequest, VerifyPaymentProofRequest, VerifyPaymentProofResponse,
+};
 use crate::offers::handler::{CreateOfferParams, PayOfferParams};
-use crate::offers::validate_amount;
-use crate::offers::{get_destination, OfferError};
-use crate::{lndkrpc, Bolt12InvoiceString, OfferHandler, TLS_CERT_FILENAME, TLS_KEY_FILENAME};
+use crate::offers::{get_destination, EncodedPaymentProof, OfferError, PaymentProof};
+use crate::offers::{validate_amount, validate_invoice};
+use crate::policy::PaymentPolicy;
+use crate::seeds::{rotate_seed, SeedError, SeedStore};
+use crate::store::{PaymentDetails, PaymentRecord, PaymentStatus, PaymentStore, StoreError};
+use crate::{lndkrpc, Bolt12InvoiceString, OfferHandler};
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::Network;
+use futures::Stream;
 use lightning::blinded_path::payment::BlindedPaymentPath;
-use lightning::blinded_path::{Direction, IntroductionNode};
+use lightning::blinded_path::{BlindedHop, Direction, IntroductionNode};
 use lightning::ln::channelmanager::PaymentId;
 use lightning::offers::invoice::Bolt12Invoice;
-use lightning::offers::offer::{Offer, Quantity};
+use lightning::offers::offer::{Amount, Offer, Quantity};
+use lightning::onion_message::dns_resolution::HumanReadableName;
+use lightning::onion_message::messenger::Destination;
 use lightning::sign::EntropySource;
 use lightning::util::ser::Writeable;
 use lndkrpc::offers_server::Offers;
 use lndkrpc::{
-    Bolt12InvoiceContents, DecodeInvoiceRequest, FeatureBit, GetInvoiceRequest, GetInvoiceResponse,
-    PayInvoiceRequest, PayInvoiceResponse, PayOfferRequest, PayOfferResponse, PaymentHash,
-    PaymentPaths,
+    Bolt12InvoiceContents, CurrencyAmount, DecodeInvoiceRequest, DecodeOfferRequest, FeatureBit,
+    GetInvoiceRequest, GetInvoiceResponse, OfferContents, PayHumanReadableNameRequest,
+    PayHumanReadableNameResponse, PayInvoiceRequest, PayInvoiceResponse, PayOfferRequest,
+    PayOfferResponse, PaymentHash, PaymentPaths,
 };
-use rcgen::{generate_simple_self_signed, CertifiedKey, Error as RcgenError};
-use std::error::Error;
-use std::fmt::Display;
-use std::fs::{metadata, set_permissions, File};
-use std::io::Write;
 use std::num::NonZeroU64;
-use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 use tonic::metadata::MetadataMap;
-use tonic::transport::Identity;
 use tonic::{Request, Response, Status};
-use tonic_lnd::lnrpc::GetInfoRequest;
+use tonic_lnd::lnrpc::htlc_attempt::HtlcStatus;
+use tonic_lnd::lnrpc::Payment;
 pub struct LNDKServer {
     offer_handler: Arc<OfferHandler>,
     #[allow(dead_code)]
     node_id: PublicKey,
-    // The LND tls cert we need to establish a connection with LND.
-    lnd_cert: String,
-    address: String,
+    // The network LND is running on, which we learn once at startup.
+    network: Network,
+    // Connections to LND, keyed by the macaroon the caller authenticated with.
+    lnd_clients: LndClientPool,
+    // The macaroon lndk was started with, used to talk to LND on behalf of callers that
+    // authenticate with an lndk macaroon.
+    lnd_macaroon: String,
+    macaroon_auth: Arc<MacaroonAuth>,
+    // Spending controls that every payment has to comply with.
+    policy: PaymentPolicy,
+    // Payments made with idempotency keys, and the invoices we've paid.
+    payment_store: PaymentStore,
+    // Sends and receives onion messages of types that lndk doesn't handle itself.
+    custom_messenger: Arc<CustomMessenger>,
+    // The generations of the seed that our offers and payments are authenticated with.
+    seed_store: Arc<SeedStore>,
 }
 
 impl LNDKServer {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         offer_handler: Arc<OfferHandler>,
         node_id: &str,
-        lnd_cert: String,
-        address: String,
+        network: Network,
+        lnd_clients: LndClientPool,
+        lnd_macaroon: String,
+        macaroon_auth: Arc<MacaroonAuth>,
+        policy: PaymentPolicy,
+        payment_store: PaymentStore,
+        custom_messenger: Arc<CustomMessenger>,
+        seed_store: Arc<SeedStore>,
     ) -> Self {
         Self {
             offer_handler,
             node_id: PublicKey::from_str(node_id).unwrap(),
-            lnd_cert,
-            address,
+            network,
+            lnd_clients,
+            lnd_macaroon,
+            macaroon_auth,
+            policy,
+            payment_store,
+            custom_messenger,
+            seed_store,
         }
     }
+
+    /// Checks that the caller is allowed to make a request that requires the permission provided,
+    /// and returns the LND macaroon we should use to service it.
+    ///
+    /// Callers may authenticate with an lndk macaroon, in which case we check its permissions and
+    /// talk to LND with lndk's own macaroon. For backwards compatibility, any other macaroon is
+    /// assumed to be an LND macaroon and is forwarded to LND as is.
+    fn authorize(&self, metadata: &MetadataMap, required: Permission) -> Result<String, Status> {
+        let macaroon = check_auth_metadata(metadata)?;
+        if !is_lndk_macaroon(&macaroon) {
+            return Ok(macaroon);
+        }
+
+        verify_lndk_macaroon(&self.macaroon_auth, &macaroon, required)?;
+        Ok(self.lnd_macaroon.clone())
+    }
+
+    /// Checks that the caller is allowed to make a request that requires the permission provided,
+    /// for requests that lndk serves without calling LND. LND never sees an LND macaroon passed
+    /// to these, so it can't reject a bad one, and we only accept lndk macaroons instead.
+    fn authorize_lndk(&self, metadata: &MetadataMap, required: Permission) -> Result<(), Status> {
+        authorize_lndk_macaroon(&self.macaroon_auth, metadata, required)
+    }
+}
+
+fn authorize_lndk_macaroon(
+    macaroon_auth: &MacaroonAuth,
+    metadata: &MetadataMap,
+    required: Permission,
+) -> Result<(), Status> {
+    let macaroon = check_auth_metadata(metadata)?;
+    if !is_lndk_macaroon(&macaroon) {
+        return Err(Status::unauthenticated(
+            "This request requires an lndk macaroon",
+        ));
+    }
+    verify_lndk_macaroon(macaroon_auth, &macaroon, required)
+}
+
+fn verify_lndk_macaroon(
+    macaroon_auth: &MacaroonAuth,
+    macaroon: &str,
+    required: Permission,
+) -> Result<(), Status> {
+    let macaroon =
+        LndkMacaroon::from_hex(macaroon).map_err(|e| Status::unauthenticated(e.to_string()))?;
+    macaroon_auth
+        .verify(&macaroon, required)
+        .map_err(|e| match e {
+            MacaroonError::PermissionDenied(_) => Status::permission_denied(e.to_string()),
+            _ => Status::unauthenticated(e.to_string()),
+        })
 }
 
 #[tonic::async_trait]
 impl Offers for LNDKServer {
+    type SubscribeCustomOnionMessagesStream =
+        Pin<Box<dyn Stream<Item = Result<CustomOnionMessage, Status>> + Send>>;
+
     async fn pay_offer(
         &self,
         request: Request<PayOfferRequest>,
@@ -67,14 +163,7 @@ impl Offers for LNDKServer {
         log::info!("Received a request: {:?}", request.get_ref());
 
         let metadata = request.metadata();
-        let macaroon = check_auth_metadata(metadata)?;
-        let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
-            macaroon,
-        };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let mut client = get_lnd_client(lnd_cfg)
-            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+        let macaroon = self.authorize(metadata, Permission::Pay)?;
 
         let inner_request = request.get_ref();
         let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
@@ -90,51 +179,104 @@ impl Offers for LNDKServer {
             ))
         })?;
         let reply_path = None;
-        let info = client
-            .lightning()
-            .get_info(GetInfoRequest {})
+        let payment_id = inner_request
+            .payment_id
+            .as_deref()
+            .map(parse_payment_id)
+            .transpose()?;
+
+        // Check the payment against our spending policy before we send any invoice request.
+        self.policy
+            .check_issuer(offer.issuer_signing_pubkey())
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+        let amount = validate_amount(offer.amount().as_ref(), inner_request.amount)
             .await
-            .expect("failed to get info")
-            .into_inner();
-        let network = get_network(info)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let client = self
+            .lnd_clients
+            .get_client(&macaroon)
             .await
-            .map_err(|e| Status::internal(format!("{e:?}")))?;
+            .map_err(client_pool_status)?;
+
+        // If the client has already made this payment, return its result rather than paying again.
+        let idempotency_key = inner_request.idempotency_key.as_deref();
+        if let Some(key) = idempotency_key {
+            let request_id = format!("offer:{}:{amount}", inner_request.offer);
+            if let Err(e) = self.payment_store.begin(key, &request_id).await {
+                return Ok(Response::new(previous_payment(e)?.into()));
+            }
+        }
 
-        let fee_limit = create_fee_limit(inner_request.fee_limit, inner_request.fee_limit_percent);
+        let reservation = match self.policy.reserve(amount) {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                if let Some(key) = idempotency_key {
+                    self.payment_store.abandon(key).await;
+                }
+                return Err(Status::permission_denied(e.to_string()));
+            }
+        };
+
+        let (fee_limit, fee_limit_percent) = self
+            .policy
+            .fee_limit(inner_request.fee_limit, inner_request.fee_limit_percent);
+        let fee_limit = create_fee_limit(fee_limit, fee_limit_percent);
 
         let cfg = PayOfferParams {
-            offer,
+            offer: offer.clone(),
             amount: inner_request.amount,
             payer_note: inner_request.payer_note.clone(),
-            network,
+            network: self.network,
             client,
             destination,
             reply_path,
             response_invoice_timeout: inner_request.response_invoice_timeout,
             fee_limit,
+            payment_id,
         };
 
-        let payment = match self.offer_handler.pay_offer(cfg).await {
-            Ok(payment) => {
+        let result = self.offer_handler.pay_offer(cfg).await;
+        if result.is_err() {
+            self.policy.release(reservation);
+        }
+        let result = match result {
+            Ok(result) => {
                 log::info!("Payment succeeded.");
-                payment
+                result
             }
-            Err(e) => match e {
-                OfferError::InvalidAmount(e) => {
-                    return Err(Status::invalid_argument(e.to_string()))
+            Err(e) => {
+                if let Some(key) = idempotency_key {
+                    self.payment_store
+                        .complete(key, None, Err(e.to_string()))
+                        .await;
                 }
-                OfferError::InvalidCurrency => {
-                    return Err(Status::invalid_argument(format!("{e}")))
-                }
-                _ => return Err(Status::internal(format!("Internal error: {e}"))),
-            },
-        };
-
-        let reply = PayOfferResponse {
-            payment_preimage: payment.payment_preimage,
+                return Err(offer_error_status(e));
+            }
         };
 
-        Ok(Response::new(reply))
+        let payment = result.payment;
+        // The payment has gone through, so we record it even if the invoice can't be encoded.
+        let invoice = encode_invoice_as_hex(&result.invoice).unwrap_or_default();
+        let details = payment_details(&payment, invoice.clone(), result.payment_id);
+        if let Some(key) = idempotency_key {
+            self.payment_store
+                .complete(key, Some(payment.payment_hash.clone()), Ok(details.clone()))
+                .await;
+        }
+        self.payment_store
+            .save_proof(
+                &payment.payment_hash,
+                EncodedPaymentProof {
+                    offer: offer.to_string(),
+                    invoice_request: hex::encode(result.invoice_request.encode()),
+                    invoice,
+                    payment_preimage: payment.payment_preimage.clone(),
+                },
+            )
+            .await;
+
+        Ok(Response::new(details.into()))
     }
 
     async fn decode_invoice(
@@ -151,6 +293,66 @@ impl Offers for LNDKServer {
         Ok(Response::new(reply))
     }
 
+    async fn decode_offer(
+        &self,
+        request: Request<DecodeOfferRequest>,
+    ) -> Result<Response<OfferContents>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let offer = Offer::from_str(&request.get_ref().offer).map_err(|e| {
+            Status::invalid_argument(format!(
+                "The provided offer was invalid. Please provide a valid offer in bech32 format,
+                i.e. starting with 'lno'. Error: {e:?}"
+            ))
+        })?;
+
+        Ok(Response::new(generate_offer_contents(&offer)))
+    }
+
+    async fn pay_human_readable_name(
+        &self,
+        request: Request<PayHumanReadableNameRequest>,
+    ) -> Result<Response<PayHumanReadableNameResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let macaroon = self.authorize(request.metadata(), Permission::Pay)?;
+        let client = self
+            .lnd_clients
+            .get_client(&macaroon)
+            .await
+            .map_err(client_pool_status)?;
+
+        let (metadata, extensions, inner_request) = request.into_parts();
+        let name = parse_human_readable_name(&inner_request.name)?;
+        let offer = self
+            .offer_handler
+            .resolve_name(client, name, inner_request.response_invoice_timeout)
+            .await
+            .map_err(offer_error_status)?;
+        log::info!("Resolved {} to offer {offer}.", inner_request.name);
+
+        // From here on, paying the name is the same as paying its offer.
+        let pay_request = PayOfferRequest {
+            offer: offer.to_string(),
+            amount: inner_request.amount,
+            payer_note: inner_request.payer_note,
+            response_invoice_timeout: inner_request.response_invoice_timeout,
+            fee_limit: inner_request.fee_limit,
+            fee_limit_percent: inner_request.fee_limit_percent,
+            idempotency_key: inner_request.idempotency_key,
+            payment_id: inner_request.payment_id,
+        };
+        let payment = self
+            .pay_offer(Request::from_parts(metadata, extensions, pay_request))
+            .await?
+            .into_inner();
+
+        Ok(Response::new(PayHumanReadableNameResponse {
+            offer: offer.to_string(),
+            payment: Some(payment),
+        }))
+    }
+
     async fn get_invoice(
         &self,
         request: Request<GetInvoiceRequest>,
@@ -158,14 +360,12 @@ impl Offers for LNDKServer {
         log::info!("Received a request: {:?}", request.get_ref());
 
         let metadata = request.metadata();
-        let macaroon = check_auth_metadata(metadata)?;
-        let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
-            macaroon,
-        };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let mut client = get_lnd_client(lnd_cfg)
-            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+        let macaroon = self.authorize(metadata, Permission::Read)?;
+        let client = self
+            .lnd_clients
+            .get_client(&macaroon)
+            .await
+            .map_err(client_pool_status)?;
 
         let inner_request = request.get_ref();
         let offer = Offer::from_str(&inner_request.offer).map_err(|e| {
@@ -179,27 +379,23 @@ impl Offers for LNDKServer {
             .await
             .map_err(|e| Status::unavailable(format!("Couldn't find destination: {e}")))?;
         let reply_path = None;
-
-        let info = client
-            .lightning()
-            .get_info(GetInfoRequest {})
-            .await
-            .expect("failed to get info")
-            .into_inner();
-        let network = get_network(info)
-            .await
-            .map_err(|e| Status::internal(format!("{e:?}")))?;
+        let payment_id = inner_request
+            .payment_id
+            .as_deref()
+            .map(parse_payment_id)
+            .transpose()?;
 
         let cfg = PayOfferParams {
-            offer,
+            offer: offer.clone(),
             amount: inner_request.amount,
             payer_note: inner_request.payer_note.clone(),
-            network,
+            network: self.network,
             client,
             destination,
             reply_path,
             response_invoice_timeout: inner_request.response_invoice_timeout,
             fee_limit: None,
+            payment_id,
         };
 
         let (invoice, _, payment_id) = match self.offer_handler.get_invoice(cfg).await {
@@ -207,26 +403,32 @@ impl Offers for LNDKServer {
                 log::info!("Invoice request succeeded.");
                 invoice
             }
-            Err(e) => match e {
-                OfferError::InvalidAmount(e) => {
-                    return Err(Status::invalid_argument(e.to_string()))
-                }
-                OfferError::InvalidCurrency => {
-                    return Err(Status::invalid_argument(format!("{e}")))
-                }
-                _ => return Err(Status::internal(format!("Internal error: {e}"))),
-            },
+            Err(e) => return Err(offer_error_status(e)),
         };
 
         // We need to remove the payment from our tracking map now.
         // TODO: This is a hack to remove the payment from the tracking map. We should do it when
         // get_invoice params option or other way.
-        {
-            self.offer_handler.remove_active_payment(payment_id);
+        let invoice_request = self.offer_handler.remove_active_payment(payment_id);
+        let invoice_hex_str = encode_invoice_as_hex(&invoice)?;
+
+        // The invoice may be paid with PayInvoice later on, in which case we'll need the offer and
+        // invoice request to prove that it was paid.
+        if let Some(invoice_request) = invoice_request {
+            self.payment_store.save_unpaid_proof(
+                &hex::encode(invoice.payment_hash().0),
+                EncodedPaymentProof {
+                    offer: offer.to_string(),
+                    invoice_request: hex::encode(invoice_request.encode()),
+                    invoice: invoice_hex_str.clone(),
+                    payment_preimage: String::new(),
+                },
+                invoice_expiry(&invoice),
+            );
         }
 
         let reply: GetInvoiceResponse = GetInvoiceResponse {
-            invoice_hex_str: encode_invoice_as_hex(&invoice)?,
+            invoice_hex_str,
             invoice_contents: Some(generate_bolt12_invoice_contents(&invoice)),
         };
 
@@ -240,14 +442,7 @@ impl Offers for LNDKServer {
         log::info!("Received a request: {:?}", request.get_ref());
 
         let metadata = request.metadata();
-        let macaroon = check_auth_metadata(metadata)?;
-        let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
-            macaroon,
-        };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let client = get_lnd_client(lnd_cfg)
-            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
+        let macaroon = self.authorize(metadata, Permission::Pay)?;
 
         let inner_request = request.get_ref();
         let invoice_string: Bolt12InvoiceString = inner_request.invoice.clone().into();
@@ -258,31 +453,110 @@ impl Offers for LNDKServer {
             ))
         })?;
 
-        let amount = match validate_amount(invoice.amount().as_ref(), inner_request.amount).await {
-            Ok(amount) => amount,
-            Err(e) => return Err(Status::invalid_argument(e.to_string())),
+        // Unless the caller tells us otherwise, we pay the amount the invoice asks for. Either way,
+        // the invoice has to check out before we go any further.
+        let amount = inner_request.amount.unwrap_or(invoice.amount_msats());
+        validate_invoice(&invoice, self.network, amount).map_err(offer_error_status)?;
+
+        // Invoices for offers with blinded paths are signed with a key that's derived for the
+        // invoice, so we check the issuer of the offer we fetched the invoice for instead. If we
+        // didn't fetch it, or no longer remember doing so, the invoice's own signing key is all
+        // we have to go on. That's the issuer's key for offers without blinded paths, and a key
+        // that no allow list contains otherwise, so those invoices are refused when one is set.
+        let payment_hash = hex::encode(invoice.payment_hash().0);
+        let issuer = self
+            .payment_store
+            .fetched_offer(&payment_hash)
+            .and_then(|offer| Offer::from_str(&offer).ok())
+            .and_then(|offer| offer.issuer_signing_pubkey())
+            .unwrap_or(invoice.signing_pubkey());
+        self.policy
+            .check_issuer(Some(issuer))
+            .map_err(|e| Status::permission_denied(e.to_string()))?;
+
+        let client = self
+            .lnd_clients
+            .get_client(&macaroon)
+            .await
+            .map_err(client_pool_status)?;
+
+        // If the client has already made this payment, return its result rather than paying again.
+        let idempotency_key = inner_request.idempotency_key.as_deref();
+        if let Some(key) = idempotency_key {
+            let request_id = format!("invoice:{}:{amount}", inner_request.invoice);
+            if let Err(e) = self.payment_store.begin(key, &request_id).await {
+                return Ok(Response::new(previous_payment(e)?.into()));
+            }
+        }
+
+        // Never pay the same invoice twice.
+        if let Err(e) = self
+            .payment_store
+            .begin_invoice(&payment_hash, invoice_expiry(&invoice))
+            .await
+        {
+            if let Some(key) = idempotency_key {
+                self.payment_store.abandon(key).await;
+            }
+            return Err(store_error_status(e));
+        }
+
+        let reservation = match self.policy.reserve(amount) {
+            Ok(reservation) => reservation,
+            Err(e) => {
+                if let Some(key) = idempotency_key {
+                    self.payment_store.abandon(key).await;
+                }
+                self.payment_store
+                    .complete_invoice(&payment_hash, false)
+                    .await;
+                return Err(Status::permission_denied(e.to_string()));
+            }
         };
+
         let payment_id = PaymentId(self.offer_handler.messenger_utils.get_secure_random_bytes());
 
-        let fee_limit = create_fee_limit(inner_request.fee_limit, inner_request.fee_limit_percent);
+        let (fee_limit, fee_limit_percent) = self
+            .policy
+            .fee_limit(inner_request.fee_limit, inner_request.fee_limit_percent);
+        let fee_limit = create_fee_limit(fee_limit, fee_limit_percent);
 
-        let invoice = match self
+        let result = self
             .offer_handler
             .pay_invoice(client, amount, &invoice, payment_id, fee_limit)
-            .await
-        {
-            Ok(invoice) => {
+            .await;
+        if result.is_err() {
+            self.policy.release(reservation);
+        }
+        self.payment_store
+            .complete_invoice(&payment_hash, result.is_ok())
+            .await;
+        let payment = match result {
+            Ok(payment) => {
                 log::info!("Invoice paid.");
-                invoice
+                payment
+            }
+            Err(e) => {
+                if let Some(key) = idempotency_key {
+                    self.payment_store
+                        .complete(key, Some(payment_hash.clone()), Err(e.to_string()))
+                        .await;
+                }
+                return Err(Status::internal(format!("Error paying invoice: {e}")));
             }
-            Err(e) => return Err(Status::internal(format!("Error paying invoice: {e}"))),
         };
 
-        let reply = PayInvoiceResponse {
-            payment_preimage: invoice.payment_preimage,
-        };
+        let details = payment_details(&payment, inner_request.invoice.clone(), payment_id);
+        if let Some(key) = idempotency_key {
+            self.payment_store
+                .complete(key, Some(payment_hash.clone()), Ok(details.clone()))
+                .await;
+        }
+        self.payment_store
+            .complete_proof(&payment_hash, &payment.payment_preimage)
+            .await;
 
-        Ok(Response::new(reply))
+        Ok(Response::new(details.into()))
     }
 
     async fn create_offer(
@@ -292,31 +566,20 @@ impl Offers for LNDKServer {
         log::info!("Received a request: {:?}", request.get_ref());
 
         let metadata = request.metadata();
-        let macaroon = check_auth_metadata(metadata)?;
-        let creds = Creds::String {
-            cert: self.lnd_cert.clone(),
-            macaroon,
-        };
-        let lnd_cfg = LndCfg::new(self.address.clone(), creds);
-        let mut client = get_lnd_client(lnd_cfg)
-            .map_err(|e| Status::unavailable(format!("Couldn't connect to lnd: {e}")))?;
-        let inner_request = request.get_ref();
-        let info = client
-            .lightning()
-            .get_info(GetInfoRequest {})
-            .await
-            .expect("failed to get info")
-            .into_inner();
-        let network = get_network(info)
+        let macaroon = self.authorize(metadata, Permission::CreateOffer)?;
+        let client = self
+            .lnd_clients
+            .get_client(&macaroon)
             .await
-            .map_err(|e| Status::internal(format!("{e:?}")))?;
+            .map_err(client_pool_status)?;
+        let inner_request = request.get_ref();
         let quantity = parse_quantity(inner_request.quantity)
             .map_err(|_| Status::invalid_argument("Invalid quantity provided"))?;
 
         let request = CreateOfferParams {
             client,
             amount_msats: inner_request.amount.unwrap_or(0),
-            chain: network,
+            chain: self.network,
             description: inner_request.description.clone(),
             issuer: inner_request.issuer.clone(),
             quantity,
@@ -332,6 +595,490 @@ impl Offers for LNDKServer {
         };
         Ok(Response::new(reply))
     }
+
+    async fn cancel_payment(
+        &self,
+        request: Request<CancelPaymentRequest>,
+    ) -> Result<Response<CancelPaymentResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        self.authorize_lndk(request.metadata(), Permission::Pay)?;
+        let payment_id = parse_payment_id(&request.get_ref().payment_id)?;
+
+        match self.offer_handler.cancel_payment(payment_id) {
+            Ok(()) => Ok(Response::new(CancelPaymentResponse {})),
+            Err(e @ OfferError::PaymentNotFound(_)) => Err(Status::not_found(e.to_string())),
+            Err(e @ OfferError::PaymentAlreadyDispatched(_)) => {
+                Err(Status::failed_precondition(e.to_string()))
+            }
+            Err(e) => Err(Status::internal(format!("Internal error: {e}"))),
+        }
+    }
+
+    async fn estimate_fee(
+        &self,
+        request: Request<EstimateFeeRequest>,
+    ) -> Result<Response<EstimateFeeResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        // Clients that can pay are also allowed to find out what a payment would cost, so that
+        // they can confirm it first.
+        let metadata = request.metadata();
+        let macaroon = self
+            .authorize(metadata, Permission::Read)
+            .or_else(|e| match e.code() {
+                tonic::Code::PermissionDenied => self.authorize(metadata, Permission::Pay),
+                _ => Err(e),
+            })?;
+        let client = self
+            .lnd_clients
+            .get_client(&macaroon)
+            .await
+            .map_err(client_pool_status)?;
+
+        let inner_request = request.get_ref();
+        let (invoice, amount) = match (inner_request.invoice.is_empty(), &inner_request.offer) {
+            (false, None) => {
+                let invoice_string: Bolt12InvoiceString = inner_request.invoice.clone().into();
+                let invoice = Bolt12Invoice::try_from(invoice_string).map_err(|e| {
+                    Status::invalid_argument(format!(
+                        "The provided invoice was invalid. Please provide a valid invoice in hex format.
+                        Error: {e:?}"
+                    ))
+                })?;
+                let amount = inner_request.amount.unwrap_or(invoice.amount_msats());
+                (invoice, amount)
+            }
+            (true, Some(offer)) => {
+                // We have to ask the offer's creator for an invoice to know what paths we'd pay
+                // over.
+                let offer = Offer::from_str(offer).map_err(|e| {
+                    Status::invalid_argument(format!(
+                        "The provided offer was invalid. Please provide a valid offer in bech32 format,
+                        i.e. starting with 'lno'. Error: {e:?}"
+                    ))
+                })?;
+                let destination = get_destination(&offer)
+                    .await
+                    .map_err(|e| Status::unavailable(format!("Couldn't find destination: {e}")))?;
+                let cfg = PayOfferParams {
+                    offer,
+                    amount: inner_request.amount,
+                    payer_note: None,
+                    network: self.network,
+                    client: client.clone(),
+                    destination,
+                    reply_path: None,
+                    response_invoice_timeout: inner_request.response_invoice_timeout,
+                    fee_limit: None,
+                    payment_id: None,
+                };
+                let (invoice, amount, payment_id) = self
+                    .offer_handler
+                    .get_invoice(cfg)
+                    .await
+                    .map_err(offer_error_status)?;
+                self.offer_handler.remove_active_payment(payment_id);
+                (invoice, amount)
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    "Exactly one of invoice or offer must be set",
+                ))
+            }
+        };
+
+        // There's no point estimating the fee for an invoice we wouldn't pay.
+        validate_invoice(&invoice, self.network, amount).map_err(offer_error_status)?;
+
+        let (fee_limit, fee_limit_percent) = self
+            .policy
+            .fee_limit(inner_request.fee_limit, inner_request.fee_limit_percent);
+        let fee_limit = create_fee_limit(fee_limit, fee_limit_percent);
+
+        let estimate = self
+            .offer_handler
+            .estimate_fee(client, amount, &invoice, fee_limit)
+            .await
+            .map_err(offer_error_status)?;
+
+        Ok(Response::new(EstimateFeeResponse {
+            amount_msats: amount,
+            min_fee_msats: estimate.min_fee_msats,
+            min_total_time_lock: estimate.min_total_time_lock,
+            max_fee_msats: estimate.max_fee_msats,
+            max_total_time_lock: estimate.max_total_time_lock,
+            routable_paths: estimate.routable_paths as u32,
+            invoice: encode_invoice_as_hex(&invoice)?,
+        }))
+    }
+
+    async fn bake_macaroon(
+        &self,
+        request: Request<BakeMacaroonRequest>,
+    ) -> Result<Response<BakeMacaroonResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        // Only lndk's own admin macaroons can bake new macaroons, since we have no way of checking
+        // what an LND macaroon is allowed to do.
+        let macaroon = check_auth_metadata(request.metadata())?;
+        if !is_lndk_macaroon(&macaroon) {
+            return Err(Status::permission_denied(
+                "Baking macaroons requires an lndk admin macaroon",
+            ));
+        }
+        verify_lndk_macaroon(&self.macaroon_auth, &macaroon, Permission::Admin)?;
+
+        let inner_request = request.get_ref();
+        if inner_request.permissions.is_empty() {
+            return Err(Status::invalid_argument(
+                "At least one permission must be provided",
+            ));
+        }
+        let permissions = parse_permissions(&inner_request.permissions)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        let macaroon = self.macaroon_auth.bake(&permissions);
+        let reply = BakeMacaroonResponse {
+            macaroon: macaroon.to_hex(),
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn export_payment_proof(
+        &self,
+        request: Request<ExportPaymentProofRequest>,
+    ) -> Result<Response<lndkrpc::PaymentProof>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        self.authorize_lndk(request.metadata(), Permission::Read)?;
+        let payment_hash = &request.get_ref().payment_hash;
+        let proof = self.payment_store.proof(payment_hash).ok_or_else(|| {
+            Status::not_found(format!(
+                "No proof of payment for payment hash {payment_hash}"
+            ))
+        })?;
+
+        Ok(Response::new(lndkrpc::PaymentProof {
+            offer: proof.offer,
+            invoice_request: proof.invoice_request,
+            invoice: proof.invoice,
+            payment_preimage: proof.payment_preimage,
+        }))
+    }
+
+    async fn verify_payment_proof(
+        &self,
+        request: Request<VerifyPaymentProofRequest>,
+    ) -> Result<Response<VerifyPaymentProofResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let proof = request
+            .into_inner()
+            .proof
+            .ok_or_else(|| Status::invalid_argument("A payment proof is required"))?;
+        let proof = EncodedPaymentProof {
+            offer: proof.offer,
+            invoice_request: proof.invoice_request,
+            invoice: proof.invoice,
+            payment_preimage: proof.payment_preimage,
+        };
+
+        // A proof that doesn't check out isn't an error on the caller's part, so we report why
+        // it's invalid rather than failing the request.
+        let proof = match PaymentProof::decode(&proof).and_then(|proof| {
+            proof.verify()?;
+            Ok(proof)
+        }) {
+            Ok(proof) => proof,
+            Err(e) => {
+                return Ok(Response::new(VerifyPaymentProofResponse {
+                    valid: false,
+                    error: e.to_string(),
+                    ..Default::default()
+                }))
+            }
+        };
+
+        let invoice_request = &proof.invoice_request;
+        let reply = VerifyPaymentProofResponse {
+            valid: true,
+            error: String::new(),
+            payment_hash: hex::encode(proof.invoice.payment_hash().0),
+            amount_msats: proof.invoice.amount_msats(),
+            payer_note: invoice_request.payer_note().map(|note| note.to_string()),
+            payer_signing_pubkey: invoice_request.payer_signing_pubkey().to_string(),
+            signing_pubkey: proof.invoice.signing_pubkey().to_string(),
+        };
+
+        Ok(Response::new(reply))
+    }
+
+    async fn send_custom_onion_message(
+        &self,
+        request: Request<SendCustomOnionMessageRequest>,
+    ) -> Result<Response<SendCustomOnionMessageResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        self.authorize_lndk(request.metadata(), Permission::Messages)?;
+        let inner = request.into_inner();
+        let message = CustomMessage {
+            tlv_type: inner.tlv_type,
+            data: hex::decode(&inner.data).map_err(|e| {
+                Status::invalid_argument(format!("Invalid hex-encoded message data: {e}"))
+            })?,
+        };
+
+        let result = match (inner.destination.is_empty(), inner.reply_token.is_empty()) {
+            (false, true) => {
+                let node_id = PublicKey::from_str(&inner.destination).map_err(|e| {
+                    Status::invalid_argument(format!("Invalid destination pubkey: {e}"))
+                })?;
+                self.custom_messenger
+                    .send(message, Destination::Node(node_id), inner.request_reply)
+            }
+            (true, false) => {
+                let responder = hex::decode(&inner.reply_token)
+                    .ok()
+                    .and_then(|token| decode_reply_token(&token).ok())
+                    .ok_or_else(|| Status::invalid_argument("Invalid reply token"))?;
+                self.custom_messenger
+                    .respond(message, responder, inner.request_reply)
+            }
+            _ => {
+                return Err(Status::invalid_argument(
+                    "Exactly one of destination or reply_token must be set",
+                ))
+            }
+        };
+        result.map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        Ok(Response::new(SendCustomOnionMessageResponse {}))
+    }
+
+    async fn subscribe_custom_onion_messages(
+        &self,
+        request: Request<SubscribeCustomOnionMessagesRequest>,
+    ) -> Result<Response<Self::SubscribeCustomOnionMessagesStream>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        self.authorize_lndk(request.metadata(), Permission::Messages)?;
+        let tlv_types = request.into_inner().tlv_types;
+        let receiver = self.custom_messenger.subscribe();
+        // The receiver is dropped once we've told the client they lagged, which ends the stream.
+        let stream = futures::stream::unfold(Some(receiver), move |receiver| {
+            let tlv_types = tlv_types.clone();
+            async move {
+                let mut receiver = receiver?;
+                loop {
+                    match receiver.recv().await {
+                        Ok(received) => {
+                            let tlv_type = received.message.tlv_type;
+                            if tlv_types.is_empty() || tlv_types.contains(&tlv_type) {
+                                return Some((
+                                    Ok(convert_custom_message(received)),
+                                    Some(receiver),
+                                ));
+                            }
+                        }
+                        // If the client can't keep up, we let them know that they missed messages
+                        // by ending the stream.
+                        Err(RecvError::Lagged(missed)) => {
+                            let status =
+                                Status::data_loss(format!("Missed {missed} onion messages"));
+                            return Some((Err(status), None));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn rotate_seed(
+        &self,
+        request: Request<RotateSeedRequest>,
+    ) -> Result<Response<RotateSeedResponse>, Status> {
+        log::info!("Received a request: {:?}", request.get_ref());
+
+        let macaroon = self.authorize(request.metadata(), Permission::Admin)?;
+        let mut client = self
+            .lnd_clients
+            .get_client(&macaroon)
+            .await
+            .map_err(client_pool_status)?;
+
+        let generation = rotate_seed(&self.seed_store, &self.offer_handler, &mut client)
+            .await
+            .map_err(|e| match e {
+                SeedError::ConcurrentRotation => Status::aborted(e.to_string()),
+                _ => Status::internal(e.to_string()),
+            })?;
+
+        let reply = RotateSeedResponse {
+            key_index: generation.key_index,
+            retired_key_indexes: self
+                .seed_store
+                .retired()
+                .iter()
+                .map(|generation| generation.key_index)
+                .collect(),
+        };
+        Ok(Response::new(reply))
+    }
+}
+
+// Returns the preimage of a payment that was already made with the same idempotency key, or the
+// status to return if that payment didn't succeed.
+fn previous_payment(err: StoreError) -> Result<PaymentDetails, Status> {
+    match err {
+        StoreError::Duplicate(PaymentRecord {
+            status: PaymentStatus::Succeeded,
+            payment_preimage: Some(payment_preimage),
+            payment_hash,
+            details,
+            ..
+        }) => {
+            // Payments recorded by older versions of lndk only have their preimage and hash.
+            Ok(details.unwrap_or_else(|| PaymentDetails {
+                payment_preimage,
+                payment_hash: payment_hash.unwrap_or_default(),
+                ..Default::default()
+            }))
+        }
+        StoreError::Duplicate(ref record) => {
+            let msg = match &record.error {
+                Some(e) => format!("{err}: {e}"),
+                None => err.to_string(),
+            };
+            Err(Status::already_exists(msg))
+        }
+        _ => Err(store_error_status(err)),
+    }
+}
+
+// Returns when the invoice expires, in seconds since the unix epoch.
+fn invoice_expiry(invoice: &Bolt12Invoice) -> u64 {
+    (invoice.created_at() + invoice.relative_expiry()).as_secs()
+}
+
+// Collects the details of a successful payment that we return to the client.
+fn payment_details(payment: &Payment, invoice: String, payment_id: PaymentId) -> PaymentDetails {
+    PaymentDetails {
+        payment_preimage: payment.payment_preimage.clone(),
+        payment_hash: payment.payment_hash.clone(),
+        amount_msats: payment.value_msat as u64,
+        fee_msats: payment.fee_msat as u64,
+        attempts: payment.htlcs.len() as u32,
+        creation_time_ns: payment.creation_time_ns,
+        settle_time_ns: settle_time_ns(payment),
+        invoice,
+        payment_id: hex::encode(payment_id.0),
+    }
+}
+
+impl From<PaymentDetails> for PayOfferResponse {
+    fn from(details: PaymentDetails) -> Self {
+        PayOfferResponse {
+            payment_preimage: details.payment_preimage,
+            payment_hash: details.payment_hash,
+            amount_msats: details.amount_msats,
+            fee_msats: details.fee_msats,
+            attempts: details.attempts,
+            creation_time_ns: details.creation_time_ns,
+            settle_time_ns: details.settle_time_ns,
+            invoice: details.invoice,
+            payment_id: details.payment_id,
+        }
+    }
+}
+
+impl From<PaymentDetails> for PayInvoiceResponse {
+    fn from(details: PaymentDetails) -> Self {
+        PayInvoiceResponse {
+            payment_preimage: details.payment_preimage,
+            payment_hash: details.payment_hash,
+            amount_msats: details.amount_msats,
+            fee_msats: details.fee_msats,
+            attempts: details.attempts,
+            creation_time_ns: details.creation_time_ns,
+            settle_time_ns: details.settle_time_ns,
+            invoice: details.invoice,
+            payment_id: details.payment_id,
+        }
+    }
+}
+
+// Returns when the payment's successful HTLC resolved, in nanoseconds since the unix epoch.
+fn settle_time_ns(payment: &Payment) -> i64 {
+    payment
+        .htlcs
+        .iter()
+        .filter(|htlc| htlc.status() == HtlcStatus::Succeeded)
+        .map(|htlc| htlc.resolve_time_ns)
+        .max()
+        .unwrap_or_default()
+}
+
+fn store_error_status(err: StoreError) -> Status {
+    match err {
+        StoreError::Duplicate(_) | StoreError::InvoiceAlreadyPaid(_) => {
+            Status::already_exists(err.to_string())
+        }
+        StoreError::KeyReused => Status::invalid_argument(err.to_string()),
+        StoreError::Persist(_) => Status::internal(err.to_string()),
+    }
+}
+
+// Maps errors from the LND client pool to a grpc status. If LND refused the caller's macaroon we
+// pass its status code on, so that an unauthorized caller doesn't look like an outage.
+fn client_pool_status(e: ClientPoolError) -> Status {
+    match &e {
+        ClientPoolError::Connect(_) => Status::unavailable(e.to_string()),
+        ClientPoolError::Rejected(status) => Status::new(status.code(), e.to_string()),
+    }
+}
+
+// Maps errors from the offer handler to a grpc status, so that callers can tell bad requests
+// apart from failures on our end.
+fn offer_error_status(e: OfferError) -> Status {
+    match e {
+        OfferError::InvalidAmount(_)
+        | OfferError::InvalidCurrency
+        | OfferError::InvoiceExpired(_)
+        | OfferError::InvoiceChainMismatch(_)
+        | OfferError::InvoiceAmountMismatch { .. }
+        | OfferError::UnsupportedInvoiceFeatures(_)
+        | OfferError::InvalidPaymentProof(_) => Status::invalid_argument(e.to_string()),
+        OfferError::PaymentCancelled(_) => Status::cancelled(e.to_string()),
+        OfferError::PaymentAbandoned(_) | OfferError::InvoiceMismatch(_) => {
+            Status::aborted(e.to_string())
+        }
+        OfferError::NoDnsResolvers => Status::failed_precondition(e.to_string()),
+        OfferError::ResolveNameTimeout(_) => Status::deadline_exceeded(e.to_string()),
+        OfferError::ResolveNameFailure(_) => Status::unavailable(e.to_string()),
+        _ => Status::internal(format!("Internal error: {e}")),
+    }
+}
+
+// Parses a BIP-353 name of the form user@domain, which may be prefixed with ₿ as names usually are
+// when they're displayed.
+fn parse_human_readable_name(name: &str) -> Result<HumanReadableName, Status> {
+    let name = name.strip_prefix('₿').unwrap_or(name);
+    HumanReadableName::from_encoded(name).map_err(|_| {
+        Status::invalid_argument("Name must be a human-readable name of the form user@domain")
+    })
+}
+
+fn parse_payment_id(payment_id: &str) -> Result<PaymentId, Status> {
+    let bytes: [u8; 32] = hex::decode(payment_id)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| Status::invalid_argument("Payment id must be 32 hex-encoded bytes"))?;
+
+    Ok(PaymentId(bytes))
 }
 
 fn parse_quantity(rpc_quantity: Option<u64>) -> Result<Option<Quantity>, ()> {
@@ -365,7 +1112,7 @@ fn check_auth_metadata(metadata: &MetadataMap) -> Result<String, Status> {
             .to_string(),
         _ => {
             return Err(Status::unauthenticated(
-                "No LND macaroon provided: Make sure to provide macaroon in request metadata",
+                "No macaroon provided: Make sure to provide macaroon in request metadata",
             ))
         }
     };
@@ -373,81 +1120,6 @@ fn check_auth_metadata(metadata: &MetadataMap) -> Result<String, Status> {
     Ok(macaroon)
 }
 
-/// An error that occurs when generating TLS credentials.
-#[derive(Debug)]
-pub enum CertificateGenFailure {
-    RcgenError(RcgenError),
-    IoError(std::io::Error),
-}
-
-impl Display for CertificateGenFailure {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            CertificateGenFailure::RcgenError(e) => {
-                write!(f, "Error generating TLS certificate: {e:?}")
-            }
-            CertificateGenFailure::IoError(e) => write!(f, "IO error: {e:?}"),
-        }
-    }
-}
-
-impl Error for CertificateGenFailure {}
-
-// If a tls cert/key pair doesn't already exist, generate_tls_creds creates the tls cert/key pair
-// required to secure connections to LNDK's gRPC server. By default they are stored in ~/.lndk.
-pub fn generate_tls_creds(
-    data_dir: PathBuf,
-    tls_ips_string: Option<String>,
-) -> Result<(), CertificateGenFailure> {
-    let cert_path = data_dir.join(TLS_CERT_FILENAME);
-    let key_path = data_dir.join(TLS_KEY_FILENAME);
-    let tls_ips = collect_tls_ips(tls_ips_string);
-
-    // Did we have to generate a new key? In that case we also need to regenerate the certificate.
-    if !key_path.exists() || !cert_path.exists() {
-        log::debug!("Generating fresh TLS credentials in {data_dir:?}");
-        let mut subject_alt_names = vec!["localhost".to_string()];
-        if let Some(ips) = tls_ips {
-            for ip in ips {
-                subject_alt_names.push(ip);
-            }
-        };
-
-        let CertifiedKey { cert, key_pair } = generate_simple_self_signed(subject_alt_names)
-            .map_err(CertificateGenFailure::RcgenError)?;
-
-        // Create the tls files. Make sure the key is user-readable only:
-        let mut file = File::create(&key_path).map_err(CertificateGenFailure::IoError)?;
-        let mut perms = metadata(&key_path)
-            .map_err(CertificateGenFailure::IoError)?
-            .permissions();
-        perms.set_mode(0o600);
-        set_permissions(&key_path, perms).map_err(CertificateGenFailure::IoError)?;
-
-        file.write_all(key_pair.serialize_pem().as_bytes())
-            .map_err(CertificateGenFailure::IoError)?;
-        drop(file);
-
-        std::fs::write(&cert_path, cert.pem()).map_err(CertificateGenFailure::IoError)?;
-    };
-
-    Ok(())
-}
-
-// Read the existing tls credentials from disk.
-pub fn read_tls(data_dir: PathBuf) -> Result<Identity, std::io::Error> {
-    let cert = std::fs::read_to_string(data_dir.join(TLS_CERT_FILENAME))?;
-    let key = std::fs::read_to_string(data_dir.join(TLS_KEY_FILENAME))?;
-
-    Ok(Identity::from_pem(cert, key))
-}
-
-// The user first passes in the tls ips as a comma-deliminated string into LNDK. Here we turn that
-// string into a Vec.
-fn collect_tls_ips(tls_ips_str: Option<String>) -> Option<Vec<String>> {
-    tls_ips_str.map(|tls_ips_str| tls_ips_str.split(',').map(|str| str.to_owned()).collect())
-}
-
 fn create_fee_limit(
     fee_limit: Option<u32>,
     fee_limit_percent: Option<u32>,
@@ -463,7 +1135,8 @@ fn create_fee_limit(
     }
 }
 
-fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12InvoiceContents {
+/// Converts an invoice into the structured Bolt12InvoiceContents we return over grpc.
+pub fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12InvoiceContents {
     Bolt12InvoiceContents {
         chain: invoice.chain().to_string(),
         quantity: invoice.quantity(),
@@ -479,10 +1152,66 @@ fn generate_bolt12_invoice_contents(invoice: &Bolt12Invoice) -> lndkrpc::Bolt12I
         node_id: Some(convert_public_key(&invoice.signing_pubkey())),
         signature: invoice.signature().to_string(),
         payment_paths: extract_payment_paths(invoice),
-        features: convert_invoice_features(invoice.invoice_features().clone()),
+        features: known_feature_bits(invoice.invoice_features().le_flags()),
         payer_note: invoice
             .payer_note()
             .map(|payer_note| payer_note.to_string()),
+        unknown_features: unknown_feature_bits(invoice.invoice_features().le_flags()),
+    }
+}
+
+/// Converts an offer into the structured OfferContents we return over grpc.
+pub fn generate_offer_contents(offer: &Offer) -> OfferContents {
+    let (amount_msats, currency_amount) = match offer.amount() {
+        Some(Amount::Bitcoin { amount_msats }) => (Some(amount_msats), None),
+        Some(Amount::Currency {
+            iso4217_code,
+            amount,
+        }) => (
+            None,
+            Some(CurrencyAmount {
+                currency: String::from_utf8_lossy(&iso4217_code).to_string(),
+                amount,
+            }),
+        ),
+        None => (None, None),
+    };
+    let max_quantity = match offer.supported_quantity() {
+        Quantity::One => Some(1),
+        Quantity::Bounded(max) => Some(max.get()),
+        Quantity::Unbounded => None,
+    };
+
+    OfferContents {
+        chains: offer
+            .chains()
+            .iter()
+            .map(|chain| chain.to_string())
+            .collect(),
+        amount_msats,
+        currency_amount,
+        description: offer
+            .description()
+            .map(|description| description.to_string()),
+        issuer: offer.issuer().map(|issuer| issuer.to_string()),
+        min_quantity: 1,
+        max_quantity,
+        absolute_expiry: offer.absolute_expiry().map(|expiry| expiry.as_secs()),
+        issuer_signing_pubkey: offer
+            .issuer_signing_pubkey()
+            .map(|pubkey| convert_public_key(&pubkey)),
+        paths: offer
+            .paths()
+            .iter()
+            .map(|path| {
+                convert_blinded_path_parts(
+                    path.introduction_node(),
+                    path.blinding_point(),
+                    path.blinded_hops(),
+                )
+            })
+            .collect(),
+        metadata: offer.metadata().cloned().unwrap_or_default(),
     }
 }
 
@@ -505,13 +1234,45 @@ fn extract_payment_paths(invoice: &Bolt12Invoice) -> Vec<PaymentPaths> {
         .collect()
 }
 
+fn convert_custom_message(received: ReceivedCustomMessage) -> CustomOnionMessage {
+    CustomOnionMessage {
+        tlv_type: received.message.tlv_type,
+        data: hex::encode(received.message.data),
+        reply_token: received
+            .responder
+            .map(|responder| hex::encode(encode_reply_token(&responder)))
+            .unwrap_or_default(),
+    }
+}
+
 fn convert_public_key(native_pub_key: &PublicKey) -> lndkrpc::PublicKey {
     let pub_key_bytes = native_pub_key.encode();
     lndkrpc::PublicKey { key: pub_key_bytes }
 }
 
-fn convert_invoice_features(_features: impl std::fmt::Debug) -> Vec<i32> {
-    vec![FeatureBit::MppOpt as i32]
+// Returns the position of every bit set in a little-endian feature bitmap.
+fn feature_bits(le_flags: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    le_flags.iter().enumerate().flat_map(|(i, byte)| {
+        (0..8)
+            .filter(move |bit| byte & (1 << bit) != 0)
+            .map(move |bit| (i * 8 + bit) as u32)
+    })
+}
+
+// Returns the feature bits set that we have a FeatureBit for.
+fn known_feature_bits(le_flags: &[u8]) -> Vec<i32> {
+    feature_bits(le_flags)
+        .map(|bit| bit as i32)
+        .filter(|bit| FeatureBit::try_from(*bit).is_ok())
+        .collect()
+}
+
+// Returns the feature bits set that we don't have a FeatureBit for, so that callers can still
+// see them.
+fn unknown_feature_bits(le_flags: &[u8]) -> Vec<u32> {
+    feature_bits(le_flags)
+        .filter(|bit| FeatureBit::try_from(*bit as i32).is_err())
+        .collect()
 }
 
 fn convert_blinded_pay_info(
@@ -523,12 +1284,26 @@ fn convert_blinded_pay_info(
         cltv_expiry_delta: native_info.cltv_expiry_delta as u32,
         htlc_minimum_msat: native_info.htlc_minimum_msat,
         htlc_maximum_msat: native_info.htlc_maximum_msat,
-        ..Default::default()
+        features: known_feature_bits(native_info.features.le_flags()),
+        unknown_features: unknown_feature_bits(native_info.features.le_flags()),
     }
 }
 
 fn convert_blinded_path(native_info: &BlindedPaymentPath) -> lndkrpc::BlindedPath {
-    let introduction_node = match native_info.introduction_node() {
+    convert_blinded_path_parts(
+        native_info.introduction_node(),
+        native_info.blinding_point(),
+        native_info.blinded_hops(),
+    )
+}
+
+// Payment and message paths are different types, but they share the same structure.
+fn convert_blinded_path_parts(
+    introduction_node: &IntroductionNode,
+    blinding_point: PublicKey,
+    blinded_hops: &[BlindedHop],
+) -> lndkrpc::BlindedPath {
+    let introduction_node = match introduction_node {
         IntroductionNode::NodeId(pubkey) => lndkrpc::IntroductionNode {
             node_id: Some(convert_public_key(pubkey)),
             directed_short_channel_id: None,
@@ -551,9 +1326,8 @@ fn convert_blinded_path(native_info: &BlindedPaymentPath) -> lndkrpc::BlindedPat
 
     lndkrpc::BlindedPath {
         introduction_node: Some(introduction_node),
-        blinding_point: Some(convert_public_key(&native_info.blinding_point())),
-        blinded_hops: native_info
-            .blinded_hops()
+        blinding_point: Some(convert_public_key(&blinding_point)),
+        blinded_hops: blinded_hops
             .iter()
             .map(|hop| lndkrpc::BlindedHop {
                 blinded_node_id: Some(convert_public_key(&hop.blinded_node_id)),
@@ -566,22 +1340,250 @@ fn convert_blinded_path(native_info: &BlindedPaymentPath) -> lndkrpc::BlindedPat
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::policy::PolicyCfg;
+    use crate::tests::test_utils::{
+        build_invoice, build_invoice_request, build_offer, pubkey, recipient_keys,
+    };
+    use bitcoin::constants::ChainHash;
+    use std::path::Path;
+
+    fn metadata_with_macaroon(macaroon: &str) -> MetadataMap {
+        let mut metadata = MetadataMap::new();
+        metadata.insert("macaroon", macaroon.parse().unwrap());
+        metadata
+    }
+
+    // Builds a server with the policy provided, whose LND pool can't connect to anything.
+    async fn test_server(dir: &Path, policy: PolicyCfg) -> LNDKServer {
+        LNDKServer::new(
+            Arc::new(OfferHandler::default()),
+            &pubkey(0).to_string(),
+            Network::Regtest,
+            LndClientPool::new("https://127.0.0.1:1".to_string(), String::new()),
+            String::new(),
+            Arc::new(MacaroonAuth::new([1; 32])),
+            PaymentPolicy::new(policy),
+            PaymentStore::open(&dir.join("payments.json")).unwrap(),
+            Arc::new(CustomMessenger::new()),
+            Arc::new(SeedStore::open(&dir.join("seeds.json"), Duration::from_secs(60)).unwrap()),
+        )
+        .await
+    }
+
+    fn with_macaroon<T>(message: T, permission: Permission) -> Request<T> {
+        let mut request = Request::new(message);
+        let macaroon = MacaroonAuth::new([1; 32]).bake(&[permission]).to_hex();
+        request
+            .metadata_mut()
+            .insert("macaroon", macaroon.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn test_policy_refuses_before_contacting_lnd() {
+        let dir = tempfile::tempdir().unwrap();
+        let policy = PolicyCfg {
+            denied_issuers: vec![recipient_keys().public_key()],
+            ..Default::default()
+        };
+        let server = test_server(dir.path(), policy).await;
+
+        // We refuse to pay a denied issuer's offer before we connect to LND, let alone send an
+        // invoice request.
+        let offer = build_offer(20_000);
+        let status = server
+            .pay_offer(with_macaroon(
+                PayOfferRequest {
+                    offer: offer.to_string(),
+                    amount: Some(20_000),
+                    ..Default::default()
+                },
+                Permission::Pay,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+        // We didn't fetch this invoice, so it's checked against the key it's signed with.
+        let invoice = build_invoice(&build_invoice_request(&offer, 20_000));
+        let status = server
+            .pay_invoice(with_macaroon(
+                PayInvoiceRequest {
+                    invoice: hex::encode(invoice.encode()),
+                    ..Default::default()
+                },
+                Permission::Pay,
+            ))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert!(server.lnd_clients.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscriber_stream_ends() {
+        use crate::custom_messages::SUBSCRIBER_BUFFER;
+        use futures::StreamExt;
+        use lightning::onion_message::messenger::CustomOnionMessageHandler;
+
+        let dir = tempfile::tempdir().unwrap();
+        let server = test_server(dir.path(), PolicyCfg::default()).await;
+        let mut stream = server
+            .subscribe_custom_onion_messages(with_macaroon(
+                SubscribeCustomOnionMessagesRequest::default(),
+                Permission::Messages,
+            ))
+            .await
+            .unwrap()
+            .into_inner();
+
+        // Receive more messages than we buffer before the client reads any of them.
+        for _ in 0..=SUBSCRIBER_BUFFER {
+            let message = CustomMessage {
+                tlv_type: 101,
+                data: vec![1],
+            };
+            server
+                .custom_messenger
+                .handle_custom_message(message, None, None);
+        }
+
+        let status = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DataLoss);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[test]
+    fn test_authorize_lndk_macaroon() {
+        let auth = MacaroonAuth::new([1; 32]);
+        let pay = auth.bake(&[Permission::Pay]).to_hex();
+        assert!(
+            authorize_lndk_macaroon(&auth, &metadata_with_macaroon(&pay), Permission::Pay).is_ok()
+        );
+
+        // Anything that isn't an lndk macaroon can't be checked, since LND never sees it.
+        let status =
+            authorize_lndk_macaroon(&auth, &metadata_with_macaroon("0201abcd"), Permission::Pay)
+                .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        // Nor can an lndk macaroon baked with another root key.
+        let other = MacaroonAuth::new([2; 32]).bake(&[Permission::Pay]).to_hex();
+        let status =
+            authorize_lndk_macaroon(&auth, &metadata_with_macaroon(&other), Permission::Pay)
+                .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+    }
+
+    #[test]
+    fn test_authorize_custom_messages() {
+        let auth = MacaroonAuth::new([1; 32]);
+        let messages = auth.bake(&[Permission::Messages]).to_hex();
+        let metadata = metadata_with_macaroon(&messages);
+        assert!(authorize_lndk_macaroon(&auth, &metadata, Permission::Messages).is_ok());
+
+        // A junk macaroon would otherwise let anyone send and read onion messages as our node.
+        let metadata = metadata_with_macaroon("junk");
+        let status = authorize_lndk_macaroon(&auth, &metadata, Permission::Messages).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::Unauthenticated);
+
+        let pay = auth.bake(&[Permission::Pay]).to_hex();
+        let metadata = metadata_with_macaroon(&pay);
+        let status = authorize_lndk_macaroon(&auth, &metadata, Permission::Messages).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    }
+
+    #[test]
+    fn test_parse_human_readable_name() {
+        for encoded in ["alice@example.com", "₿alice@example.com"] {
+            let name = parse_human_readable_name(encoded).unwrap();
+            assert_eq!(name.user(), "alice");
+            assert_eq!(name.domain(), "example.com");
+        }
+
+        assert!(parse_human_readable_name("example.com").is_err());
+        assert!(parse_human_readable_name("").is_err());
+    }
+
+    #[test]
+    fn test_settle_time() {
+        let htlc = |status: HtlcStatus, resolve_time_ns| tonic_lnd::lnrpc::HtlcAttempt {
+            status: status as i32,
+            resolve_time_ns,
+            ..Default::default()
+        };
+
+        // A failed attempt that resolved after the successful one doesn't count.
+        let payment = Payment {
+            htlcs: vec![
+                htlc(HtlcStatus::Succeeded, 2_000),
+                htlc(HtlcStatus::Failed, 3_000),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(settle_time_ns(&payment), 2_000);
+
+        let payment = Payment {
+            htlcs: vec![htlc(HtlcStatus::Failed, 1_000)],
+            ..Default::default()
+        };
+        assert_eq!(settle_time_ns(&payment), 0);
+    }
+
+    #[test]
+    fn test_convert_features() {
+        // basic_mpp optional (17), route blinding required (24) and an unassigned odd bit (101).
+        let mut le_flags = vec![0; 13];
+        le_flags[2] |= 1 << 1;
+        le_flags[3] |= 1 << 0;
+        le_flags[12] |= 1 << 5;
+
+        assert_eq!(
+            known_feature_bits(&le_flags),
+            vec![
+                FeatureBit::MppOpt as i32,
+                FeatureBit::RouteBlindingRequired as i32
+            ]
+        );
+        assert_eq!(unknown_feature_bits(&le_flags), vec![101]);
+    }
+
+    #[test]
+    fn test_convert_no_features() {
+        assert!(known_feature_bits(&[]).is_empty());
+        assert!(unknown_feature_bits(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_generate_offer_contents() {
+        let offer = build_offer(20_000);
+        let contents = generate_offer_contents(&offer);
+
+        assert_eq!(
+            contents.chains,
+            vec![ChainHash::using_genesis_block(Network::Regtest).to_string()]
+        );
+        assert_eq!(contents.amount_msats, Some(20_000));
+        assert!(contents.currency_amount.is_none());
+        assert_eq!(contents.description, Some("coffee".to_string()));
+        assert_eq!(contents.min_quantity, 1);
+        assert_eq!(contents.max_quantity, Some(1));
+        assert_eq!(
+            contents.issuer_signing_pubkey,
+            Some(convert_public_key(&recipient_keys().public_key()))
+        );
+        assert!(contents.paths.is_empty());
+    }
 
     #[test]
-    fn test_collect_tls_ips() {
-        // Test that it returns a vector of one element if only one ip is provided.
-        let tls_ips_str = Some("192.168.0.1".to_string());
-        let tls_ips = collect_tls_ips(tls_ips_str);
-        assert!(tls_ips.is_some());
-        assert!(tls_ips.as_ref().unwrap().len() == 1);
-
-        // If no ip is provided, collect_tls_ips should return None.
-        assert!(collect_tls_ips(None).is_none());
-
-        // If two ips are provided, a vector of length two should be returned.
-        let tls_ips_str = Some("192.168.0.1,192.168.0.3".to_string());
-        let tls_ips = collect_tls_ips(tls_ips_str);
-        assert!(tls_ips.is_some());
-        assert!(tls_ips.as_ref().unwrap().len() == 2);
+    fn test_offer_contents_json() {
+        let contents = generate_offer_contents(&build_offer(20_000));
+        let json = serde_json::to_value(&contents).unwrap();
+
+        // Keys are encoded as hex rather than as an array of bytes.
+        assert_eq!(
+            json["issuer_signing_pubkey"]["key"],
+            recipient_keys().public_key().to_string()
+        );
     }
 }
@@ -0,0 +1,732 @@
+use crate::offers::EncodedPaymentProof;
+use crate::tls::write_private_file;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long we remember payments made with an idempotency key once they're done, and keep the
+// proofs of offers we've paid. A client that retries with the same key after this may pay again.
+const PAYMENT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// The most unpaid proofs we hold on to. Every GetInvoice call adds one, so once we hit this we
+// drop the ones that expire first.
+const MAX_UNPAID_PROOFS: usize = 1000;
+
+/// The state of a payment tracked by the PaymentStore.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PaymentStatus {
+    /// The payment has been started and we haven't heard back yet.
+    InFlight,
+    /// The payment succeeded.
+    Succeeded,
+    /// The payment failed.
+    Failed,
+    /// The payment was in flight when lndk shut down, so we don't know how it ended. Its outcome
+    /// can be found by looking up the payment in LND.
+    Unknown,
+}
+
+impl Display for PaymentStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PaymentStatus::InFlight => write!(f, "in flight"),
+            PaymentStatus::Succeeded => write!(f, "succeeded"),
+            PaymentStatus::Failed => write!(f, "failed"),
+            PaymentStatus::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+/// PaymentRecord is what we persist about a payment made with an idempotency key.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PaymentRecord {
+    /// Identifies the request that created the payment (eg, the offer and amount), so that we can
+    /// catch a key that's reused for a different payment.
+    pub request: String,
+    pub status: PaymentStatus,
+    pub payment_hash: Option<String>,
+    pub payment_preimage: Option<String>,
+    pub error: Option<String>,
+    /// What we told the client when the payment succeeded, so that a retried request gets the
+    /// same response. Payments recorded by older versions of lndk don't have this.
+    #[serde(default)]
+    pub details: Option<PaymentDetails>,
+    /// When the payment was last updated, in seconds since the unix epoch.
+    #[serde(default)]
+    pub updated_at: u64,
+}
+
+/// PaymentDetails are the details of a successful payment that PayOffer and PayInvoice return.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PaymentDetails {
+    pub payment_preimage: String,
+    pub payment_hash: String,
+    pub amount_msats: u64,
+    pub fee_msats: u64,
+    pub attempts: u32,
+    pub creation_time_ns: i64,
+    pub settle_time_ns: i64,
+    pub invoice: String,
+    pub payment_id: String,
+}
+
+/// An error that occurs when a payment can't be started because of a previous one.
+#[derive(Debug, PartialEq)]
+pub enum StoreError {
+    /// A payment was already made with this idempotency key.
+    Duplicate(PaymentRecord),
+    /// The idempotency key was already used for a different request.
+    KeyReused,
+    /// The invoice with this payment hash is already being paid, or has been paid.
+    InvoiceAlreadyPaid(PaymentStatus),
+    /// We couldn't write the store to disk.
+    Persist(String),
+}
+
+impl Display for StoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StoreError::Duplicate(record) => write!(
+                f,
+                "A payment with this idempotency key already exists and is {}",
+                record.status
+            ),
+            StoreError::KeyReused => write!(
+                f,
+                "This idempotency key was already used for a different payment"
+            ),
+            StoreError::InvoiceAlreadyPaid(status) => {
+                write!(
+                    f,
+                    "A payment for this invoice already exists and is {status}"
+                )
+            }
+            StoreError::Persist(e) => write!(f, "Error persisting payment store: {e}"),
+        }
+    }
+}
+
+impl Error for StoreError {}
+
+// The status of an invoice payment, which we keep until the invoice expires. After that it can't
+// be paid again anyway.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct InvoiceRecord {
+    status: PaymentStatus,
+    /// When the invoice expires, in seconds since the unix epoch.
+    expires_at: u64,
+}
+
+// A proof that we paid an offer, along with when we saved it.
+#[derive(Clone, Serialize, Deserialize)]
+struct ProofRecord {
+    #[serde(flatten)]
+    proof: EncodedPaymentProof,
+    /// When the proof was saved, in seconds since the unix epoch.
+    #[serde(default)]
+    saved_at: u64,
+}
+
+// An unpaid proof, along with when its invoice expires.
+struct UnpaidProof {
+    proof: EncodedPaymentProof,
+    expires_at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoreData {
+    // Payments keyed by the idempotency key the client provided.
+    payments: HashMap<String, PaymentRecord>,
+    // Invoice payments, keyed by hex-encoded payment hash.
+    invoices: HashMap<String, InvoiceRecord>,
+    // Proofs that we paid offers, keyed by hex-encoded payment hash.
+    #[serde(default)]
+    proofs: HashMap<String, ProofRecord>,
+    // Proofs for invoices that we fetched for an offer but haven't paid yet, which are missing
+    // their preimage. They're only kept in memory, so an invoice has to be paid by the same lndk
+    // process that fetched it for us to be able to prove the payment.
+    #[serde(skip)]
+    unpaid_proofs: HashMap<String, UnpaidProof>,
+}
+
+impl StoreData {
+    // Forgets payments that finished and proofs that were saved more than PAYMENT_RETENTION ago,
+    // and invoices and unpaid proofs that have expired. Payments that are still in flight are
+    // always kept.
+    fn prune(&mut self, now: u64) {
+        let retain_after = now.saturating_sub(PAYMENT_RETENTION.as_secs());
+        self.payments.retain(|_, record| {
+            record.status == PaymentStatus::InFlight || record.updated_at > retain_after
+        });
+        self.invoices.retain(|_, record| {
+            record.status == PaymentStatus::InFlight || record.expires_at > now
+        });
+        self.proofs
+            .retain(|_, record| record.saved_at > retain_after);
+        self.unpaid_proofs
+            .retain(|_, unpaid| unpaid.expires_at > now);
+    }
+}
+
+/// PaymentStore persists payments made with an idempotency key, and the invoices we've paid, so
+/// that retried requests don't end up paying twice. It's stored as a JSON file in lndk's data
+/// directory, and rewritten in full on every update. Finished payments, old proofs and expired
+/// invoices are pruned, so that it stays small. Since it holds payment preimages, the file is
+/// only readable by the current user.
+pub struct PaymentStore {
+    path: PathBuf,
+    data: Mutex<StoreData>,
+    // Counts updates to the store, so that a write that's overtaken by a later one doesn't
+    // replace the newer contents on disk.
+    version: AtomicU64,
+    // The version of the store that was last written to disk.
+    written: Arc<Mutex<u64>>,
+}
+
+impl PaymentStore {
+    /// Opens the store at the path provided, creating it if it doesn't exist. Any payments that
+    /// were in flight when we last shut down are marked as unknown.
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let mut data: StoreData = if path.exists() {
+            let contents = fs::read_to_string(path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            StoreData::default()
+        };
+
+        let now = now();
+        for record in data.payments.values_mut() {
+            if record.status == PaymentStatus::InFlight {
+                record.status = PaymentStatus::Unknown;
+            }
+            // Payments recorded by older versions of lndk are kept for a full retention period.
+            if record.updated_at == 0 {
+                record.updated_at = now;
+            }
+        }
+        for record in data.invoices.values_mut() {
+            if record.status == PaymentStatus::InFlight {
+                record.status = PaymentStatus::Unknown;
+            }
+        }
+        // Proofs saved by older versions of lndk are also kept for a full retention period.
+        for record in data.proofs.values_mut() {
+            if record.saved_at == 0 {
+                record.saved_at = now;
+            }
+        }
+        data.prune(now);
+
+        write_store(path, &serialize(&data)?)?;
+        Ok(PaymentStore {
+            path: path.to_path_buf(),
+            data: Mutex::new(data),
+            version: AtomicU64::new(0),
+            written: Arc::new(Mutex::new(0)),
+        })
+    }
+
+    /// Records a new in-flight payment for the idempotency key provided. If the key was already
+    /// used we return the existing payment instead, so the caller doesn't pay again.
+    pub async fn begin(&self, key: &str, request: &str) -> Result<(), StoreError> {
+        let snapshot = {
+            let now = now();
+            let mut data = self.data.lock().unwrap();
+            data.prune(now);
+            if let Some(record) = data.payments.get(key) {
+                if record.request != request {
+                    return Err(StoreError::KeyReused);
+                }
+                return Err(StoreError::Duplicate(record.clone()));
+            }
+
+            data.payments.insert(
+                key.to_string(),
+                PaymentRecord {
+                    request: request.to_string(),
+                    status: PaymentStatus::InFlight,
+                    payment_hash: None,
+                    payment_preimage: None,
+                    error: None,
+                    details: None,
+                    updated_at: now,
+                },
+            );
+            self.snapshot(&data)
+        };
+        self.persist(snapshot)
+            .await
+            .map_err(|e| StoreError::Persist(e.to_string()))
+    }
+
+    /// Removes a payment that was rejected before we attempted it, so that the key can be used
+    /// again.
+    pub async fn abandon(&self, key: &str) {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            if data.payments.remove(key).is_none() {
+                return;
+            }
+            self.snapshot(&data)
+        };
+        self.persist_or_log(snapshot).await;
+    }
+
+    /// Records the outcome of a payment started with begin.
+    pub async fn complete(
+        &self,
+        key: &str,
+        payment_hash: Option<String>,
+        result: Result<PaymentDetails, String>,
+    ) {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            if let Some(record) = data.payments.get_mut(key) {
+                record.payment_hash = payment_hash;
+                record.updated_at = now();
+                match result {
+                    Ok(details) => {
+                        record.status = PaymentStatus::Succeeded;
+                        record.payment_preimage = Some(details.payment_preimage.clone());
+                        record.details = Some(details);
+                    }
+                    Err(e) => {
+                        record.status = PaymentStatus::Failed;
+                        record.error = Some(e);
+                    }
+                }
+            }
+            self.snapshot(&data)
+        };
+        self.persist_or_log(snapshot).await;
+    }
+
+    /// Records that we're about to pay the invoice with the payment hash provided, which expires
+    /// at the unix time provided. Invoices that are in flight or already paid are refused, but a
+    /// failed payment may be retried.
+    pub async fn begin_invoice(
+        &self,
+        payment_hash: &str,
+        expires_at: u64,
+    ) -> Result<(), StoreError> {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            data.prune(now());
+            match data.invoices.get(payment_hash).map(|record| &record.status) {
+                None | Some(PaymentStatus::Failed) => {}
+                Some(status) => return Err(StoreError::InvoiceAlreadyPaid(status.clone())),
+            }
+
+            data.invoices.insert(
+                payment_hash.to_string(),
+                InvoiceRecord {
+                    status: PaymentStatus::InFlight,
+                    expires_at,
+                },
+            );
+            self.snapshot(&data)
+        };
+        self.persist(snapshot)
+            .await
+            .map_err(|e| StoreError::Persist(e.to_string()))
+    }
+
+    /// Records the outcome of an invoice payment started with begin_invoice.
+    pub async fn complete_invoice(&self, payment_hash: &str, succeeded: bool) {
+        let status = if succeeded {
+            PaymentStatus::Succeeded
+        } else {
+            PaymentStatus::Failed
+        };
+
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            if let Some(record) = data.invoices.get_mut(payment_hash) {
+                record.status = status;
+            }
+            self.snapshot(&data)
+        };
+        self.persist_or_log(snapshot).await;
+    }
+
+    /// Records the proof that we paid an offer. It's kept for PAYMENT_RETENTION.
+    pub async fn save_proof(&self, payment_hash: &str, proof: EncodedPaymentProof) {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            data.unpaid_proofs.remove(payment_hash);
+            data.proofs.insert(
+                payment_hash.to_string(),
+                ProofRecord {
+                    proof,
+                    saved_at: now(),
+                },
+            );
+            self.snapshot(&data)
+        };
+        self.persist_or_log(snapshot).await;
+    }
+
+    /// Holds on to the offer and invoice request that an invoice was fetched with, so that we
+    /// can prove the payment if the invoice is paid before it expires, at the unix time provided.
+    pub fn save_unpaid_proof(
+        &self,
+        payment_hash: &str,
+        proof: EncodedPaymentProof,
+        expires_at: u64,
+    ) {
+        let mut data = self.data.lock().unwrap();
+        data.prune(now());
+        while data.unpaid_proofs.len() >= MAX_UNPAID_PROOFS {
+            let first_expiry = data
+                .unpaid_proofs
+                .iter()
+                .min_by_key(|(_, unpaid)| unpaid.expires_at)
+                .map(|(payment_hash, _)| payment_hash.clone());
+            match first_expiry {
+                Some(payment_hash) => data.unpaid_proofs.remove(&payment_hash),
+                None => break,
+            };
+        }
+        data.unpaid_proofs
+            .insert(payment_hash.to_string(), UnpaidProof { proof, expires_at });
+    }
+
+    /// Returns the offer that the invoice with the payment hash provided was fetched for, if we
+    /// fetched it and it hasn't been paid yet.
+    pub fn fetched_offer(&self, payment_hash: &str) -> Option<String> {
+        let data = self.data.lock().unwrap();
+        data.unpaid_proofs
+            .get(payment_hash)
+            .map(|unpaid| unpaid.proof.offer.clone())
+    }
+
+    /// Completes the proof for an invoice saved with save_unpaid_proof, once it has been paid.
+    /// Invoices that weren't fetched for an offer don't have a proof, and are ignored.
+    pub async fn complete_proof(&self, payment_hash: &str, payment_preimage: &str) {
+        let unpaid = self.data.lock().unwrap().unpaid_proofs.remove(payment_hash);
+        if let Some(UnpaidProof { proof, .. }) = unpaid {
+            self.save_proof(
+                payment_hash,
+                EncodedPaymentProof {
+                    payment_preimage: payment_preimage.to_string(),
+                    ..proof
+                },
+            )
+            .await;
+        }
+    }
+
+    /// Returns the proof that we paid the invoice with the payment hash provided, if we have one.
+    pub fn proof(&self, payment_hash: &str) -> Option<EncodedPaymentProof> {
+        self.data
+            .lock()
+            .unwrap()
+            .proofs
+            .get(payment_hash)
+            .map(|record| record.proof.clone())
+    }
+
+    // Serializes the store so that it can be written once we've released the lock. Must be
+    // called with the lock held, so that versions are handed out in the order of updates.
+    fn snapshot(&self, data: &StoreData) -> Result<(u64, String), std::io::Error> {
+        let version = self.version.fetch_add(1, Ordering::SeqCst) + 1;
+        Ok((version, serialize(data)?))
+    }
+
+    // Writes a snapshot of the store to disk on the blocking thread pool, so that we don't hold up
+    // the runtime. Snapshots that are older than the one already on disk are skipped.
+    async fn persist(
+        &self,
+        snapshot: Result<(u64, String), std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        let (version, contents) = snapshot?;
+        let path = self.path.clone();
+        let written = self.written.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut written = written.lock().unwrap();
+            if version <= *written {
+                return Ok(());
+            }
+            write_store(&path, &contents)?;
+            *written = version;
+            Ok(())
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    async fn persist_or_log(&self, snapshot: Result<(u64, String), std::io::Error>) {
+        if let Err(e) = self.persist(snapshot).await {
+            log::error!("Error persisting payment store: {e}");
+        }
+    }
+}
+
+fn serialize(data: &StoreData) -> Result<String, std::io::Error> {
+    serde_json::to_string(data).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+// Writes the store to a temporary file and moves it into place, so that we never leave a
+// partially written store behind.
+fn write_store(path: &Path, contents: &str) -> Result<(), std::io::Error> {
+    let tmp_path = path.with_extension("tmp");
+    // A temporary file left behind by an older version of lndk may be readable by others, and its
+    // permissions would be kept when we open it.
+    if tmp_path.exists() {
+        fs::remove_file(&tmp_path)?;
+    }
+    write_private_file(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // An expiry time for invoices that are still payable.
+    fn expiry() -> u64 {
+        now() + 3600
+    }
+
+    #[tokio::test]
+    async fn test_idempotent_payment() {
+        let dir = tempdir().unwrap();
+        let store = PaymentStore::open(&dir.path().join("payments.json")).unwrap();
+
+        assert!(store.begin("key", "offer:1000").await.is_ok());
+        match store.begin("key", "offer:1000").await {
+            Err(StoreError::Duplicate(record)) => {
+                assert_eq!(record.status, PaymentStatus::InFlight)
+            }
+            _ => panic!("expected duplicate payment"),
+        }
+        assert_eq!(
+            store.begin("key", "offer:2000").await,
+            Err(StoreError::KeyReused)
+        );
+
+        let details = PaymentDetails {
+            payment_preimage: "preimage".to_string(),
+            payment_hash: "hash".to_string(),
+            amount_msats: 1000,
+            fee_msats: 5,
+            payment_id: "id".to_string(),
+            ..Default::default()
+        };
+        store
+            .complete("key", Some("hash".to_string()), Ok(details.clone()))
+            .await;
+        match store.begin("key", "offer:1000").await {
+            Err(StoreError::Duplicate(record)) => {
+                assert_eq!(record.status, PaymentStatus::Succeeded);
+                assert_eq!(record.payment_preimage, Some("preimage".to_string()));
+                assert_eq!(record.details, Some(details));
+            }
+            _ => panic!("expected duplicate payment"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_abandon_payment() {
+        let dir = tempdir().unwrap();
+        let store = PaymentStore::open(&dir.path().join("payments.json")).unwrap();
+
+        assert!(store.begin("key", "offer:1000").await.is_ok());
+        store.abandon("key").await;
+        assert!(store.begin("key", "offer:1000").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_invoice_paid_once() {
+        let dir = tempdir().unwrap();
+        let store = PaymentStore::open(&dir.path().join("payments.json")).unwrap();
+
+        assert!(store.begin_invoice("hash", expiry()).await.is_ok());
+        assert_eq!(
+            store.begin_invoice("hash", expiry()).await,
+            Err(StoreError::InvoiceAlreadyPaid(PaymentStatus::InFlight))
+        );
+
+        // A failed payment may be retried, but not a successful one.
+        store.complete_invoice("hash", false).await;
+        assert!(store.begin_invoice("hash", expiry()).await.is_ok());
+        store.complete_invoice("hash", true).await;
+        assert_eq!(
+            store.begin_invoice("hash", expiry()).await,
+            Err(StoreError::InvoiceAlreadyPaid(PaymentStatus::Succeeded))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_payment_proofs() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("payments.json");
+        let proof = EncodedPaymentProof {
+            offer: "offer".to_string(),
+            invoice_request: "invoice_request".to_string(),
+            invoice: "invoice".to_string(),
+            payment_preimage: String::new(),
+        };
+        {
+            let store = PaymentStore::open(&path).unwrap();
+            store.save_unpaid_proof("paid", proof.clone(), expiry());
+            store.save_unpaid_proof("unpaid", proof.clone(), expiry());
+            assert_eq!(store.proof("paid"), None);
+            assert_eq!(store.fetched_offer("paid"), Some("offer".to_string()));
+            assert_eq!(store.fetched_offer("other"), None);
+
+            store.complete_proof("paid", "preimage").await;
+            assert_eq!(
+                store.proof("paid").map(|proof| proof.payment_preimage),
+                Some("preimage".to_string())
+            );
+
+            // Invoices without a saved proof are ignored.
+            store.complete_proof("other", "preimage").await;
+            assert_eq!(store.proof("other"), None);
+        }
+
+        // Only proofs for paid invoices survive a restart.
+        let store = PaymentStore::open(&path).unwrap();
+        assert!(store.proof("paid").is_some());
+        store.complete_proof("unpaid", "preimage").await;
+        assert_eq!(store.proof("unpaid"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reopen_store() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("payments.json");
+        {
+            let store = PaymentStore::open(&path).unwrap();
+            store.begin("done", "offer:1000").await.unwrap();
+            store
+                .complete(
+                    "done",
+                    None,
+                    Ok(PaymentDetails {
+                        payment_preimage: "preimage".to_string(),
+                        ..Default::default()
+                    }),
+                )
+                .await;
+            store.begin("pending", "offer:1000").await.unwrap();
+            store.begin_invoice("hash", expiry()).await.unwrap();
+        }
+
+        // Payments that were in flight when we shut down have an unknown outcome.
+        let store = PaymentStore::open(&path).unwrap();
+        match store.begin("done", "offer:1000").await {
+            Err(StoreError::Duplicate(record)) => {
+                assert_eq!(record.status, PaymentStatus::Succeeded)
+            }
+            _ => panic!("expected duplicate payment"),
+        }
+        match store.begin("pending", "offer:1000").await {
+            Err(StoreError::Duplicate(record)) => {
+                assert_eq!(record.status, PaymentStatus::Unknown)
+            }
+            _ => panic!("expected duplicate payment"),
+        }
+        assert_eq!(
+            store.begin_invoice("hash", expiry()).await,
+            Err(StoreError::InvoiceAlreadyPaid(PaymentStatus::Unknown))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_prune_store() {
+        let dir = tempdir().unwrap();
+        let store = PaymentStore::open(&dir.path().join("payments.json")).unwrap();
+        let now = now();
+
+        store.begin("old", "offer:1000").await.unwrap();
+        store.complete("old", None, Err("failed".to_string())).await;
+        store.begin("pending", "offer:1000").await.unwrap();
+        store.begin_invoice("expired", now - 1).await.unwrap();
+        store.complete_invoice("expired", true).await;
+        store.begin_invoice("in flight", now - 1).await.unwrap();
+        store.save_unpaid_proof("expired", EncodedPaymentProof::default(), now - 1);
+        store
+            .save_proof("old", EncodedPaymentProof::default())
+            .await;
+        store
+            .save_proof("recent", EncodedPaymentProof::default())
+            .await;
+
+        let mut data = store.data.lock().unwrap();
+        data.payments.get_mut("old").unwrap().updated_at = now - PAYMENT_RETENTION.as_secs() - 1;
+        data.proofs.get_mut("old").unwrap().saved_at = now - PAYMENT_RETENTION.as_secs() - 1;
+        data.prune(now);
+
+        // Payments and invoices that are still in flight are never forgotten.
+        assert!(!data.payments.contains_key("old"));
+        assert!(data.payments.contains_key("pending"));
+        assert!(!data.invoices.contains_key("expired"));
+        assert!(data.invoices.contains_key("in flight"));
+        assert!(data.unpaid_proofs.is_empty());
+        assert!(!data.proofs.contains_key("old"));
+        assert!(data.proofs.contains_key("recent"));
+    }
+
+    #[tokio::test]
+    async fn test_store_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("payments.json");
+        // Stores written by older versions of lndk may be readable by others.
+        fs::write(&path, "{\"payments\":{},\"invoices\":{}}").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let store = PaymentStore::open(&path).unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+
+        store
+            .save_proof("hash", EncodedPaymentProof::default())
+            .await;
+        assert_eq!(mode(&path), 0o600);
+        assert!(PaymentStore::open(&path).unwrap().proof("hash").is_some());
+    }
+
+    #[test]
+    fn test_unpaid_proofs_capped() {
+        let dir = tempdir().unwrap();
+        let store = PaymentStore::open(&dir.path().join("payments.json")).unwrap();
+        let expiry = expiry();
+
+        for i in 0..MAX_UNPAID_PROOFS {
+            let proof = EncodedPaymentProof {
+                offer: i.to_string(),
+                ..Default::default()
+            };
+            store.save_unpaid_proof(&i.to_string(), proof, expiry + i as u64);
+        }
+        store.save_unpaid_proof("new", EncodedPaymentProof::default(), expiry);
+
+        // The proof that expires first makes way for the new one.
+        assert_eq!(store.fetched_offer("0"), None);
+        assert_eq!(store.fetched_offer("1"), Some("1".to_string()));
+        assert!(store.fetched_offer("new").is_some());
+        assert_eq!(
+            store.data.lock().unwrap().unpaid_proofs.len(),
+            MAX_UNPAID_PROOFS
+        );
+    }
+}
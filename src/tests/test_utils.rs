@@ -1,4 +1,21 @@
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::key::Keypair;
 use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::Network;
+use lightning::blinded_path::payment::{BlindedPayInfo, BlindedPaymentPath};
+use lightning::blinded_path::BlindedHop;
+use lightning::ln::channelmanager::PaymentId;
+use lightning::ln::inbound_payment::ExpandedKey;
+use lightning::offers::invoice::{Bolt12Invoice, UnsignedBolt12Invoice};
+use lightning::offers::invoice_request::InvoiceRequest;
+use lightning::offers::nonce::Nonce;
+use lightning::offers::offer::{Offer, OfferBuilder};
+use lightning::types::features::BlindedHopFeatures;
+use lightning::types::payment::{PaymentHash, PaymentPreimage};
+use std::time::{Duration, SystemTime};
+
+/// The preimage for the payment hash of invoices built by these helpers.
+pub const PAYMENT_PREIMAGE: PaymentPreimage = PaymentPreimage([2; 32]);
 
 pub fn pubkey(byte: u8) -> PublicKey {
     let secp_ctx = Secp256k1::new();
@@ -8,3 +25,75 @@ pub fn pubkey(byte: u8) -> PublicKey {
 pub fn privkey(byte: u8) -> SecretKey {
     SecretKey::from_slice(&[byte; 32]).unwrap()
 }
+
+/// The keys that offers and invoices built by these helpers are signed with.
+pub fn recipient_keys() -> Keypair {
+    Keypair::from_secret_key(&Secp256k1::new(), &privkey(1))
+}
+
+/// Builds a regtest offer for the amount provided, signed by recipient_keys.
+pub fn build_offer(amount_msats: u64) -> Offer {
+    OfferBuilder::new(recipient_keys().public_key())
+        .chain(Network::Regtest)
+        .amount_msats(amount_msats)
+        .description("coffee".to_string())
+        .build()
+        .unwrap()
+}
+
+/// Builds an invoice request for the offer provided, for the amount provided.
+pub fn build_invoice_request(offer: &Offer, amount_msats: u64) -> InvoiceRequest {
+    let expanded_key = ExpandedKey::new([42; 32]);
+    let nonce = Nonce::try_from(&[42u8; 16][..]).unwrap();
+    offer
+        .request_invoice(&expanded_key, nonce, &Secp256k1::new(), PaymentId([42; 32]))
+        .unwrap()
+        .amount_msats(amount_msats)
+        .unwrap()
+        .build_and_sign()
+        .unwrap()
+}
+
+/// Builds a payment path with a single blinded hop.
+pub fn build_payment_path() -> BlindedPaymentPath {
+    BlindedPaymentPath::from_blinded_path_and_payinfo(
+        pubkey(10),
+        pubkey(11),
+        vec![BlindedHop {
+            blinded_node_id: pubkey(12),
+            encrypted_payload: vec![1, 2, 3],
+        }],
+        BlindedPayInfo {
+            fee_base_msat: 1_000,
+            fee_proportional_millionths: 100,
+            cltv_expiry_delta: 144,
+            htlc_minimum_msat: 1,
+            htlc_maximum_msat: 1_000_000_000,
+            features: BlindedHopFeatures::empty(),
+        },
+    )
+}
+
+/// Builds an invoice responding to the invoice request provided, created at the time provided
+/// (as a duration since the unix epoch) and signed by recipient_keys.
+pub fn build_invoice_at(invoice_request: &InvoiceRequest, created_at: Duration) -> Bolt12Invoice {
+    let payment_hash = PaymentHash(sha256::Hash::hash(&PAYMENT_PREIMAGE.0).to_byte_array());
+    invoice_request
+        .respond_with_no_std(vec![build_payment_path()], payment_hash, created_at)
+        .unwrap()
+        .build()
+        .unwrap()
+        .sign(|message: &UnsignedBolt12Invoice| {
+            Ok(Secp256k1::new()
+                .sign_schnorr_no_aux_rand(message.as_ref().as_digest(), &recipient_keys()))
+        })
+        .unwrap()
+}
+
+/// Builds an invoice responding to the invoice request provided, created now.
+pub fn build_invoice(invoice_request: &InvoiceRequest) -> Bolt12Invoice {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    build_invoice_at(invoice_request, now)
+}
@@ -0,0 +1,792 @@
+use crate::{CLIENT_CA_CERT_FILENAME, CLIENT_CA_KEY_FILENAME, TLS_CERT_FILENAME, TLS_KEY_FILENAME};
+use bitcoin::hashes::{sha256, Hash};
+use futures::channel::mpsc;
+use futures::{SinkExt, Stream};
+use log::{debug, error, info, warn};
+use rcgen::{
+    date_time_ymd, BasicConstraints, Certificate, CertificateParams, DnType, Error as RcgenError,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, SanType,
+};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{interval, sleep, timeout};
+use tokio_rustls::rustls::client::danger::HandshakeSignatureValid;
+use tokio_rustls::rustls::crypto::ring::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::{CertificateDer, UnixTime};
+use tokio_rustls::rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, RootCertStore, ServerConfig,
+    SignatureScheme,
+};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use triggered::Listener;
+
+// How often we check whether our certificate needs to be renewed, or has changed on disk.
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+// How long a client has to complete the TLS handshake before we drop its connection.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The number of connections that have completed their handshake that we buffer before the grpc
+// server picks them up.
+const TLS_ACCEPT_BUFFER: usize = 64;
+
+// How long we stop accepting connections for after an error that isn't specific to a single
+// connection, such as running out of file descriptors, so that we don't spin on it.
+const TLS_ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+// How long the certificates we generate are valid for.
+const TLS_CERT_VALIDITY: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+// How long before our certificate expires we generate a new one.
+const TLS_RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// How long the CA that we issue client certificates from is valid for.
+const CLIENT_CA_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+/// An error that occurs when generating TLS credentials.
+#[derive(Debug)]
+pub enum CertificateGenFailure {
+    RcgenError(RcgenError),
+    IoError(std::io::Error),
+}
+
+impl Display for CertificateGenFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CertificateGenFailure::RcgenError(e) => {
+                write!(f, "Error generating TLS certificate: {e:?}")
+            }
+            CertificateGenFailure::IoError(e) => write!(f, "IO error: {e:?}"),
+        }
+    }
+}
+
+impl Error for CertificateGenFailure {}
+
+/// An error that occurs when loading the gRPC server's TLS credentials.
+#[derive(Debug)]
+pub enum TlsError {
+    /// We couldn't generate or renew our self-signed certificate.
+    Generate(CertificateGenFailure),
+    /// We couldn't read the certificate or key.
+    Io(std::io::Error),
+    /// The certificate file doesn't contain any certificates.
+    NoCertificate,
+    /// The key file doesn't contain a private key.
+    NoPrivateKey,
+    /// The private key isn't of a type that we support.
+    InvalidKey(String),
+    /// The client CA file doesn't contain a usable CA certificate.
+    InvalidClientCa(String),
+    /// A client certificate fingerprint isn't a hex-encoded SHA-256 hash.
+    InvalidFingerprint(String),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Generate(e) => write!(f, "{e}"),
+            TlsError::Io(e) => write!(f, "Error reading TLS credentials: {e}"),
+            TlsError::NoCertificate => write!(f, "No certificate found in TLS cert file"),
+            TlsError::NoPrivateKey => write!(f, "No private key found in TLS key file"),
+            TlsError::InvalidKey(e) => write!(f, "Unsupported TLS private key: {e}"),
+            TlsError::InvalidClientCa(e) => write!(f, "Invalid TLS client CA: {e}"),
+            TlsError::InvalidFingerprint(fingerprint) => write!(
+                f,
+                "Invalid client certificate fingerprint {fingerprint}, expected a hex-encoded \
+                SHA-256 hash"
+            ),
+        }
+    }
+}
+
+impl Error for TlsError {}
+
+/// Where the gRPC server's TLS credentials come from.
+#[derive(Clone, Debug)]
+pub enum TlsSource {
+    /// A self-signed certificate that we generate in the data directory, covering localhost and
+    /// the comma-separated tls ips provided. We renew it before it expires.
+    Generated {
+        data_dir: PathBuf,
+        tls_ips: Option<String>,
+    },
+    /// A certificate and key that are managed by the operator, for instance by an ACME client.
+    /// We pick up new versions of the files, but never write to them.
+    Provided {
+        cert_path: PathBuf,
+        key_path: PathBuf,
+    },
+}
+
+impl TlsSource {
+    fn paths(&self) -> (PathBuf, PathBuf) {
+        match self {
+            TlsSource::Generated { data_dir, .. } => (
+                data_dir.join(TLS_CERT_FILENAME),
+                data_dir.join(TLS_KEY_FILENAME),
+            ),
+            TlsSource::Provided {
+                cert_path,
+                key_path,
+            } => (cert_path.clone(), key_path.clone()),
+        }
+    }
+}
+
+/// ReloadableCert serves the gRPC server's certificate to TLS handshakes, and swaps in a new one
+/// when it's renewed or changed on disk, so that the server doesn't need to be restarted.
+#[derive(Debug)]
+pub struct ReloadableCert {
+    source: TlsSource,
+    cert: RwLock<Arc<CertifiedKey>>,
+    // When the cert and key files were last modified, when we loaded them.
+    modified: Mutex<(Option<SystemTime>, Option<SystemTime>)>,
+}
+
+impl ReloadableCert {
+    /// Loads the credentials from the source provided, generating them first if we manage them.
+    pub fn load(source: TlsSource) -> Result<Self, TlsError> {
+        if let TlsSource::Generated { data_dir, tls_ips } = &source {
+            generate_tls_creds(data_dir.clone(), tls_ips.clone()).map_err(TlsError::Generate)?;
+        }
+
+        let (cert_path, key_path) = source.paths();
+        let modified = (modified_at(&cert_path), modified_at(&key_path));
+        let cert = read_certified_key(&cert_path, &key_path)?;
+
+        Ok(ReloadableCert {
+            source,
+            cert: RwLock::new(Arc::new(cert)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Renews our certificate if we manage it and it needs to be, and reloads the credentials if
+    /// they've changed on disk since we last loaded them. Returns whether they were reloaded.
+    pub fn reload(&self) -> Result<bool, TlsError> {
+        if let TlsSource::Generated { data_dir, tls_ips } = &self.source {
+            generate_tls_creds(data_dir.clone(), tls_ips.clone()).map_err(TlsError::Generate)?;
+        }
+
+        let (cert_path, key_path) = self.source.paths();
+        let modified = (modified_at(&cert_path), modified_at(&key_path));
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        // If the files are only partly updated, we'll fail to load them and try again next time.
+        let cert = read_certified_key(&cert_path, &key_path)?;
+        *self.cert.write().unwrap() = Arc::new(cert);
+        *self.modified.lock().unwrap() = modified;
+
+        Ok(true)
+    }
+
+    /// Periodically reloads the credentials until shutdown is signaled.
+    pub async fn run(&self, listener: Listener) {
+        let mut ticker = interval(TLS_RELOAD_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = listener.clone() => return,
+                _ = ticker.tick() => match self.reload() {
+                    Ok(true) => info!("Reloaded TLS credentials for the grpc server."),
+                    Ok(false) => {}
+                    Err(e) => error!("Error reloading TLS credentials: {e}"),
+                },
+            }
+        }
+    }
+
+    /// Returns a TLS config for the gRPC server that always presents our latest certificate. If a
+    /// client verifier is provided, clients must present a certificate that it accepts.
+    pub fn server_config(
+        self: &Arc<Self>,
+        client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+    ) -> ServerConfig {
+        let builder = ServerConfig::builder();
+        let builder = match client_verifier {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config =
+            builder.with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        config
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.cert.read().unwrap()))
+    }
+}
+
+/// Builds a verifier that only accepts client certificates issued by the CA at the path provided.
+/// If fingerprints are provided, as a comma-separated list of hex-encoded SHA-256 hashes of the
+/// certificates, the certificate must also be one of them.
+pub fn client_verifier(
+    ca_path: &Path,
+    fingerprints: Option<&str>,
+) -> Result<Arc<dyn ClientCertVerifier>, TlsError> {
+    let ca_pem = fs::read(ca_path).map_err(TlsError::Io)?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut ca_pem.as_slice()) {
+        roots
+            .add(cert.map_err(TlsError::Io)?)
+            .map_err(|e| TlsError::InvalidClientCa(e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(TlsError::InvalidClientCa(format!(
+            "no certificate found in {ca_path:?}"
+        )));
+    }
+
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+        .build()
+        .map_err(|e| TlsError::InvalidClientCa(e.to_string()))?;
+    match fingerprints {
+        Some(fingerprints) => Ok(Arc::new(FingerprintVerifier {
+            inner: verifier,
+            fingerprints: parse_fingerprints(fingerprints)?,
+        })),
+        None => Ok(verifier),
+    }
+}
+
+// Parses a comma-separated list of certificate fingerprints. Colons between bytes, as printed by
+// openssl, are allowed.
+fn parse_fingerprints(fingerprints: &str) -> Result<HashSet<[u8; 32]>, TlsError> {
+    fingerprints
+        .split(',')
+        .map(str::trim)
+        .filter(|fingerprint| !fingerprint.is_empty())
+        .map(|fingerprint| {
+            hex::decode(fingerprint.replace(':', ""))
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| TlsError::InvalidFingerprint(fingerprint.to_string()))
+        })
+        .collect()
+}
+
+/// Returns the SHA-256 hash of a DER-encoded certificate.
+pub fn cert_fingerprint(cert_der: &[u8]) -> [u8; 32] {
+    sha256::Hash::hash(cert_der).to_byte_array()
+}
+
+// FingerprintVerifier narrows down the client certificates that another verifier accepts to an
+// allow-list of fingerprints, so that a single certificate can be revoked without replacing the
+// CA.
+#[derive(Debug)]
+struct FingerprintVerifier {
+    inner: Arc<dyn ClientCertVerifier>,
+    fingerprints: HashSet<[u8; 32]>,
+}
+
+impl ClientCertVerifier for FingerprintVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, tokio_rustls::rustls::Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+
+        let fingerprint = cert_fingerprint(end_entity);
+        if !self.fingerprints.contains(&fingerprint) {
+            warn!(
+                "Rejected grpc client certificate with fingerprint {}, which isn't allowed.",
+                hex::encode(fingerprint)
+            );
+            return Err(tokio_rustls::rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+// generate_tls_creds creates the tls cert/key pair required to secure connections to LNDK's gRPC
+// server, if it doesn't already exist. By default they are stored in ~/.lndk. An existing cert is
+// replaced if it's close to expiring, or if the tls ips it was created for have changed. Returns
+// whether new credentials were written.
+pub fn generate_tls_creds(
+    data_dir: PathBuf,
+    tls_ips_string: Option<String>,
+) -> Result<bool, CertificateGenFailure> {
+    let cert_path = data_dir.join(TLS_CERT_FILENAME);
+    let key_path = data_dir.join(TLS_KEY_FILENAME);
+    let mut subject_alt_names = vec!["localhost".to_string()];
+    if let Some(ips) = collect_tls_ips(tls_ips_string) {
+        for ip in ips {
+            subject_alt_names.push(ip);
+        }
+    };
+
+    if key_path.exists() && cert_path.exists() {
+        let cert_pem = fs::read_to_string(&cert_path).map_err(CertificateGenFailure::IoError)?;
+        if !tls_cert_needs_renewal(&cert_pem, &subject_alt_names, unix_now()) {
+            return Ok(false);
+        }
+        log::info!("Renewing TLS credentials in {data_dir:?}");
+    } else {
+        log::debug!("Generating fresh TLS credentials in {data_dir:?}");
+    }
+
+    let now = unix_now();
+    let mut params =
+        CertificateParams::new(subject_alt_names).map_err(CertificateGenFailure::RcgenError)?;
+    // Allow for some clock skew between us and our clients.
+    params.not_before = date_time_ymd(1970, 1, 1) + Duration::from_secs(now - 24 * 60 * 60);
+    params.not_after = date_time_ymd(1970, 1, 1) + Duration::from_secs(now) + TLS_CERT_VALIDITY;
+    let key_pair = KeyPair::generate().map_err(CertificateGenFailure::RcgenError)?;
+    let cert = params
+        .self_signed(&key_pair)
+        .map_err(CertificateGenFailure::RcgenError)?;
+
+    // Create the tls files. Make sure the key is user-readable only. The new files are moved into
+    // place so that a server reloading them never sees a cert that doesn't match its key.
+    let tmp_key_path = key_path.with_extension("tmp");
+    write_private_file(&tmp_key_path, &key_pair.serialize_pem())
+        .map_err(CertificateGenFailure::IoError)?;
+
+    let tmp_cert_path = cert_path.with_extension("tmp");
+    fs::write(&tmp_cert_path, cert.pem()).map_err(CertificateGenFailure::IoError)?;
+    fs::rename(&tmp_key_path, &key_path).map_err(CertificateGenFailure::IoError)?;
+    fs::rename(&tmp_cert_path, &cert_path).map_err(CertificateGenFailure::IoError)?;
+
+    Ok(true)
+}
+
+// Returns whether a cert we generated expires within TLS_RENEW_BEFORE, or doesn't cover exactly
+// the subject alt names provided. Certs we can't parse are replaced too.
+fn tls_cert_needs_renewal(cert_pem: &str, subject_alt_names: &[String], now: u64) -> bool {
+    let params = match CertificateParams::from_ca_cert_pem(cert_pem) {
+        Ok(params) => params,
+        Err(e) => {
+            log::warn!("Could not parse existing TLS certificate: {e}");
+            return true;
+        }
+    };
+
+    let renew_at = now.saturating_add(TLS_RENEW_BEFORE.as_secs());
+    if params.not_after.unix_timestamp() < renew_at as i64 {
+        return true;
+    }
+
+    let mut cert_names: Vec<String> = params
+        .subject_alt_names
+        .iter()
+        .filter_map(|name| match name {
+            SanType::DnsName(name) => Some(name.as_str().to_string()),
+            SanType::IpAddress(ip) => Some(ip.to_string()),
+            _ => None,
+        })
+        .collect();
+    // rcgen treats names that parse as ip addresses as such, so we normalize ours the same way.
+    let mut wanted_names: Vec<String> = subject_alt_names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => name.clone(),
+        })
+        .collect();
+    cert_names.sort();
+    cert_names.dedup();
+    wanted_names.sort();
+    wanted_names.dedup();
+
+    cert_names != wanted_names
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// The user first passes in the tls ips as a comma-deliminated string into LNDK. Here we turn that
+// string into a Vec.
+fn collect_tls_ips(tls_ips_str: Option<String>) -> Option<Vec<String>> {
+    tls_ips_str.map(|tls_ips_str| tls_ips_str.split(',').map(|str| str.to_owned()).collect())
+}
+
+/// A client certificate issued by our client CA, along with its private key.
+pub struct ClientCert {
+    pub cert_pem: String,
+    pub key_pem: String,
+    /// The hex-encoded SHA-256 hash of the certificate, for the server's allow-list.
+    pub fingerprint: String,
+}
+
+impl ClientCert {
+    /// Writes the certificate and key to the paths provided. The key is only readable by the
+    /// current user.
+    pub fn write(&self, cert_path: &Path, key_path: &Path) -> Result<(), std::io::Error> {
+        write_private_file(key_path, &self.key_pem)?;
+        fs::write(cert_path, &self.cert_pem)
+    }
+}
+
+/// Issues a client certificate for the gRPC server's mutual TLS, with the name provided as its
+/// common name. The CA that signs it is kept in the data directory, and created the first time
+/// a certificate is issued.
+pub fn issue_client_cert(data_dir: &Path, name: &str) -> Result<ClientCert, CertificateGenFailure> {
+    let (ca_cert, ca_key) = load_or_create_client_ca(data_dir)?;
+
+    let now = unix_now();
+    let mut params = CertificateParams::new(vec![]).map_err(CertificateGenFailure::RcgenError)?;
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+    params.not_before = date_time_ymd(1970, 1, 1) + Duration::from_secs(now - 24 * 60 * 60);
+    params.not_after = date_time_ymd(1970, 1, 1) + Duration::from_secs(now) + TLS_CERT_VALIDITY;
+    let key_pair = KeyPair::generate().map_err(CertificateGenFailure::RcgenError)?;
+    let cert = params
+        .signed_by(&key_pair, &ca_cert, &ca_key)
+        .map_err(CertificateGenFailure::RcgenError)?;
+
+    Ok(ClientCert {
+        cert_pem: cert.pem(),
+        key_pem: key_pair.serialize_pem(),
+        fingerprint: hex::encode(cert_fingerprint(cert.der())),
+    })
+}
+
+// Loads the client CA from the data directory, creating it if it doesn't exist yet.
+fn load_or_create_client_ca(
+    data_dir: &Path,
+) -> Result<(Certificate, KeyPair), CertificateGenFailure> {
+    let cert_path = data_dir.join(CLIENT_CA_CERT_FILENAME);
+    let key_path = data_dir.join(CLIENT_CA_KEY_FILENAME);
+
+    if cert_path.exists() && key_path.exists() {
+        let cert_pem = fs::read_to_string(&cert_path).map_err(CertificateGenFailure::IoError)?;
+        let key_pem = fs::read_to_string(&key_path).map_err(CertificateGenFailure::IoError)?;
+        let key_pair = KeyPair::from_pem(&key_pem).map_err(CertificateGenFailure::RcgenError)?;
+        // Re-signing the CA's own parameters gives us a certificate with the same subject and key
+        // to sign with, so certificates issued now chain to the CA file that the server trusts.
+        let cert = CertificateParams::from_ca_cert_pem(&cert_pem)
+            .and_then(|params| params.self_signed(&key_pair))
+            .map_err(CertificateGenFailure::RcgenError)?;
+        return Ok((cert, key_pair));
+    }
+
+    info!("Creating TLS client CA in {data_dir:?}");
+    let now = unix_now();
+    let mut params = CertificateParams::new(vec![]).map_err(CertificateGenFailure::RcgenError)?;
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "lndk client ca");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    params.not_before = date_time_ymd(1970, 1, 1) + Duration::from_secs(now - 24 * 60 * 60);
+    params.not_after = date_time_ymd(1970, 1, 1) + Duration::from_secs(now) + CLIENT_CA_VALIDITY;
+    let key_pair = KeyPair::generate().map_err(CertificateGenFailure::RcgenError)?;
+    let cert = params
+        .self_signed(&key_pair)
+        .map_err(CertificateGenFailure::RcgenError)?;
+
+    write_private_file(&key_path, &key_pair.serialize_pem())
+        .map_err(CertificateGenFailure::IoError)?;
+    fs::write(&cert_path, cert.pem()).map_err(CertificateGenFailure::IoError)?;
+
+    Ok((cert, key_pair))
+}
+
+// Writes a file that's only readable by the current user, such as a private key.
+pub(crate) fn write_private_file(path: &Path, contents: &str) -> Result<(), std::io::Error> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())
+}
+
+/// Accepts connections on the address provided and completes their TLS handshake with the config
+/// provided, returning a stream of connections for the gRPC server to serve. Handshakes happen in
+/// the background, so that a slow client doesn't hold up others.
+pub async fn tls_incoming(
+    addr: SocketAddr,
+    config: ServerConfig,
+) -> Result<impl Stream<Item = Result<TlsStream<TcpStream>, std::io::Error>>, std::io::Error> {
+    let tcp_listener = TcpListener::bind(addr).await?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (sender, receiver) = mpsc::channel(TLS_ACCEPT_BUFFER);
+
+    tokio::spawn(async move {
+        // We stop accepting connections once the server has shut down and dropped the receiver.
+        while !sender.is_closed() {
+            let (stream, peer) = match tcp_listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) if is_connection_error(&e) => {
+                    debug!("Error accepting grpc connection: {e}");
+                    continue;
+                }
+                Err(e) => {
+                    warn!(
+                        "Error accepting grpc connections, pausing for {}s: {e}",
+                        TLS_ACCEPT_ERROR_DELAY.as_secs()
+                    );
+                    sleep(TLS_ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_nodelay(true) {
+                debug!("Could not set TCP_NODELAY for {peer}: {e}");
+            }
+
+            let acceptor = acceptor.clone();
+            let mut sender = sender.clone();
+            tokio::spawn(async move {
+                match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let _ = sender.send(Ok(stream)).await;
+                    }
+                    Ok(Err(e)) => debug!("TLS handshake with {peer} failed: {e}"),
+                    Err(_) => debug!("TLS handshake with {peer} timed out"),
+                }
+            });
+        }
+    });
+
+    Ok(receiver)
+}
+
+// Returns whether an error accepting a connection only affects that connection, so we can move
+// straight on to the next one.
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+// Reads a pem-encoded certificate chain and private key from disk.
+fn read_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, TlsError> {
+    let cert_pem = fs::read(cert_path).map_err(TlsError::Io)?;
+    let key_pem = fs::read(key_path).map_err(TlsError::Io)?;
+
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(TlsError::Io)?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    let key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+        .map_err(TlsError::Io)?
+        .ok_or(TlsError::NoPrivateKey)?;
+    let signing_key = any_supported_type(&key).map_err(|e| TlsError::InvalidKey(e.to_string()))?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_collect_tls_ips() {
+        // Test that it returns a vector of one element if only one ip is provided.
+        let tls_ips_str = Some("192.168.0.1".to_string());
+        let tls_ips = collect_tls_ips(tls_ips_str);
+        assert!(tls_ips.is_some());
+        assert!(tls_ips.as_ref().unwrap().len() == 1);
+
+        // If no ip is provided, collect_tls_ips should return None.
+        assert!(collect_tls_ips(None).is_none());
+
+        // If two ips are provided, a vector of length two should be returned.
+        let tls_ips_str = Some("192.168.0.1,192.168.0.3".to_string());
+        let tls_ips = collect_tls_ips(tls_ips_str);
+        assert!(tls_ips.is_some());
+        assert!(tls_ips.as_ref().unwrap().len() == 2);
+    }
+
+    #[test]
+    fn test_generate_tls_creds() {
+        let dir = tempdir().unwrap();
+        let data_dir = dir.path().to_path_buf();
+        let sans = vec!["localhost".to_string(), "192.168.0.1".to_string()];
+
+        assert!(generate_tls_creds(data_dir.clone(), Some("192.168.0.1".to_string())).unwrap());
+        let cert_pem = fs::read_to_string(data_dir.join(TLS_CERT_FILENAME)).unwrap();
+        assert!(!tls_cert_needs_renewal(&cert_pem, &sans, unix_now()));
+
+        // The same names don't need a new cert, but a new tls ip does.
+        assert!(!generate_tls_creds(data_dir.clone(), Some("192.168.0.1".to_string())).unwrap());
+        assert!(generate_tls_creds(data_dir.clone(), Some("lndkisdope.org".to_string())).unwrap());
+
+        // A cert is renewed once it's close to expiring.
+        let renew_at = unix_now() + TLS_CERT_VALIDITY.as_secs() - TLS_RENEW_BEFORE.as_secs();
+        assert!(!tls_cert_needs_renewal(&cert_pem, &sans, renew_at - 60));
+        assert!(tls_cert_needs_renewal(&cert_pem, &sans, renew_at + 60));
+    }
+
+    #[test]
+    fn test_is_connection_error() {
+        assert!(is_connection_error(&ErrorKind::ConnectionReset.into()));
+        assert!(!is_connection_error(&std::io::Error::from_raw_os_error(24)));
+    }
+
+    #[test]
+    fn test_reload_provided_cert() {
+        let dir = tempdir().unwrap();
+        let data_dir = dir.path().join("generated");
+        fs::create_dir(&data_dir).unwrap();
+        generate_tls_creds(data_dir.clone(), None).unwrap();
+
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        fs::copy(data_dir.join(TLS_CERT_FILENAME), &cert_path).unwrap();
+        fs::copy(data_dir.join(TLS_KEY_FILENAME), &key_path).unwrap();
+
+        let cert = ReloadableCert::load(TlsSource::Provided {
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+        })
+        .unwrap();
+        assert!(!cert.reload().unwrap());
+        let loaded = cert.cert.read().unwrap().cert.clone();
+
+        // Replace the files with a new cert, as an ACME client would on renewal.
+        generate_tls_creds(data_dir.clone(), Some("192.168.0.1".to_string())).unwrap();
+        fs::copy(data_dir.join(TLS_CERT_FILENAME), &cert_path).unwrap();
+        fs::copy(data_dir.join(TLS_KEY_FILENAME), &key_path).unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        fs::File::options()
+            .write(true)
+            .open(&cert_path)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+
+        assert!(cert.reload().unwrap());
+        assert_ne!(cert.cert.read().unwrap().cert, loaded);
+    }
+
+    #[test]
+    fn test_invalid_provided_cert() {
+        let dir = tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        fs::write(&cert_path, "not a cert").unwrap();
+        fs::write(&key_path, "not a key").unwrap();
+
+        let result = ReloadableCert::load(TlsSource::Provided {
+            cert_path,
+            key_path,
+        });
+        assert!(matches!(result, Err(TlsError::NoCertificate)));
+    }
+
+    fn client_cert_der(cert: &ClientCert) -> CertificateDer<'static> {
+        rustls_pemfile::certs(&mut cert.cert_pem.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_client_verifier() {
+        let dir = tempdir().unwrap();
+        let alice = issue_client_cert(dir.path(), "alice").unwrap();
+        let bob = issue_client_cert(dir.path(), "bob").unwrap();
+        let ca_path = dir.path().join(CLIENT_CA_CERT_FILENAME);
+
+        // Certificates issued later are signed by the same CA.
+        let verifier = client_verifier(&ca_path, None).unwrap();
+        for cert in [&alice, &bob] {
+            assert!(verifier
+                .verify_client_cert(&client_cert_der(cert), &[], UnixTime::now())
+                .is_ok());
+        }
+
+        // With an allow-list, only the certificates on it are accepted.
+        let fingerprints = format!(" {} ", alice.fingerprint.to_uppercase());
+        let verifier = client_verifier(&ca_path, Some(&fingerprints)).unwrap();
+        assert!(verifier
+            .verify_client_cert(&client_cert_der(&alice), &[], UnixTime::now())
+            .is_ok());
+        assert!(verifier
+            .verify_client_cert(&client_cert_der(&bob), &[], UnixTime::now())
+            .is_err());
+
+        // Certificates from another CA are rejected, even if their fingerprint is allowed.
+        let other_dir = tempdir().unwrap();
+        let mallory = issue_client_cert(other_dir.path(), "mallory").unwrap();
+        let verifier = client_verifier(&ca_path, Some(&mallory.fingerprint)).unwrap();
+        assert!(verifier
+            .verify_client_cert(&client_cert_der(&mallory), &[], UnixTime::now())
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_fingerprints() {
+        let fingerprint = [0xab; 32];
+        let colons = vec!["AB"; 32].join(":");
+        let parsed =
+            parse_fingerprints(&format!("{},{colons},", hex::encode(fingerprint))).unwrap();
+        assert_eq!(parsed, HashSet::from([fingerprint]));
+
+        assert!(matches!(
+            parse_fingerprints("abcd"),
+            Err(TlsError::InvalidFingerprint(_))
+        ));
+        assert!(matches!(
+            parse_fingerprints("not hex"),
+            Err(TlsError::InvalidFingerprint(_))
+        ));
+    }
+}
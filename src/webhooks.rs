@@ -0,0 +1,626 @@
+use crate::offers::handler::{OfferEvent, OfferHandler};
+use async_trait::async_trait;
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client as HttpClient, Method, Request as HttpRequest};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use lightning::sign::EntropySource;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::select;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{interval, timeout, Duration};
+use tonic_lnd::lnrpc::invoice::InvoiceState;
+use tonic_lnd::lnrpc::{Invoice, InvoiceSubscription};
+use tonic_lnd::tonic::Streaming;
+use tonic_lnd::Client;
+use triggered::Listener;
+
+/// The header that carries the hex-encoded HMAC-SHA256 of the request body, keyed with the
+/// webhook secret.
+pub const SIGNATURE_HEADER: &str = "X-Lndk-Signature";
+
+/// The header that carries the id of the delivery, which stays the same across retries so that
+/// receivers can drop duplicates.
+pub const DELIVERY_HEADER: &str = "X-Lndk-Delivery";
+
+// How long we wait for the receiver to respond to a delivery.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often we check for deliveries that are due.
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(1);
+
+// The delay before the first retry, which doubles with every failed attempt up to the maximum.
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+// How long past its expiry we keep waiting on an unpaid invoice, so that we still pick up
+// payments that settled just before it expired while we were offline.
+const AWAITING_PAYMENT_GRACE: u64 = 24 * 60 * 60;
+
+/// WebhookCfg configures where we POST events to.
+#[derive(Clone, Debug)]
+pub struct WebhookCfg {
+    pub url: String,
+    /// The key that we sign request bodies with, so that receivers can check that events came
+    /// from us.
+    pub secret: String,
+    /// The number of times we try to deliver an event before giving up on it.
+    pub max_attempts: u32,
+}
+
+/// The body we POST for each event.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    id: &'a str,
+    /// When the event happened, in seconds since the unix epoch.
+    created_at: u64,
+    event: &'a OfferEvent,
+}
+
+/// Delivery is an event that we haven't managed to deliver yet.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    pub body: String,
+    pub attempts: u32,
+    /// When we'll next try to deliver the event, in seconds since the unix epoch.
+    pub next_attempt_at: u64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct QueueData {
+    deliveries: Vec<Delivery>,
+    // The hex-encoded payment hashes of the invoices we sent in response to invoice requests
+    // that haven't been paid yet, along with when each invoice expires.
+    awaiting_payment: HashMap<String, u64>,
+    // The settle index of the last invoice we saw settle, so that we pick up invoices that
+    // settled while we were offline.
+    settle_index: u64,
+}
+
+/// WebhookQueue persists the events we haven't delivered yet, so that they survive restarts. Like
+/// the PaymentStore, it's stored as a JSON file in lndk's data directory and rewritten in full
+/// on every update.
+pub struct WebhookQueue {
+    path: PathBuf,
+    data: Mutex<QueueData>,
+}
+
+impl WebhookQueue {
+    /// Opens the queue at the path provided, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> Result<Self, std::io::Error> {
+        let data = if path.exists() {
+            let contents = fs::read_to_string(path)?;
+            serde_json::from_str(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            QueueData::default()
+        };
+
+        Ok(WebhookQueue {
+            path: path.to_path_buf(),
+            data: Mutex::new(data),
+        })
+    }
+
+    /// Queues an event to be delivered right away.
+    pub fn push(&self, id: String, body: String, now: u64) {
+        self.update(|data| {
+            data.deliveries.push(Delivery {
+                id,
+                body,
+                attempts: 0,
+                next_attempt_at: now,
+            })
+        });
+    }
+
+    /// Returns the deliveries that are due at the time provided.
+    pub fn due(&self, now: u64) -> Vec<Delivery> {
+        let data = self.data.lock().unwrap();
+        data.deliveries
+            .iter()
+            .filter(|delivery| delivery.next_attempt_at <= now)
+            .cloned()
+            .collect()
+    }
+
+    /// Removes a delivery once the receiver has accepted it.
+    pub fn delivered(&self, id: &str) {
+        self.update(|data| data.deliveries.retain(|delivery| delivery.id != id));
+    }
+
+    /// Schedules a failed delivery to be retried with exponential backoff. Once it has been
+    /// attempted max_attempts times we give up on it, and return false.
+    pub fn failed(&self, id: &str, now: u64, max_attempts: u32) -> bool {
+        let mut retrying = false;
+        self.update(|data| {
+            if let Some(delivery) = data.deliveries.iter_mut().find(|d| d.id == id) {
+                delivery.attempts += 1;
+                delivery.next_attempt_at = now + retry_delay(delivery.attempts).as_secs();
+                retrying = delivery.attempts < max_attempts;
+            }
+            if !retrying {
+                data.deliveries.retain(|delivery| delivery.id != id);
+            }
+        });
+        retrying
+    }
+
+    /// Remembers an invoice that we sent in response to an invoice request, so that we can
+    /// notify the receiver when it's paid. Invoices that expired without being paid are
+    /// forgotten.
+    pub fn await_payment(&self, payment_hash: String, expires_at: u64, now: u64) {
+        self.update(|data| {
+            data.awaiting_payment
+                .retain(|_, expires_at| expires_at.saturating_add(AWAITING_PAYMENT_GRACE) > now);
+            data.awaiting_payment.insert(payment_hash, expires_at);
+        });
+    }
+
+    /// Records that an invoice settled, and returns whether it's one we were waiting on.
+    pub fn settled(&self, payment_hash: &str, settle_index: u64) -> bool {
+        let mut awaited = false;
+        self.update(|data| {
+            awaited = data.awaiting_payment.remove(payment_hash).is_some();
+            data.settle_index = data.settle_index.max(settle_index);
+        });
+        awaited
+    }
+
+    pub fn settle_index(&self) -> u64 {
+        self.data.lock().unwrap().settle_index
+    }
+
+    fn update(&self, f: impl FnOnce(&mut QueueData)) {
+        let mut data = self.data.lock().unwrap();
+        f(&mut data);
+        if let Err(e) = self.persist(&data) {
+            error!("Error persisting webhook queue: {e}");
+        }
+    }
+
+    fn persist(&self, data: &QueueData) -> Result<(), std::io::Error> {
+        let contents = serde_json::to_string(data)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        let tmp_path = self.path.with_extension("tmp");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path)
+    }
+}
+
+// Returns how long we wait before retrying a delivery that has failed the number of times
+// provided.
+fn retry_delay(attempts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+    MIN_RETRY_DELAY.saturating_mul(factor).min(MAX_RETRY_DELAY)
+}
+
+/// Signs a request body with the webhook secret, returning the hex-encoded HMAC-SHA256.
+pub fn sign_body(secret: &str, body: &str) -> String {
+    let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    let hmac = Hmac::<sha256::Hash>::from_engine(engine);
+    hex::encode(hmac.to_byte_array())
+}
+
+/// WebhookSender POSTs a signed delivery to the receiver.
+#[async_trait]
+pub trait WebhookSender: Send + Sync {
+    async fn post(&self, url: &str, delivery: &Delivery, signature: &str) -> Result<(), String>;
+}
+
+/// HttpSender delivers webhooks over http or https.
+pub struct HttpSender {
+    client: HttpClient<HttpsConnector<HttpConnector>>,
+}
+
+impl HttpSender {
+    pub fn new() -> Self {
+        let connector = HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        HttpSender {
+            client: HttpClient::builder().build(connector),
+        }
+    }
+}
+
+impl Default for HttpSender {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl WebhookSender for HttpSender {
+    async fn post(&self, url: &str, delivery: &Delivery, signature: &str) -> Result<(), String> {
+        let request = HttpRequest::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("content-type", "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .header(DELIVERY_HEADER, &delivery.id)
+            .body(Body::from(delivery.body.clone()))
+            .map_err(|e| e.to_string())?;
+
+        let response = timeout(REQUEST_TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| format!("no response in {} seconds", REQUEST_TIMEOUT.as_secs()))?
+            .map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("receiver responded with {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Webhooks POSTs signed JSON events about our payments and offers to a receiver, retrying
+/// failed deliveries with backoff.
+pub struct Webhooks {
+    cfg: WebhookCfg,
+    queue: WebhookQueue,
+    sender: Arc<dyn WebhookSender>,
+}
+
+impl Webhooks {
+    pub fn new(cfg: WebhookCfg, queue: WebhookQueue, sender: Arc<dyn WebhookSender>) -> Self {
+        Webhooks { cfg, queue, sender }
+    }
+
+    /// Delivers events from the offer handler, and for payments to the invoices it sends out,
+    /// until we're shut down.
+    pub async fn run(
+        self: Arc<Self>,
+        offer_handler: Arc<OfferHandler>,
+        client: Client,
+        listener: Listener,
+    ) {
+        info!("Delivering webhooks to {}.", self.cfg.url);
+
+        // Deliveries can take a while when the receiver is slow or down, so we make them in their
+        // own task. That way we keep draining events while they're in flight, and every event is
+        // queued on disk as soon as it happens.
+        let delivery = tokio::spawn(self.clone().deliver());
+        self.receive(offer_handler, client, listener).await;
+        delivery.abort();
+    }
+
+    // Queues events from the offer handler, and for payments to the invoices it sends out, until
+    // we're shut down.
+    async fn receive(&self, offer_handler: Arc<OfferHandler>, client: Client, listener: Listener) {
+        let mut events = offer_handler.subscribe_events();
+        let mut invoices = None;
+        let mut ticker = interval(DELIVERY_INTERVAL);
+
+        loop {
+            select! {
+                event = events.recv() => match event {
+                    Ok(event) => self.enqueue(&offer_handler, event),
+                    Err(RecvError::Lagged(missed)) => warn!("Missed {missed} webhook events."),
+                    Err(RecvError::Closed) => return,
+                },
+                invoice = next_invoice(&mut invoices) => match invoice {
+                    Some(invoice) => self.handle_invoice(&offer_handler, invoice),
+                    // We'll subscribe again on the next tick.
+                    None => invoices = None,
+                },
+                _ = ticker.tick(), if invoices.is_none() => {
+                    invoices = self.subscribe_invoices(client.clone()).await;
+                },
+                _ = listener.clone() => {
+                    info!("Webhooks received signal to shut down.");
+                    return;
+                }
+            }
+        }
+    }
+
+    // Attempts due deliveries until the task is aborted.
+    async fn deliver(self: Arc<Self>) {
+        let mut ticker = interval(DELIVERY_INTERVAL);
+        loop {
+            ticker.tick().await;
+            self.deliver_due(now()).await;
+        }
+    }
+
+    fn enqueue(&self, offer_handler: &OfferHandler, event: OfferEvent) {
+        if let OfferEvent::InvoiceRequestAnswered {
+            payment_hash,
+            expires_at,
+            ..
+        } = &event
+        {
+            self.queue
+                .await_payment(payment_hash.clone(), *expires_at, now());
+        }
+
+        let id = hex::encode(offer_handler.messenger_utils.get_secure_random_bytes());
+        let created_at = now();
+        let payload = WebhookPayload {
+            id: &id,
+            created_at,
+            event: &event,
+        };
+        match serde_json::to_string(&payload) {
+            Ok(body) => self.queue.push(id, body, created_at),
+            Err(e) => error!("Error serializing webhook event: {e}"),
+        }
+    }
+
+    fn handle_invoice(&self, offer_handler: &OfferHandler, invoice: Invoice) {
+        if invoice.state != InvoiceState::Settled as i32 {
+            return;
+        }
+
+        let payment_hash = hex::encode(&invoice.r_hash);
+        if self.queue.settled(&payment_hash, invoice.settle_index) {
+            self.enqueue(
+                offer_handler,
+                OfferEvent::PaymentReceived {
+                    payment_hash,
+                    amount_msats: invoice.amt_paid_msat as u64,
+                },
+            );
+        }
+    }
+
+    async fn subscribe_invoices(&self, mut client: Client) -> Option<Streaming<Invoice>> {
+        let subscription = InvoiceSubscription {
+            add_index: 0,
+            settle_index: self.queue.settle_index(),
+        };
+        match client.lightning().subscribe_invoices(subscription).await {
+            Ok(response) => Some(response.into_inner()),
+            Err(e) => {
+                warn!("Error subscribing to invoices for webhooks: {e}");
+                None
+            }
+        }
+    }
+
+    /// Attempts every delivery that's due at the time provided.
+    pub async fn deliver_due(&self, now: u64) {
+        for delivery in self.queue.due(now) {
+            let signature = sign_body(&self.cfg.secret, &delivery.body);
+            match self.sender.post(&self.cfg.url, &delivery, &signature).await {
+                Ok(()) => {
+                    debug!("Delivered webhook {}.", delivery.id);
+                    self.queue.delivered(&delivery.id);
+                }
+                Err(e) => {
+                    if self.queue.failed(&delivery.id, now, self.cfg.max_attempts) {
+                        warn!("Error delivering webhook {}, will retry: {e}", delivery.id);
+                    } else {
+                        error!("Error delivering webhook {}, giving up: {e}", delivery.id);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Waits for the next invoice update, or forever if we're not subscribed. Returns None if the
+// subscription ended.
+async fn next_invoice(invoices: &mut Option<Streaming<Invoice>>) -> Option<Invoice> {
+    match invoices {
+        Some(stream) => stream.message().await.ok().flatten(),
+        None => std::future::pending().await,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // Records the deliveries it's asked to make, and fails the first `failures` of them.
+    struct StubSender {
+        posted: Mutex<Vec<(Delivery, String)>>,
+        failures: Mutex<u32>,
+    }
+
+    impl StubSender {
+        fn new(failures: u32) -> Self {
+            StubSender {
+                posted: Mutex::new(Vec::new()),
+                failures: Mutex::new(failures),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl WebhookSender for StubSender {
+        async fn post(
+            &self,
+            _url: &str,
+            delivery: &Delivery,
+            signature: &str,
+        ) -> Result<(), String> {
+            self.posted
+                .lock()
+                .unwrap()
+                .push((delivery.clone(), signature.to_string()));
+            let mut failures = self.failures.lock().unwrap();
+            if *failures > 0 {
+                *failures -= 1;
+                return Err("unavailable".to_string());
+            }
+            Ok(())
+        }
+    }
+
+    fn cfg(url: String) -> WebhookCfg {
+        WebhookCfg {
+            url,
+            secret: "secret".to_string(),
+            max_attempts: 3,
+        }
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), Duration::from_secs(5));
+        assert_eq!(retry_delay(2), Duration::from_secs(10));
+        assert_eq!(retry_delay(3), Duration::from_secs(20));
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_queue_survives_restart() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("webhooks.json");
+
+        let queue = WebhookQueue::open(&path).unwrap();
+        queue.push("a".to_string(), "{}".to_string(), 100);
+        queue.await_payment("hash".to_string(), 1000, 100);
+        assert!(queue.failed("a", 100, 3));
+
+        let queue = WebhookQueue::open(&path).unwrap();
+        assert!(queue.due(100).is_empty());
+        let due = queue.due(105);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+
+        assert!(queue.settled("hash", 7));
+        assert!(!queue.settled("hash", 8));
+        assert_eq!(queue.settle_index(), 8);
+    }
+
+    #[test]
+    fn test_forget_expired_invoices() {
+        let dir = tempdir().unwrap();
+        let queue = WebhookQueue::open(&dir.path().join("webhooks.json")).unwrap();
+        queue.await_payment("expired".to_string(), 1000, 100);
+        queue.await_payment(
+            "recent".to_string(),
+            1000,
+            1000 + AWAITING_PAYMENT_GRACE - 1,
+        );
+
+        // An invoice paid just before it expired is still picked up within the grace period.
+        assert!(queue.settled("recent", 1));
+
+        // Invoices that were never paid are dropped once the grace period is over.
+        queue.await_payment("new".to_string(), u64::MAX, 1000 + AWAITING_PAYMENT_GRACE);
+        assert!(!queue.settled("expired", 2));
+        assert!(queue.settled("new", 3));
+    }
+
+    #[tokio::test]
+    async fn test_deliver_with_retries() {
+        let dir = tempdir().unwrap();
+        let queue = WebhookQueue::open(&dir.path().join("webhooks.json")).unwrap();
+        queue.push("a".to_string(), "{\"id\":\"a\"}".to_string(), 0);
+        let sender = Arc::new(StubSender::new(1));
+        let webhooks = Webhooks::new(cfg("http://localhost".to_string()), queue, sender.clone());
+
+        // The first attempt fails, so we retry once the backoff is over.
+        webhooks.deliver_due(0).await;
+        webhooks.deliver_due(1).await;
+        assert_eq!(sender.posted.lock().unwrap().len(), 1);
+        webhooks.deliver_due(5).await;
+
+        let posted = sender.posted.lock().unwrap();
+        assert_eq!(posted.len(), 2);
+        assert_eq!(posted[1].1, sign_body("secret", "{\"id\":\"a\"}"));
+        assert!(webhooks.queue.due(u64::MAX).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_give_up_after_max_attempts() {
+        let dir = tempdir().unwrap();
+        let queue = WebhookQueue::open(&dir.path().join("webhooks.json")).unwrap();
+        queue.push("a".to_string(), "{}".to_string(), 0);
+        let sender = Arc::new(StubSender::new(u32::MAX));
+        let webhooks = Webhooks::new(cfg("http://localhost".to_string()), queue, sender.clone());
+
+        // Each attempt is made once the previous one's backoff is over.
+        for now in [0, 10_000, 20_000, 30_000] {
+            webhooks.deliver_due(now).await;
+        }
+
+        assert_eq!(sender.posted.lock().unwrap().len(), 3);
+        assert!(webhooks.queue.due(u64::MAX).is_empty());
+    }
+
+    // Accepts a single http request, responds with the status line provided and returns the
+    // request it received.
+    async fn serve_once(listener: TcpListener, status: &'static str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..read]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let content_length = text
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length: "))
+                    .map(|len| len.trim().parse::<usize>().unwrap())
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        let response = format!("HTTP/1.1 {status}\r\ncontent-length: 0\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http_sender() {
+        let delivery = Delivery {
+            id: "a".to_string(),
+            body: "{\"id\":\"a\"}".to_string(),
+            attempts: 0,
+            next_attempt_at: 0,
+        };
+        let signature = sign_body("secret", &delivery.body);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "200 OK"));
+        HttpSender::new()
+            .post(&url, &delivery, &signature)
+            .await
+            .unwrap();
+
+        let request = server.await.unwrap().to_lowercase();
+        assert!(request.starts_with("post /hooks http/1.1"));
+        assert!(request.contains(&format!("x-lndk-signature: {signature}")));
+        assert!(request.contains("x-lndk-delivery: a"));
+        assert!(request.ends_with(&delivery.body));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(listener, "500 Internal Server Error"));
+        assert!(HttpSender::new()
+            .post(&url, &delivery, &signature)
+            .await
+            .is_err());
+        server.await.unwrap();
+    }
+}
@@ -203,6 +203,7 @@ pub async fn setup_lndk(
         skip_version_check: false,
         rate_limit_count: 10,
         rate_limit_period_secs: 1,
+        dns_resolver: None,
     };
 
     // Make sure lndk successfully sends the invoice_request.
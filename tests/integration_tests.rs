@@ -20,7 +20,7 @@ use lndk::lnd::validate_lnd_creds;
 use lndk::offers::create_reply_path;
 use lndk::offers::handler::{CreateOfferParams, OfferHandler, PayOfferParams};
 use lndk::onion_messenger::MessengerUtilities;
-use lndk::{setup_logger, LifecycleSignals};
+use lndk::{setup_logger, LifecycleSignals, LndkNodeBuilder};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -63,6 +63,7 @@ async fn get_invoice_with_retry(
                 reply_path: None,
                 response_invoice_timeout: Some(15),
                 fee_limit: None,
+                payment_id: None,
             })
             .await
             .map_err(|_| lndk::offers::OfferError::InvoiceTimeout(15));
@@ -108,6 +109,7 @@ async fn create_offers(
             reply_path: None,
             response_invoice_timeout: None,
             fee_limit: None,
+            payment_id: None,
         };
 
         pay_cfgs.push(pay_cfg);
@@ -228,6 +230,7 @@ async fn test_lndk_get_invoice() {
         skip_version_check: false,
         rate_limit_count: 10,
         rate_limit_period_secs: 1,
+        dns_resolver: None,
     };
 
     let mut client = lnd.client.clone().unwrap();
@@ -296,6 +299,7 @@ async fn test_lndk_get_invoice() {
         skip_version_check: false,
         rate_limit_count: 10,
         rate_limit_period_secs: 1,
+        dns_resolver: None,
     };
 
     let log_file = Some(lndk_dir.join(format!("lndk-logs.txt")));
@@ -363,6 +367,7 @@ async fn test_lndk_pay_offer() {
         reply_path: None,
         response_invoice_timeout: None,
         fee_limit: None,
+        payment_id: None,
     };
     select! {
         val = messenger.run(lndk_cfg.clone(), Arc::clone(&handler)) => {
@@ -416,6 +421,7 @@ async fn test_lndk_pay_offer_concurrently() {
         reply_path: None,
         response_invoice_timeout: None,
         fee_limit: None,
+        payment_id: None,
     };
     // Let's also try to pay the same offer multiple times concurrently.
     select! {
@@ -602,6 +608,7 @@ async fn test_check_lndk_pay_offer_with_reconnection() {
         reply_path: None,
         response_invoice_timeout: None,
         fee_limit: None,
+        payment_id: None,
     };
     select! {
         val = messenger.run(lndk_cfg.clone(), Arc::clone(&handler)) => {
@@ -704,6 +711,40 @@ async fn test_create_offer() {
     ldk2.stop().await;
 }
 
+#[tokio::test(flavor = "multi_thread")]
+// Test that a node built with LndkNodeBuilder passes its options on to the offer handler, and that
+// shutting it down through its handle stops it running.
+async fn test_lndk_node_shutdown() {
+    let test_name = "lndk_node_shutdown";
+    let (_bitcoind, lnd, ldk1, ldk2, lndk_dir) = common::setup_test_infrastructure(test_name).await;
+    let (ldk1_pubkey, _) = ldk1.get_node_info();
+
+    let (cfg, _, _, _) =
+        common::setup_lndk(&lnd.cert_path, &lnd.macaroon_path, lnd.address, lndk_dir).await;
+    let node = LndkNodeBuilder::new(cfg)
+        .response_invoice_timeout(30)
+        .dns_resolvers(vec![ldk1_pubkey])
+        .build()
+        .await
+        .expect("should build node");
+    let handle = node.handle();
+    assert_eq!(handle.offer_handler().response_invoice_timeout(), 30);
+    assert_eq!(handle.offer_handler().dns_resolvers(), &[ldk1_pubkey]);
+
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        time::sleep(Duration::from_secs(5)).await;
+        shutdown_handle.shutdown();
+    });
+    let result = time::timeout(Duration::from_secs(60), node.run())
+        .await
+        .expect("node should stop running once it's shut down");
+    assert!(result.is_ok());
+
+    ldk1.stop().await;
+    ldk2.stop().await;
+}
+
 async fn pay_offer_and_wait_for_payment(
     ldk: &LdkNode,
     offer: Offer,
@@ -751,11 +792,13 @@ async fn test_receive_payment_from_offer() {
         skip_version_check: false,
         rate_limit_count: 10,
         rate_limit_period_secs: 1,
+        dns_resolver: None,
     };
     let handler = Arc::new(OfferHandler::new(
         None,
         None,
         Some(lnd.client.clone().unwrap()),
+        vec![],
     ));
     let messenger = lndk::LndkOnionMessenger::new();
 